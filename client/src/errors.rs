@@ -1,3 +1,4 @@
+use entropy_api_key_service_shared::{ErrorCode, ErrorResponse};
 use entropy_protocol::sign_and_encrypt::EncryptedSignedMessageErr;
 use thiserror::Error;
 
@@ -8,6 +9,54 @@ pub enum ClientError {
     EncryptionOrAuthentication(#[from] EncryptedSignedMessageErr),
     #[error("HTTP response {0}: {1}")]
     BadResponse(reqwest::StatusCode, String),
+    #[error("Request timestamp is too far from the service's current time")]
+    Stale,
+    #[error("Request has already been received by the service")]
+    Replayed,
+    #[error("No API key has been deployed for the service the request is for")]
+    NoKeyForService,
+    #[error("The API key for the service the request is for has expired")]
+    ApiKeyExpired,
+    #[error("Too many recent requests")]
+    RateLimited,
+    #[error("{0}")]
+    InvalidUrl(String),
+    #[error("URL has no host")]
+    UrlHasNoHost,
+    #[error("Only http and https URLs are supported")]
+    UnsupportedUrlScheme,
+    #[error("{0}")]
+    InvalidHeader(String),
+    #[error("Secret name is not valid")]
+    InvalidSecretName,
+    #[error("Expiry must be in the future")]
+    ExpiryInPast,
+    #[error("{0}")]
+    InvalidRotationRecipe(String),
+    #[error("{0}")]
+    InvalidInjectionRule(String),
+    #[error("{0}")]
+    InvalidRequestPolicy(String),
+    #[error("{0}")]
+    InvalidBackup(String),
+    #[error("{0}")]
+    EgressDenied(String),
+    #[error("{0}")]
+    InjectionNotAllowed(String),
+    #[error("{0}")]
+    PolicyViolation(String),
+    #[error("No API key with the given service and secret name has been deployed")]
+    NoSuchApiKey,
+    #[error("The API key has no pending version")]
+    NoPendingVersion,
+    #[error("The API key has no previous version within its grace period")]
+    NoPreviousVersion,
+    #[error("{0}")]
+    Upstream(String),
+    #[error("{0}")]
+    RotationFailed(String),
+    #[error("Service error: {0}")]
+    Service(ErrorResponse),
     #[error("Time subtraction error: {0}")]
     SystemTime(#[from] std::time::SystemTimeError),
     #[error("JSON: {0}")]
@@ -28,4 +77,60 @@ pub enum ClientError {
     SubxtRpcError(#[from] subxt::ext::subxt_rpcs::Error),
     #[error("Client: {0}")]
     EntropyClient(#[from] entropy_client::ClientError),
+    #[error("Response was not signed by the selected API key service")]
+    UnexpectedResponseSigner,
+    #[error("Unauthenticated HTTP response {0}: {1}")]
+    UnauthenticatedResponse(reqwest::StatusCode, String),
+    #[error("{0}")]
+    UnsupportedHttpMethod(#[from] entropy_api_key_service_shared::UnsupportedHttpMethod),
+}
+
+impl ClientError {
+    /// Whether the same request may succeed if made again later
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ClientError::RateLimited | ClientError::Upstream(_) => true,
+            ClientError::Service(error_response) => error_response.code.is_retryable(),
+            _ => false,
+        }
+    }
+}
+
+impl From<ErrorResponse> for ClientError {
+    fn from(error_response: ErrorResponse) -> Self {
+        match error_response.code {
+            ErrorCode::Stale => ClientError::Stale,
+            ErrorCode::Replayed => ClientError::Replayed,
+            ErrorCode::NoKeyForService => ClientError::NoKeyForService,
+            ErrorCode::ApiKeyExpired => ClientError::ApiKeyExpired,
+            ErrorCode::RateLimited => ClientError::RateLimited,
+            ErrorCode::InvalidUrl => ClientError::InvalidUrl(error_response.message),
+            ErrorCode::UrlHasNoHost => ClientError::UrlHasNoHost,
+            ErrorCode::UnsupportedUrlScheme => ClientError::UnsupportedUrlScheme,
+            ErrorCode::InvalidHeader => ClientError::InvalidHeader(error_response.message),
+            ErrorCode::InvalidSecretName => ClientError::InvalidSecretName,
+            ErrorCode::ExpiryInPast => ClientError::ExpiryInPast,
+            ErrorCode::InvalidRotationRecipe => {
+                ClientError::InvalidRotationRecipe(error_response.message)
+            }
+            ErrorCode::InvalidInjectionRule => {
+                ClientError::InvalidInjectionRule(error_response.message)
+            }
+            ErrorCode::InvalidRequestPolicy => {
+                ClientError::InvalidRequestPolicy(error_response.message)
+            }
+            ErrorCode::InvalidBackup => ClientError::InvalidBackup(error_response.message),
+            ErrorCode::EgressDenied => ClientError::EgressDenied(error_response.message),
+            ErrorCode::InjectionNotAllowed => {
+                ClientError::InjectionNotAllowed(error_response.message)
+            }
+            ErrorCode::PolicyViolation => ClientError::PolicyViolation(error_response.message),
+            ErrorCode::NoSuchApiKey => ClientError::NoSuchApiKey,
+            ErrorCode::NoPendingVersion => ClientError::NoPendingVersion,
+            ErrorCode::NoPreviousVersion => ClientError::NoPreviousVersion,
+            ErrorCode::Upstream => ClientError::Upstream(error_response.message),
+            ErrorCode::RotationFailed => ClientError::RotationFailed(error_response.message),
+            _ => ClientError::Service(error_response),
+        }
+    }
 }
@@ -2,22 +2,37 @@
 pub mod errors;
 pub use entropy_client::chain_api::entropy::runtime_types::pallet_forest::module::ForestServerInfo;
 
-use entropy_api_key_service_shared::{DeleteApiKeyInfo, DeployApiKeyInfo, SendApiKeyMessage};
+use entropy_api_key_service_shared::{
+    DELETE_SECRET_ROUTE, DEPLOY_API_KEY_ROUTE, DeleteApiKeyInfo, DeployApiKeyInfo,
+    EXPORT_API_KEYS_ROUTE, ExportApiKeysInfo, IMPORT_API_KEYS_ROUTE, ImportApiKeysInfo,
+    LIST_API_KEYS_ROUTE, ListApiKeysInfo, MAKE_REQUEST_ROUTE, PROMOTE_API_KEY_ROUTE,
+    PromoteApiKeyInfo, ROLLBACK_API_KEY_ROUTE, RollbackApiKeyInfo, SendApiKeyMessage,
+    associated_data, backup_associated_data,
+};
+
+pub use entropy_api_key_service_shared::{
+    ApiKeyBackup, ApiKeyDetails, ApiKeyVersionDetails, ApiResponse, BackupApiKey,
+    BackupApiKeyVersion, ErrorCode, ErrorResponse, Expiry, HttpMethod, INJECTION_FORMAT_KEY,
+    InjectionRule, KeyVersion, OLD_API_KEY_PLACEHOLDER, REDACTED_SECRET, RequestPolicy,
+    RotationRecipe, RotationRequest, api_key_fingerprint,
+};
 use entropy_client::{
     chain_api::{
         EntropyConfig,
         entropy::{self, runtime_types::pallet_parameters::SupportedCvmServices},
     },
-    client::EncryptedSignedMessage,
     verify_tree_quote,
 };
 use errors::ClientError;
 use rand::{SeedableRng, rngs::StdRng, seq::SliceRandom};
+use serde::de::DeserializeOwned;
 use sp_core::{Pair, sr25519};
 use std::time::{SystemTime, UNIX_EPOCH};
 use subxt::{OnlineClient, backend::legacy::LegacyRpcMethods, utils::AccountId32};
 
-pub use entropy_api_key_service_shared::API_KEY_PLACEHOLDER;
+pub use entropy_api_key_service_shared::{API_KEY_PLACEHOLDER, api_key_placeholder};
+pub use entropy_client::client::EncryptedSignedMessage;
+pub use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
 
 /// Client for API key service
 pub struct ApiKeyServiceClient {
@@ -25,6 +40,8 @@ pub struct ApiKeyServiceClient {
     api_key_service_endpoint: String,
     /// X25519 public key of the api key service instance to use
     api_key_service_x25519_public_key: [u8; 32],
+    /// Account ID of the api key service instance to use, which responses must be signed by
+    api_key_service_account_id: [u8; 32],
     /// Client for requests
     http_client: reqwest::Client,
     /// The user's keypair for authentication
@@ -36,11 +53,13 @@ impl ApiKeyServiceClient {
     pub fn new(
         api_key_service_endpoint: String,
         api_key_service_x25519_public_key: [u8; 32],
+        api_key_service_account_id: [u8; 32],
         pair: sr25519::Pair,
     ) -> Self {
         Self {
             api_key_service_endpoint,
             api_key_service_x25519_public_key,
+            api_key_service_account_id,
             http_client: reqwest::Client::new(),
             pair,
         }
@@ -49,11 +68,13 @@ impl ApiKeyServiceClient {
     /// Create a new client with given server details
     pub fn new_with_service_info(
         api_key_service_info: ForestServerInfo,
+        api_key_service_account_id: [u8; 32],
         pair: sr25519::Pair,
     ) -> Result<Self, ClientError> {
         Ok(Self {
             api_key_service_endpoint: String::from_utf8(api_key_service_info.endpoint)?,
             api_key_service_x25519_public_key: api_key_service_info.x25519_public_key,
+            api_key_service_account_id,
             http_client: reqwest::Client::new(),
             pair,
         })
@@ -89,73 +110,235 @@ impl ApiKeyServiceClient {
         )
         .await?;
 
-        Ok(Self::new_with_service_info(api_key_service_info, pair)?)
+        Ok(Self::new_with_service_info(
+            api_key_service_info,
+            api_key_service_account_id.0,
+            pair,
+        )?)
     }
 
-    /// Deploy an API key
+    /// Deploy an API key, which will be substituted for [API_KEY_PLACEHOLDER]
     pub async fn deploy_api_key(
         &self,
         api_key: String,
         api_url: String,
+    ) -> Result<(), ClientError> {
+        self.deploy_with_options(api_key, api_url, Default::default())
+            .await
+    }
+
+    /// Deploy a named secret, for services which need several. It will be substituted for the
+    /// placeholder given by [api_key_placeholder]
+    pub async fn deploy_named_api_key(
+        &self,
+        secret_name: String,
+        api_key: String,
+        api_url: String,
+    ) -> Result<(), ClientError> {
+        let options = DeployOptions {
+            secret_name: Some(secret_name),
+            ..Default::default()
+        };
+        self.deploy_with_options(api_key, api_url, options).await
+    }
+
+    /// Deploy a secret with the given options
+    pub async fn deploy_with_options(
+        &self,
+        api_key: String,
+        api_url: String,
+        options: DeployOptions,
     ) -> Result<(), ClientError> {
         let user_api_key_info = DeployApiKeyInfo {
             api_key,
             api_url,
+            secret_name: options.secret_name,
+            expiry: options.expiry,
+            pending: options.pending,
+            rotation: options.rotation,
+            injection: options.injection,
+            policy: options.policy,
             timestamp: get_current_timestamp()?,
+            nonce: rand::random(),
         };
 
         let request = serde_json::to_vec(&user_api_key_info)?;
 
-        let response = self
-            .send_http_request("/deploy-api-key".to_string(), request)
-            .await?;
-
-        let response_status = response.status();
-        match response_status {
-            reqwest::StatusCode::OK => Ok(()),
-            _ => Err(ClientError::BadResponse(
-                response_status,
-                response.text().await.unwrap_or_default(),
-            )),
-        }
+        self.send_request(DEPLOY_API_KEY_ROUTE, request).await
     }
 
-    /// Deletes an API key
+    /// Deletes all secrets deployed for a service
     pub async fn delete_api_key(&self, api_url: String) -> Result<(), ClientError> {
+        self.delete(None, api_url).await
+    }
+
+    /// Deletes a single named secret
+    pub async fn delete_named_api_key(
+        &self,
+        secret_name: String,
+        api_url: String,
+    ) -> Result<(), ClientError> {
+        self.delete(Some(secret_name), api_url).await
+    }
+
+    /// Internal helper to delete secrets
+    async fn delete(
+        &self,
+        secret_name: Option<String>,
+        api_url: String,
+    ) -> Result<(), ClientError> {
         let user_info = DeleteApiKeyInfo {
             api_url,
+            secret_name,
             timestamp: get_current_timestamp()?,
+            nonce: rand::random(),
         };
 
         let request = serde_json::to_vec(&user_info)?;
 
-        let response = self
-            .send_http_request("/delete-secret".to_string(), request)
-            .await?;
+        self.send_request(DELETE_SECRET_ROUTE, request).await
+    }
 
-        let response_status = response.status();
-        match response_status {
-            reqwest::StatusCode::OK => Ok(()),
-            _ => Err(ClientError::BadResponse(
-                response_status,
-                response.text().await.unwrap_or_default(),
-            )),
-        }
+    /// Make the pending version of a secret active. The previously active version may still be
+    /// used with [KeyVersion::Previous], and restored with [ApiKeyServiceClient::rollback_api_key],
+    /// for the given number of seconds
+    pub async fn promote_api_key(
+        &self,
+        secret_name: Option<String>,
+        api_url: String,
+        grace_period: u64,
+    ) -> Result<(), ClientError> {
+        let user_info = PromoteApiKeyInfo {
+            api_url,
+            secret_name,
+            grace_period,
+            timestamp: get_current_timestamp()?,
+            nonce: rand::random(),
+        };
+
+        let request = serde_json::to_vec(&user_info)?;
+
+        self.send_request(PROMOTE_API_KEY_ROUTE, request).await
     }
 
-    /// Make an HTTP request
+    /// Restore the previous version of a secret during its grace period, discarding the active
+    /// version
+    pub async fn rollback_api_key(
+        &self,
+        secret_name: Option<String>,
+        api_url: String,
+    ) -> Result<(), ClientError> {
+        let user_info = RollbackApiKeyInfo {
+            api_url,
+            secret_name,
+            timestamp: get_current_timestamp()?,
+            nonce: rand::random(),
+        };
+
+        let request = serde_json::to_vec(&user_info)?;
+
+        self.send_request(ROLLBACK_API_KEY_ROUTE, request).await
+    }
+
+    /// List the secrets you have deployed, without revealing them
+    pub async fn list(&self) -> Result<Vec<ApiKeyDetails>, ClientError> {
+        let user_info = ListApiKeysInfo {
+            timestamp: get_current_timestamp()?,
+            nonce: rand::random(),
+        };
+
+        let request = serde_json::to_vec(&user_info)?;
+
+        self.send_request(LIST_API_KEYS_ROUTE, request).await
+    }
+
+    /// Export all of your secrets, encrypted to the given x25519 public key. The backup can be
+    /// imported into this or another instance of the service with [ApiKeyServiceClient::import]
+    pub async fn export(
+        &self,
+        backup_x25519_public_key: [u8; 32],
+    ) -> Result<EncryptedSignedMessage, ClientError> {
+        let user_info = ExportApiKeysInfo {
+            backup_x25519_public_key,
+            timestamp: get_current_timestamp()?,
+            nonce: rand::random(),
+        };
+
+        let request = serde_json::to_vec(&user_info)?;
+
+        self.send_request(EXPORT_API_KEYS_ROUTE, request).await
+    }
+
+    /// Decrypt a backup made with [ApiKeyServiceClient::export] using the secret key it was
+    /// encrypted to, and import the secrets it contains. Returns the number of secrets imported
+    pub async fn import(
+        &self,
+        backup: &EncryptedSignedMessage,
+        backup_x25519_secret: &StaticSecret,
+    ) -> Result<usize, ClientError> {
+        let signed_backup = backup.decrypt(
+            backup_x25519_secret,
+            &backup_associated_data(&self.pair.public().0),
+        )?;
+        let backup: ApiKeyBackup = serde_json::from_slice(&signed_backup.message.0)?;
+        self.import_api_keys(backup.api_keys).await
+    }
+
+    /// Import the given secrets, replacing any existing secrets with the same service and name.
+    /// Returns the number of secrets imported, which excludes any which have expired
+    pub async fn import_api_keys(&self, api_keys: Vec<BackupApiKey>) -> Result<usize, ClientError> {
+        let user_info = ImportApiKeysInfo {
+            api_keys,
+            timestamp: get_current_timestamp()?,
+            nonce: rand::random(),
+        };
+
+        let request = serde_json::to_vec(&user_info)?;
+
+        self.send_request(IMPORT_API_KEYS_ROUTE, request).await
+    }
+
+    /// Make an HTTP request, returning the status, headers and body of the upstream response
     pub async fn make_request(
         &self,
         request: reqwest::Request,
         http_headers: Vec<(String, String)>,
-    ) -> Result<reqwest::Response, ClientError> {
+    ) -> Result<ApiResponse, ClientError> {
+        self.make_request_with_key_version(request, http_headers, KeyVersion::Active)
+            .await
+    }
+
+    /// Make an HTTP request using the given version of each secret, returning the status,
+    /// headers and body of the upstream response
+    pub async fn make_request_with_key_version(
+        &self,
+        request: reqwest::Request,
+        http_headers: Vec<(String, String)>,
+        key_version: KeyVersion,
+    ) -> Result<ApiResponse, ClientError> {
+        let options = MakeRequestOptions {
+            key_version,
+            ..Default::default()
+        };
+        self.make_request_with_options(request, http_headers, options)
+            .await
+    }
+
+    /// Make an HTTP request with the given options, returning the status, headers and body of the
+    /// upstream response
+    pub async fn make_request_with_options(
+        &self,
+        request: reqwest::Request,
+        http_headers: Vec<(String, String)>,
+        options: MakeRequestOptions,
+    ) -> Result<ApiResponse, ClientError> {
         let request_body = match request.body() {
             Some(body) => String::from_utf8(body.as_bytes().unwrap_or_default().to_vec())?,
             None => String::new(),
         };
         let send_api_key_message = SendApiKeyMessage {
             request_body,
-            http_verb: request.method().as_str().to_lowercase().to_string(),
+            http_verb: request.method().as_str().parse()?,
             http_headers,
             api_url: request
                 .url()
@@ -164,43 +347,113 @@ impl ApiKeyServiceClient {
                 .strip_suffix("/")
                 .unwrap_or(request.url().as_str())
                 .to_string(),
+            key_version: options.key_version,
+            follow_redirects: options.follow_redirects,
             timestamp: get_current_timestamp()?,
+            nonce: rand::random(),
         };
 
         let request = serde_json::to_vec(&send_api_key_message)?;
 
-        let response = self
-            .send_http_request("/make-request".to_string(), request)
-            .await?;
-
-        Ok(response)
+        self.send_request(MAKE_REQUEST_ROUTE, request).await
     }
 
-    /// Internal helper to make a request to the service
-    async fn send_http_request(
+    /// Internal helper to make a request to the service, and verify and decrypt the response
+    async fn send_request<T: DeserializeOwned>(
         &self,
-        route: String,
+        route: &str,
         request: Vec<u8>,
-    ) -> Result<reqwest::Response, ClientError> {
-        let signed_message = EncryptedSignedMessage::new(
+    ) -> Result<T, ClientError> {
+        let associated_data = associated_data(route, &self.api_key_service_account_id);
+        let (signed_message, response_secret_key) = EncryptedSignedMessage::new_with_receiver(
             &self.pair,
             request,
             &self.api_key_service_x25519_public_key,
-            &[],
+            &associated_data,
         )?;
 
         let full_url = format!("{}{}", self.api_key_service_endpoint.clone(), route);
 
-        Ok(self
+        let response = self
             .http_client
             .post(full_url)
             .header("Content-Type", "application/json")
             .body(serde_json::to_string(&signed_message)?)
             .send()
-            .await?)
+            .await?;
+
+        let response_status = response.status();
+        let response_bytes = response.bytes().await?;
+
+        // Errors which happen before the service is able to decrypt our request cannot be
+        // encrypted, so are returned as plaintext. As anyone could have sent them, they are not
+        // trusted to say what went wrong.
+        let Ok(encrypted_response) =
+            serde_json::from_slice::<EncryptedSignedMessage>(&response_bytes)
+        else {
+            return Err(ClientError::UnauthenticatedResponse(
+                response_status,
+                String::from_utf8_lossy(&response_bytes).to_string(),
+            ));
+        };
+
+        let signed_response = encrypted_response.decrypt(&response_secret_key, &associated_data)?;
+        if signed_response.account_id().as_ref() != &self.api_key_service_account_id {
+            return Err(ClientError::UnexpectedResponseSigner);
+        }
+
+        match response_status {
+            reqwest::StatusCode::OK => Ok(serde_json::from_slice(&signed_response.message.0)?),
+            _ => Err(error_from_response(
+                response_status,
+                &signed_response.message.0,
+            )),
+        }
     }
 }
 
+/// Get the error from the body of an unsuccessful signed response, which should be an
+/// [ErrorResponse]
+fn error_from_response(status: reqwest::StatusCode, body: &[u8]) -> ClientError {
+    match serde_json::from_slice::<ErrorResponse>(body) {
+        Ok(error_response) => error_response.into(),
+        Err(_) => ClientError::BadResponse(status, String::from_utf8_lossy(body).to_string()),
+    }
+}
+
+/// Optional settings for deploying a secret
+#[derive(Debug, Clone, Default)]
+pub struct DeployOptions {
+    /// Name of the secret, for services which need several. It will be substituted for the
+    /// placeholder given by [api_key_placeholder]. If not given, it will be substituted for
+    /// [API_KEY_PLACEHOLDER]
+    pub secret_name: Option<String>,
+    /// When the secret should expire. If not given, it is kept until deleted
+    pub expiry: Option<Expiry>,
+    /// If true, deploy as a pending version of an existing secret, which can be tried out with
+    /// [KeyVersion::Pending] and then promoted with [ApiKeyServiceClient::promote_api_key]
+    pub pending: bool,
+    /// How the service should rotate the secret itself with the provider's API, if it should
+    pub rotation: Option<RotationRecipe>,
+    /// Where the placeholder for the secret may be placed in requests. If not given, it may be
+    /// placed anywhere
+    pub injection: Option<InjectionRule>,
+    /// Which requests the secret may be used for. If not given, it may be used for any request
+    pub policy: Option<RequestPolicy>,
+}
+
+/// Optional settings for making a request
+#[derive(Debug, Clone, Default)]
+pub struct MakeRequestOptions {
+    /// Which version of each secret to use
+    pub key_version: KeyVersion,
+    /// If true, follow redirects to other origins, or to paths outside the service the secrets were
+    /// deployed for. The service removes headers and query parameters containing secrets before
+    /// following them. Other redirects within the same origin are always followed, unless the
+    /// request uses a secret which has a request policy
+    pub follow_redirects: bool,
+}
+
 /// Returns the current unix time in seconds
 pub fn get_current_timestamp() -> Result<u64, ClientError> {
     Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
@@ -1,12 +1,18 @@
 //! Simple CLI for testing the API Key Service
 use anyhow::anyhow;
 use clap::{Parser, Subcommand};
-use entropy_api_key_service_client::ApiKeyServiceClient;
+use entropy_api_key_service_client::{
+    ApiKeyServiceClient, DeployOptions, EncryptedSignedMessage, Expiry, HttpMethod,
+    INJECTION_FORMAT_KEY, InjectionRule, KeyVersion, MakeRequestOptions, RequestPolicy,
+    StaticSecret, X25519PublicKey,
+};
 use reqwest::{
     Body, Method, Request, Url,
     header::{HeaderName, HeaderValue},
 };
 use sp_core::{Pair, sr25519};
+use std::path::PathBuf;
+use subxt::utils::AccountId32;
 
 #[derive(Parser, Debug, Clone)]
 #[command(about, version)]
@@ -22,6 +28,9 @@ pub struct Cli {
     /// Hex encoded 32 byte x25519 Public key of the server
     #[arg(short, long)]
     service_x25519_public_key: String,
+    /// SS58 encoded account ID of the server, which responses must be signed by
+    #[arg(long)]
+    service_account_id: AccountId32,
     /// Mnemonic or derivation path for keypair
     #[arg(short, long)]
     mnemonic: Option<String>,
@@ -35,24 +44,126 @@ enum CliCommand {
     DeployApiKey {
         /// API key to deploy
         api_key: String,
+        /// URL of the HTTP service associated with this key. It will only be used for requests
+        /// with the same scheme, host and port, under the path of this URL
+        api_url: String,
+        /// Name of the secret, for services which need several. It will be substituted for
+        /// `xxxREPLACE_ME:<name>xxx`
+        #[arg(long)]
+        secret_name: Option<String>,
+        /// Unix time in seconds at which the key should expire
+        #[arg(long, conflicts_with = "ttl")]
+        expires_at: Option<u64>,
+        /// Number of seconds after which the key should expire
+        #[arg(long)]
+        ttl: Option<u64>,
+        /// Deploy as a pending version of an existing key, to be promoted with `promote-api-key`
+        #[arg(long)]
+        pending: bool,
+        /// JSON file containing a rotation recipe, so that the service rotates the key itself
+        /// using the provider's API
+        #[arg(long)]
+        rotation_recipe: Option<PathBuf>,
+        /// Only allow the key to be placed in this header
+        #[arg(long, conflicts_with_all = ["inject_query_parameter", "inject_body"])]
+        inject_header: Option<String>,
+        /// Format of the header value, with `{key}` where the key goes, for example `Bearer {key}`
+        #[arg(long, requires = "inject_header", default_value = INJECTION_FORMAT_KEY)]
+        inject_format: String,
+        /// Only allow the key to be placed in this query parameter
+        #[arg(long, conflicts_with = "inject_body")]
+        inject_query_parameter: Option<String>,
+        /// Only allow the key to be placed in the request body
+        #[arg(long)]
+        inject_body: bool,
+        /// Only allow the key to be used with this HTTP method. May be given several times
+        #[arg(long)]
+        allow_method: Vec<HttpMethod>,
+        /// Only allow the key to be used for paths matching this glob, where `*` matches within
+        /// a path segment and `**` matches several segments. May be given several times
+        #[arg(long)]
+        allow_path: Vec<String>,
+        /// Only allow the key to be used with these query parameters. May be given several times
+        #[arg(long)]
+        allow_query_parameter: Vec<String>,
+        /// Only allow the key to be used with request bodies of up to this many bytes
+        #[arg(long)]
+        max_body_size: Option<usize>,
+    },
+    /// Make the pending version of an API key active
+    PromoteApiKey {
+        /// URL of the HTTP service associated with this key
+        api_url: String,
+        /// Name of the secret
+        #[arg(long)]
+        secret_name: Option<String>,
+        /// Number of seconds for which the previous version can still be used or rolled back to
+        #[arg(long, default_value_t = 3600)]
+        grace_period: u64,
+    },
+    /// Restore the previous version of an API key, discarding the active version
+    RollbackApiKey {
         /// URL of the HTTP service associated with this key
         api_url: String,
+        /// Name of the secret
+        #[arg(long)]
+        secret_name: Option<String>,
     },
     /// Delete an API key from the service
     DeleteApiKey {
         /// URL of the HTTP service associated with this key
         api_url: String,
+        /// Name of the secret to delete. If not given, all secrets for the service are deleted
+        #[arg(long)]
+        secret_name: Option<String>,
+    },
+    /// List the API keys you have deployed to the service
+    ListApiKeys,
+    /// Generate an x25519 keypair for encrypting backups, writing the secret key to a file and
+    /// printing the public key
+    GenerateBackupKey {
+        /// File to write the hex encoded secret key to
+        secret_key_file: PathBuf,
+    },
+    /// Export all of your API keys, encrypted to a backup public key
+    ExportApiKeys {
+        /// Hex encoded 32 byte x25519 public key to encrypt the backup to
+        #[arg(long)]
+        backup_public_key: String,
+        /// File to write the encrypted backup to
+        output_file: PathBuf,
+    },
+    /// Import API keys from an encrypted backup made with `export-api-keys`
+    ImportApiKeys {
+        /// File containing the encrypted backup
+        backup_file: PathBuf,
+        /// File containing the hex encoded secret key the backup was encrypted to
+        #[arg(long)]
+        backup_secret_key_file: PathBuf,
     },
-    /// Make a request substituting `xxxREPLACE_MExxx` with your API key
+    /// Make a request substituting `xxxREPLACE_MExxx` or `xxxREPLACE_ME:<name>xxx` with your
+    /// secrets
     MakeRequest {
         /// The full URL for the desired request
         url: Url,
-        /// The HTTP verb to use. Defaults to GET.
+        /// The HTTP verb to use: GET, HEAD, POST, PUT, PATCH, DELETE or OPTIONS. Defaults to GET.
         #[arg(long)]
         verb: Option<Method>,
         /// The request body (UTF8 only)
         #[arg(long)]
         body: Option<String>,
+        /// Use pending versions of API keys. Fails if a key used by the request has no pending
+        /// version
+        #[arg(long, conflicts_with = "use_previous")]
+        use_pending: bool,
+        /// Use previous versions of API keys. Fails if a key used by the request has none within its
+        /// grace period
+        #[arg(long)]
+        use_previous: bool,
+        /// Follow redirects to other origins, or to paths outside the URL the secrets were deployed
+        /// for. Headers and query parameters containing secrets are removed before following them
+        #[arg(long)]
+        follow_redirects: bool,
         // The Headers to be sent to the request ex: "Authorization:Bearer xxx"
         #[arg(long, value_parser = parse_key_val)]
         header_request: Option<Vec<(String, String)>>,
@@ -84,22 +195,174 @@ async fn main() -> anyhow::Result<()> {
     let client = ApiKeyServiceClient::new(
         args.service_url,
         x25519_public_key,
+        args.service_account_id.0,
         handle_mnemonic(args.mnemonic)?,
     );
 
     match args.command {
-        CliCommand::DeployApiKey { api_key, api_url } => {
-            client.deploy_api_key(api_key, api_url).await?;
+        CliCommand::DeployApiKey {
+            api_key,
+            api_url,
+            secret_name,
+            expires_at,
+            ttl,
+            pending,
+            rotation_recipe,
+            inject_header,
+            inject_format,
+            inject_query_parameter,
+            inject_body,
+            allow_method,
+            allow_path,
+            allow_query_parameter,
+            max_body_size,
+        } => {
+            let rotation = match rotation_recipe {
+                Some(path) => Some(serde_json::from_slice(&std::fs::read(path)?)?),
+                None => None,
+            };
+            let injection = match (inject_header, inject_query_parameter) {
+                (Some(name), _) => Some(InjectionRule::Header {
+                    name,
+                    format: inject_format,
+                }),
+                (_, Some(name)) => Some(InjectionRule::QueryParameter { name }),
+                _ => inject_body.then_some(InjectionRule::Body),
+            };
+            let policy = RequestPolicy {
+                methods: allow_method,
+                paths: allow_path,
+                query_parameters: (!allow_query_parameter.is_empty())
+                    .then_some(allow_query_parameter),
+                max_body_size,
+            };
+            let options = DeployOptions {
+                secret_name,
+                expiry: expires_at.map(Expiry::At).or(ttl.map(Expiry::Ttl)),
+                pending,
+                rotation,
+                injection,
+                policy: (policy != RequestPolicy::default()).then_some(policy),
+            };
+            client
+                .deploy_with_options(api_key, api_url, options)
+                .await?;
             println!("Api key deployed successfully");
         }
-        CliCommand::DeleteApiKey { api_url } => {
-            client.delete_api_key(api_url).await?;
+        CliCommand::DeleteApiKey {
+            api_url,
+            secret_name,
+        } => {
+            match secret_name {
+                Some(secret_name) => client.delete_named_api_key(secret_name, api_url).await?,
+                None => client.delete_api_key(api_url).await?,
+            }
             println!("Api key deleted successfully");
         }
+        CliCommand::PromoteApiKey {
+            api_url,
+            secret_name,
+            grace_period,
+        } => {
+            client
+                .promote_api_key(secret_name, api_url, grace_period)
+                .await?;
+            println!("Api key promoted successfully");
+        }
+        CliCommand::RollbackApiKey {
+            api_url,
+            secret_name,
+        } => {
+            client.rollback_api_key(secret_name, api_url).await?;
+            println!("Api key rolled back successfully");
+        }
+        CliCommand::ListApiKeys => {
+            for api_key in client.list().await? {
+                print!(
+                    "{} {} version {} deployed at {} fingerprint {}",
+                    api_key.service,
+                    api_key.secret_name,
+                    api_key.version,
+                    api_key.deployed_at,
+                    api_key.fingerprint
+                );
+                match api_key.expires_at {
+                    Some(expires_at) => println!(" expires at {expires_at}"),
+                    None => println!(),
+                }
+                if let Some(injection) = api_key.injection {
+                    println!("  may only be placed {injection}");
+                }
+                if let Some(policy) = api_key.policy {
+                    println!("  request policy {}", serde_json::to_string(&policy)?);
+                }
+                if let Some(next_rotation_at) = api_key.next_rotation_at {
+                    println!("  next rotation at {next_rotation_at}");
+                }
+                if let Some(pending) = api_key.pending {
+                    println!(
+                        "  pending version {} fingerprint {}",
+                        pending.version, pending.fingerprint
+                    );
+                }
+                if let Some(injection) = api_key.pending_injection {
+                    println!("  pending version may only be placed {injection}");
+                }
+                if let Some(policy) = api_key.pending_policy {
+                    println!(
+                        "  pending version request policy {}",
+                        serde_json::to_string(&policy)?
+                    );
+                }
+                if let Some(previous) = api_key.previous {
+                    println!(
+                        "  previous version {} fingerprint {} usable until {}",
+                        previous.version,
+                        previous.fingerprint,
+                        previous.usable_until.unwrap_or_default()
+                    );
+                }
+            }
+        }
+        CliCommand::GenerateBackupKey { secret_key_file } => {
+            let secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+            std::fs::write(secret_key_file, hex::encode(secret.to_bytes()))?;
+            println!(
+                "Backup public key: {}",
+                hex::encode(X25519PublicKey::from(&secret).as_bytes())
+            );
+        }
+        CliCommand::ExportApiKeys {
+            backup_public_key,
+            output_file,
+        } => {
+            let backup_public_key = hex::decode(backup_public_key)?
+                .try_into()
+                .map_err(|_| anyhow!("Backup public key must be 32 bytes"))?;
+            let backup = client.export(backup_public_key).await?;
+            std::fs::write(output_file, serde_json::to_vec(&backup)?)?;
+            println!("Api keys exported successfully");
+        }
+        CliCommand::ImportApiKeys {
+            backup_file,
+            backup_secret_key_file,
+        } => {
+            let secret: [u8; 32] =
+                hex::decode(std::fs::read_to_string(backup_secret_key_file)?.trim())?
+                    .try_into()
+                    .map_err(|_| anyhow!("Backup secret key must be 32 bytes"))?;
+            let backup: EncryptedSignedMessage =
+                serde_json::from_slice(&std::fs::read(backup_file)?)?;
+            let imported = client.import(&backup, &StaticSecret::from(secret)).await?;
+            println!("Imported {imported} api keys");
+        }
         CliCommand::MakeRequest {
             verb,
             url,
             body,
+            use_pending,
+            use_previous,
+            follow_redirects,
             header,
             header_request,
         } => {
@@ -129,10 +392,29 @@ async fn main() -> anyhow::Result<()> {
                 );
             }
 
+            let key_version = if use_pending {
+                KeyVersion::Pending
+            } else if use_previous {
+                KeyVersion::Previous
+            } else {
+                KeyVersion::Active
+            };
+            let options = MakeRequestOptions {
+                key_version,
+                follow_redirects,
+            };
             let response = client
-                .make_request(request, header_request.unwrap_or(vec![]))
+                .make_request_with_options(request, header_request.unwrap_or(vec![]), options)
                 .await?;
-            println!("Response: {response:?}");
+            for redirect in &response.redirects {
+                println!("Redirected from: {redirect}");
+            }
+            println!("Status: {}", response.status);
+            for (name, value) in &response.headers {
+                println!("{name}: {}", String::from_utf8_lossy(value));
+            }
+            println!();
+            println!("{}", response.text());
         }
     }
 
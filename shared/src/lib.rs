@@ -1,18 +1,238 @@
 //! Shared types used by the API Key Service server and client
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{fmt, str::FromStr};
+use zeroize::Zeroizing;
 
-/// The placeholder which will be replaced with your API key if given in request headers or body
+/// The placeholder which will be replaced with your API key if given in the request URL, headers or
+/// body. This refers to the secret named [DEFAULT_SECRET_NAME].
+///
+/// In a body with a JSON `Content-Type` the key will be JSON-escaped, and in a form-urlencoded body
+/// it will be form-urlencoded.
 pub const API_KEY_PLACEHOLDER: &str = "xxxREPLACE_MExxx";
 
+/// The placeholder which will be replaced with the secret being rotated away from, in the revoke
+/// request of a [RotationRecipe]
+pub const OLD_API_KEY_PLACEHOLDER: &str = "xxxREPLACE_ME_OLDxxx";
+
+/// Replaces any secret found in the URLs, headers or body of an [ApiResponse], so that secrets
+/// echoed by an upstream service are not revealed
+pub const REDACTED_SECRET: &str = "[REDACTED]";
+
+/// The name given to a secret which is deployed without a name
+pub const DEFAULT_SECRET_NAME: &str = "default";
+
+/// The maximum length of a secret name
+pub const MAX_SECRET_NAME_LENGTH: usize = 64;
+
+/// Get the placeholder which will be replaced with the secret of the given name, for example
+/// `xxxREPLACE_ME:key_idxxx`
+pub fn api_key_placeholder(secret_name: &str) -> String {
+    format!("xxxREPLACE_ME:{secret_name}xxx")
+}
+
+/// Secret names must be non-empty, at most [MAX_SECRET_NAME_LENGTH] characters long, and contain
+/// only ASCII letters, digits, `_` and `-`
+pub fn is_valid_secret_name(secret_name: &str) -> bool {
+    !secret_name.is_empty()
+        && secret_name.len() <= MAX_SECRET_NAME_LENGTH
+        && secret_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// HTTP route for deploying a secret
+pub const DEPLOY_API_KEY_ROUTE: &str = "/deploy-api-key";
+
+/// HTTP route for deleting secrets
+pub const DELETE_SECRET_ROUTE: &str = "/delete-secret";
+
+/// HTTP route for making a request using deployed secrets
+pub const MAKE_REQUEST_ROUTE: &str = "/make-request";
+
+/// HTTP route for listing deployed secrets
+pub const LIST_API_KEYS_ROUTE: &str = "/list-api-keys";
+
+/// HTTP route for promoting the pending version of a secret to be the active version
+pub const PROMOTE_API_KEY_ROUTE: &str = "/promote-api-key";
+
+/// HTTP route for restoring the previous version of a secret
+pub const ROLLBACK_API_KEY_ROUTE: &str = "/rollback-api-key";
+
+/// HTTP route for exporting an encrypted backup of secrets
+pub const EXPORT_API_KEYS_ROUTE: &str = "/export-api-keys";
+
+/// HTTP route for importing secrets from a backup
+pub const IMPORT_API_KEYS_ROUTE: &str = "/import-api-keys";
+
+/// Associated data used when encrypting requests to, and responses from, the given route of the
+/// API key service with the given account ID. This means a message cannot be used with a
+/// different route or a different instance of the service than the one it was made for.
+pub fn associated_data(route: &str, server_account_id: &[u8; 32]) -> Vec<u8> {
+    [
+        b"entropy-api-key-service".as_slice(),
+        route.as_bytes(),
+        server_account_id,
+    ]
+    .join(&0)
+}
+
+/// Associated data used when encrypting a backup of the secrets of the given account, so that it
+/// can only be imported by the same account
+pub fn backup_associated_data(account_id: &[u8; 32]) -> Vec<u8> {
+    [b"entropy-api-key-service-backup".as_slice(), account_id].join(&0)
+}
+
+/// Length in bytes of the nonce given in each request
+pub const NONCE_LENGTH: usize = 16;
+
 /// Request payload for the `/deploy-api-key` HTTP route
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct DeployApiKeyInfo {
     /// The secret API key to be deployed
     pub api_key: String,
-    /// URL of the service to use it with
+    /// URL of the service to use it with. The key will only be used for requests with the same
+    /// scheme, host and port, and a path which falls under the path of this URL
     pub api_url: String,
+    /// Name of the secret, for services which need several. If not given, [DEFAULT_SECRET_NAME] is
+    /// used
+    #[serde(default)]
+    pub secret_name: Option<String>,
+    /// When the secret should expire. If not given, it is kept until deleted
+    #[serde(default)]
+    pub expiry: Option<Expiry>,
+    /// If true, deploy the secret as a pending version of an existing secret, which can be tried
+    /// out with [KeyVersion::Pending] and then promoted to be the active version. Otherwise, the
+    /// secret replaces any existing versions.
+    #[serde(default)]
+    pub pending: bool,
+    /// How the service should rotate the secret itself. If not given with a pending version, any
+    /// existing recipe is kept
+    #[serde(default)]
+    pub rotation: Option<RotationRecipe>,
+    /// Where the secret may be placed in requests. If not given, any existing rule is kept, and
+    /// otherwise it may be placed anywhere. With a pending version, the rule only applies to the
+    /// pending version until it is promoted
+    #[serde(default)]
+    pub injection: Option<InjectionRule>,
+    /// Which requests the secret may be used for. If not given, any existing policy is kept, and
+    /// otherwise it may be used for any request. With a pending version, the policy only applies
+    /// to the pending version until it is promoted
+    #[serde(default)]
+    pub policy: Option<RequestPolicy>,
     /// Current unix time in seconds
     pub timestamp: u64,
+    /// Random value making this request unique. The service rejects any request it has already
+    /// received, so this allows identical requests to be made within the same second
+    pub nonce: [u8; NONCE_LENGTH],
+}
+
+/// Stands for the placeholder in the format of an [InjectionRule::Header]
+pub const INJECTION_FORMAT_KEY: &str = "{key}";
+
+/// Where the placeholder for a secret may be placed in requests made with it. Requests which place
+/// it anywhere else are refused, so that the secret cannot be sent where the upstream service
+/// might log or reflect it.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InjectionRule {
+    /// Only in the header with the given name, whose value must be the given format with
+    /// [INJECTION_FORMAT_KEY] replaced by the placeholder, for example `Bearer {key}`
+    Header { name: String, format: String },
+    /// Only as the whole value of the query parameter with the given name
+    QueryParameter { name: String },
+    /// Only in the request body
+    Body,
+}
+
+impl fmt::Display for InjectionRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Header { name, format } => write!(f, "in the {name} header as {format}"),
+            Self::QueryParameter { name } => write!(f, "as the {name} query parameter"),
+            Self::Body => write!(f, "in the request body"),
+        }
+    }
+}
+
+/// Which requests a secret may be used for, for example when it is given to an automated agent.
+/// Requests which place the placeholder for the secret are refused unless they meet every
+/// restriction given.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RequestPolicy {
+    /// HTTP methods which may be used. If empty, any method may be used
+    #[serde(default)]
+    pub methods: Vec<HttpMethod>,
+    /// Globs which the URL path must match one of, such as `/v1/images/*`. `*` matches within a
+    /// path segment and `**` matches any number of segments. If empty, any path may be used
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Names of the query parameters which may be given. If not given, any may be given
+    #[serde(default)]
+    pub query_parameters: Option<Vec<String>>,
+    /// Largest request body allowed, in bytes, before secrets are substituted
+    #[serde(default)]
+    pub max_body_size: Option<usize>,
+}
+
+/// When a deployed secret expires. Expired secrets will not be used, and are removed from the
+/// service shortly after expiry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Expiry {
+    /// Expire at the given unix time in seconds
+    At(u64),
+    /// Expire the given number of seconds after being deployed
+    Ttl(u64),
+}
+
+impl Expiry {
+    /// Get the unix time in seconds at which a secret deployed at the given time expires
+    pub fn expires_at(&self, deployed_at: u64) -> u64 {
+        match self {
+            Self::At(expires_at) => *expires_at,
+            Self::Ttl(ttl) => deployed_at.saturating_add(*ttl),
+        }
+    }
+}
+
+/// The shortest interval in seconds at which a secret may be rotated by the service
+pub const MIN_ROTATION_INTERVAL: u64 = 60;
+
+/// How the service rotates a secret itself, for providers with an API for creating and revoking
+/// keys. The new secret is only ever seen by the service.
+///
+/// The create request is made with the current secret, and the new secret is taken from its JSON
+/// response. The revoke request is then made with the new secret in place of the old one, and may
+/// refer to the old one with [OLD_API_KEY_PLACEHOLDER]. Other secrets deployed for the same service
+/// may also be used in either request. Both URLs must fall under the service of the secret.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RotationRecipe {
+    /// Number of seconds between rotations, at least [MIN_ROTATION_INTERVAL]
+    pub interval: u64,
+    /// Request which creates a new secret
+    pub create: RotationRequest,
+    /// JSON pointer to the new secret in the response of the create request, for example
+    /// `/data/secret`
+    pub new_key_pointer: String,
+    /// Request which revokes the old secret, if the provider needs one
+    #[serde(default)]
+    pub revoke: Option<RotationRequest>,
+}
+
+/// An HTTP request made by the service when rotating a secret, which may contain placeholders
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RotationRequest {
+    /// The HTTP verb to use
+    pub http_verb: HttpMethod,
+    /// The URL for the HTTP request
+    pub api_url: String,
+    /// The HTTP headers to use
+    #[serde(default)]
+    pub http_headers: Vec<(String, String)>,
+    /// Body of the HTTP request
+    #[serde(default)]
+    pub request_body: String,
 }
 
 /// Request payload for the `/delete-secret` HTTP route
@@ -20,8 +240,224 @@ pub struct DeployApiKeyInfo {
 pub struct DeleteApiKeyInfo {
     /// URL of the service to use it with
     pub api_url: String,
+    /// Name of the secret to delete. If not given, all secrets for the service are deleted
+    #[serde(default)]
+    pub secret_name: Option<String>,
+    /// Current unix time in seconds
+    pub timestamp: u64,
+    /// Random value making this request unique. The service rejects any request it has already
+    /// received, so this allows identical requests to be made within the same second
+    pub nonce: [u8; NONCE_LENGTH],
+}
+
+/// Request payload for the `/promote-api-key` HTTP route
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PromoteApiKeyInfo {
+    /// URL of the service the secret is used with
+    pub api_url: String,
+    /// Name of the secret. If not given, [DEFAULT_SECRET_NAME] is used
+    #[serde(default)]
+    pub secret_name: Option<String>,
+    /// Number of seconds for which the previously active version may still be used with
+    /// [KeyVersion::Previous], and restored with the `/rollback-api-key` route
+    pub grace_period: u64,
     /// Current unix time in seconds
     pub timestamp: u64,
+    /// Random value making this request unique. The service rejects any request it has already
+    /// received, so this allows identical requests to be made within the same second
+    pub nonce: [u8; NONCE_LENGTH],
+}
+
+/// Request payload for the `/rollback-api-key` HTTP route, which restores the previous version of
+/// a secret during its grace period, discarding the active version
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RollbackApiKeyInfo {
+    /// URL of the service the secret is used with
+    pub api_url: String,
+    /// Name of the secret. If not given, [DEFAULT_SECRET_NAME] is used
+    #[serde(default)]
+    pub secret_name: Option<String>,
+    /// Current unix time in seconds
+    pub timestamp: u64,
+    /// Random value making this request unique. The service rejects any request it has already
+    /// received, so this allows identical requests to be made within the same second
+    pub nonce: [u8; NONCE_LENGTH],
+}
+
+/// Which version of each secret to use when making a request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyVersion {
+    /// The active version
+    #[default]
+    Active,
+    /// The pending version, so that it can be tried out before being promoted. Requests using a
+    /// secret which has no pending version are refused.
+    Pending,
+    /// The previous version, for secrets which were promoted within their grace period. Requests
+    /// using a secret which has no usable previous version are refused.
+    Previous,
+}
+
+/// Request payload for the `/list-api-keys` HTTP route
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ListApiKeysInfo {
+    /// Current unix time in seconds
+    pub timestamp: u64,
+    /// Random value making this request unique. The service rejects any request it has already
+    /// received, so this allows identical requests to be made within the same second
+    pub nonce: [u8; NONCE_LENGTH],
+}
+
+/// Details of a deployed secret, as given in the response of the `/list-api-keys` HTTP route
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ApiKeyDetails {
+    /// The service the secret is used with: the origin and path prefix of the URL it was deployed
+    /// with
+    pub service: String,
+    /// Name of the secret
+    pub secret_name: String,
+    /// Unix time in seconds at which the secret was deployed
+    pub deployed_at: u64,
+    /// Unix time in seconds at which the secret expires, if it does
+    pub expires_at: Option<u64>,
+    /// Fingerprint of the secret, as given by [api_key_fingerprint]
+    pub fingerprint: String,
+    /// Version number of the active version of the secret
+    pub version: u32,
+    /// A version which has been deployed but not yet promoted, if any
+    pub pending: Option<ApiKeyVersionDetails>,
+    /// The version which was active before the last promotion, if it is within its grace period
+    pub previous: Option<ApiKeyVersionDetails>,
+    /// Unix time in seconds at which the service will next rotate the secret, if it has a
+    /// [RotationRecipe]
+    #[serde(default)]
+    pub next_rotation_at: Option<u64>,
+    /// Where the secret may be placed in requests, if it is restricted
+    #[serde(default)]
+    pub injection: Option<InjectionRule>,
+    /// Which requests the secret may be used for, if it is restricted
+    #[serde(default)]
+    pub policy: Option<RequestPolicy>,
+    /// The injection rule the pending version was deployed with, which replaces `injection` when
+    /// it is promoted, if it has its own
+    #[serde(default)]
+    pub pending_injection: Option<InjectionRule>,
+    /// The request policy the pending version was deployed with, which replaces `policy` when it
+    /// is promoted, if it has its own
+    #[serde(default)]
+    pub pending_policy: Option<RequestPolicy>,
+}
+
+/// Details of a pending or previous version of a deployed secret
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ApiKeyVersionDetails {
+    /// Version number
+    pub version: u32,
+    /// Unix time in seconds at which this version was deployed
+    pub deployed_at: u64,
+    /// Unix time in seconds at which this version expires, if it does
+    pub expires_at: Option<u64>,
+    /// Fingerprint of this version, as given by [api_key_fingerprint]
+    pub fingerprint: String,
+    /// For a previous version, the unix time in seconds at which its grace period ends
+    pub usable_until: Option<u64>,
+}
+
+/// Length in bytes of an api key fingerprint, before hex encoding
+pub const FINGERPRINT_LENGTH: usize = 8;
+
+/// Get a fingerprint of a secret, which can be used to tell which secret has been deployed
+/// without revealing it. It is the hex encoded start of a SHA256 hash over the secret and the
+/// account ID of its owner, so the same secret has a different fingerprint for different users.
+pub fn api_key_fingerprint(account_id: &[u8; 32], api_key: &str) -> String {
+    let hash = Sha256::new()
+        .chain_update(b"entropy-api-key-service-fingerprint")
+        .chain_update(account_id)
+        .chain_update(api_key.as_bytes())
+        .finalize();
+    hash[..FINGERPRINT_LENGTH]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Request payload for the `/export-api-keys` HTTP route. The response is an encrypted message
+/// containing an [ApiKeyBackup], signed by the service
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ExportApiKeysInfo {
+    /// X25519 public key to encrypt the backup to. Anyone with the corresponding secret key can
+    /// read all of your secrets from the backup, so it should be kept at least as safe as them
+    pub backup_x25519_public_key: [u8; 32],
+    /// Current unix time in seconds
+    pub timestamp: u64,
+    /// Random value making this request unique. The service rejects any request it has already
+    /// received, so this allows identical requests to be made within the same second
+    pub nonce: [u8; NONCE_LENGTH],
+}
+
+/// A backup of all of the secrets of an account, which is encrypted with associated data given by
+/// [backup_associated_data]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ApiKeyBackup {
+    /// Unix time in seconds at which the backup was made
+    pub exported_at: u64,
+    /// The secrets
+    pub api_keys: Vec<BackupApiKey>,
+}
+
+/// A secret as held in an [ApiKeyBackup]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct BackupApiKey {
+    /// The service the secret is used with: the origin and path prefix of the URL it was deployed
+    /// with
+    pub service: String,
+    /// Name of the secret
+    pub secret_name: String,
+    /// The active version
+    pub active: BackupApiKeyVersion,
+    /// A version which has been deployed but not yet promoted, if any
+    #[serde(default)]
+    pub pending: Option<BackupApiKeyVersion>,
+    /// How the service rotates the secret itself, if it does
+    #[serde(default)]
+    pub rotation: Option<RotationRecipe>,
+    /// Where the secret may be placed in requests, if it is restricted
+    #[serde(default)]
+    pub injection: Option<InjectionRule>,
+    /// Which requests the secret may be used for, if it is restricted
+    #[serde(default)]
+    pub policy: Option<RequestPolicy>,
+    /// The injection rule the pending version was deployed with, if it has its own
+    #[serde(default)]
+    pub pending_injection: Option<InjectionRule>,
+    /// The request policy the pending version was deployed with, if it has its own
+    #[serde(default)]
+    pub pending_policy: Option<RequestPolicy>,
+}
+
+/// A version of a secret as held in an [ApiKeyBackup]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct BackupApiKeyVersion {
+    /// The secret, which is wiped from memory when dropped
+    pub api_key: Zeroizing<String>,
+    /// Unix time in seconds at which this version expires, if it does
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+/// Request payload for the `/import-api-keys` HTTP route. Each secret replaces any existing secret
+/// with the same service and name, and expired secrets are skipped. The response gives the number
+/// of secrets imported.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ImportApiKeysInfo {
+    /// The secrets to import, taken from a decrypted [ApiKeyBackup]
+    pub api_keys: Vec<BackupApiKey>,
+    /// Current unix time in seconds
+    pub timestamp: u64,
+    /// Random value making this request unique. The service rejects any request it has already
+    /// received, so this allows identical requests to be made within the same second
+    pub nonce: [u8; NONCE_LENGTH],
 }
 
 /// Request payload for the `/make-request` HTTP route
@@ -30,11 +466,237 @@ pub struct SendApiKeyMessage {
     /// Body of the HTTP request
     pub request_body: String,
     /// The HTTP verb to use
-    pub http_verb: String,
+    pub http_verb: HttpMethod,
     /// The HTTP headers to use
     pub http_headers: Vec<(String, String)>,
     /// The URL for the HTTP request
     pub api_url: String,
+    /// Which version of each secret to use
+    #[serde(default)]
+    pub key_version: KeyVersion,
+    /// Whether to follow redirects to other origins, or to paths outside the service the secrets
+    /// were deployed for. Headers and query parameters containing secrets are removed when
+    /// following them. Other redirects within the same origin are always followed, unless the
+    /// request uses a secret which has a request policy, in which case no redirects are followed.
+    #[serde(default)]
+    pub follow_redirects: bool,
     /// Current unix time in seconds
     pub timestamp: u64,
+    /// Random value making this request unique. The service rejects any request it has already
+    /// received, so this allows identical requests to be made within the same second
+    pub nonce: [u8; NONCE_LENGTH],
+}
+
+/// Response payload of the `/make-request` HTTP route, giving the response from the upstream
+/// service
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ApiResponse {
+    /// HTTP status code of the upstream response
+    pub status: u16,
+    /// Upstream response headers as (name, value) pairs. Names are lowercase and may appear more
+    /// than once. Secrets are replaced with [REDACTED_SECRET]
+    pub headers: Vec<(String, Vec<u8>)>,
+    /// Upstream response body. Secrets are replaced with [REDACTED_SECRET]
+    pub body: Vec<u8>,
+    /// URL of the upstream response, which differs from the request URL if redirects were
+    /// followed. Secrets are replaced with [REDACTED_SECRET]
+    #[serde(default)]
+    pub url: String,
+    /// URLs which redirects were followed from, in order, starting with the request URL. Secrets
+    /// are replaced with [REDACTED_SECRET]
+    #[serde(default)]
+    pub redirects: Vec<String>,
+}
+
+impl ApiResponse {
+    /// Whether the upstream status code is in the range 200-299
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// Get the first value of the given header, if present and valid UTF8
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .and_then(|(_, value)| std::str::from_utf8(value).ok())
+    }
+
+    /// Get the body as text, replacing any invalid UTF8
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+}
+
+/// An HTTP method which may be used with the `/make-request` HTTP route
+///
+/// `CONNECT` is not supported as it does not make sense to proxy, and `TRACE` is not supported
+/// as it reflects request headers, including any substituted API key, back in the response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum HttpMethod {
+    #[serde(alias = "get")]
+    Get,
+    #[serde(alias = "head")]
+    Head,
+    #[serde(alias = "post")]
+    Post,
+    #[serde(alias = "put")]
+    Put,
+    #[serde(alias = "patch")]
+    Patch,
+    #[serde(alias = "delete")]
+    Delete,
+    #[serde(alias = "options")]
+    Options,
+}
+
+impl HttpMethod {
+    /// The method as it appears in an HTTP request
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Get => "GET",
+            Self::Head => "HEAD",
+            Self::Post => "POST",
+            Self::Put => "PUT",
+            Self::Patch => "PATCH",
+            Self::Delete => "DELETE",
+            Self::Options => "OPTIONS",
+        }
+    }
+
+    /// Whether a request body is forwarded with this method
+    pub fn allows_body(&self) -> bool {
+        !matches!(self, Self::Get | Self::Head)
+    }
+}
+
+impl fmt::Display for HttpMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for HttpMethod {
+    type Err = UnsupportedHttpMethod;
+
+    /// Parse a method, ignoring case
+    fn from_str(method: &str) -> Result<Self, Self::Err> {
+        match method.to_ascii_uppercase().as_str() {
+            "GET" => Ok(Self::Get),
+            "HEAD" => Ok(Self::Head),
+            "POST" => Ok(Self::Post),
+            "PUT" => Ok(Self::Put),
+            "PATCH" => Ok(Self::Patch),
+            "DELETE" => Ok(Self::Delete),
+            "OPTIONS" => Ok(Self::Options),
+            _ => Err(UnsupportedHttpMethod(method.to_string())),
+        }
+    }
+}
+
+/// Error given when parsing an HTTP method which is not supported
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnsupportedHttpMethod(pub String);
+
+impl fmt::Display for UnsupportedHttpMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unsupported HTTP method {}", self.0)
+    }
 }
+
+impl std::error::Error for UnsupportedHttpMethod {}
+
+/// Machine readable code identifying the kind of error returned by the service
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request was malformed, such as a message which is not valid JSON (HTTP 400)
+    BadRequest,
+    /// The URL of the request could not be parsed (HTTP 400)
+    InvalidUrl,
+    /// The URL of the request has no host (HTTP 400)
+    UrlHasNoHost,
+    /// The URL of the request is not an http or https URL (HTTP 400)
+    UnsupportedUrlScheme,
+    /// The HTTP method of the request is not supported (HTTP 400)
+    UnsupportedHttpMethod,
+    /// A header of the request is not a valid HTTP header (HTTP 400)
+    InvalidHeader,
+    /// The secret name is not valid (HTTP 400)
+    InvalidSecretName,
+    /// The expiry given when deploying a secret has already passed (HTTP 400)
+    ExpiryInPast,
+    /// The rotation recipe given when deploying a secret is not valid (HTTP 400)
+    InvalidRotationRecipe,
+    /// The injection rule given when deploying a secret is not valid (HTTP 400)
+    InvalidInjectionRule,
+    /// The request policy given when deploying a secret is not valid (HTTP 400)
+    InvalidRequestPolicy,
+    /// A backup being imported is not valid (HTTP 400)
+    InvalidBackup,
+    /// The request did not include a key to encrypt the response to (HTTP 400)
+    NoResponseKey,
+    /// The request could not be decrypted or its signature was invalid (HTTP 401)
+    Unauthenticated,
+    /// The quote of another instance of the service was not accepted (HTTP 401)
+    QuoteRejected,
+    /// A migration request from another instance of the service was not accepted (HTTP 401)
+    MigrationRejected,
+    /// The request timestamp is too far from the server's current time (HTTP 401)
+    Stale,
+    /// The destination of the request is not public, and has not been allowed (HTTP 403)
+    EgressDenied,
+    /// A secret was placed somewhere in the request which its injection rule does not allow
+    /// (HTTP 403)
+    InjectionNotAllowed,
+    /// The request uses a secret but is not allowed by the secret's request policy (HTTP 403)
+    PolicyViolation,
+    /// No secret has been deployed for the service the request is for (HTTP 404)
+    NoKeyForService,
+    /// There is no secret with the given service and secret name (HTTP 404)
+    NoSuchApiKey,
+    /// A secret for the service the request is for has expired (HTTP 410)
+    ApiKeyExpired,
+    /// The secret has no pending version (HTTP 409)
+    NoPendingVersion,
+    /// The secret has no previous version, or its grace period has ended (HTTP 409)
+    NoPreviousVersion,
+    /// The request has already been received (HTTP 409)
+    Replayed,
+    /// Too many recent requests have been made from this account (HTTP 429)
+    RateLimited,
+    /// Error from the server which is not caused by the request (HTTP 500)
+    Internal,
+    /// The upstream service could not be reached (HTTP 502)
+    Upstream,
+    /// Rotating a secret with the provider's API failed (HTTP 502)
+    RotationFailed,
+    /// A dependency of the service, such as the chain, is unavailable (HTTP 503)
+    Unavailable,
+}
+
+impl ErrorCode {
+    /// Whether the same request may succeed if retried later. Requests must be re-signed with a
+    /// new nonce and timestamp to be retried.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::RateLimited | Self::Upstream | Self::Unavailable)
+    }
+}
+
+/// Body of an error response from the service
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ErrorResponse {
+    /// The kind of error
+    pub code: ErrorCode,
+    /// Human readable description of the error
+    pub message: String,
+}
+
+impl fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ErrorResponse {}
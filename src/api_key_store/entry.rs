@@ -0,0 +1,266 @@
+use crate::errors::Err;
+use entropy_api_key_service_shared::{InjectionRule, KeyVersion, RequestPolicy, RotationRecipe};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use zeroize::Zeroizing;
+
+/// A stored api key, with the versions kept for rotation
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeyEntry {
+    /// The version which is used by default
+    pub active: ApiKeyVersion,
+    /// A version which has been deployed but not yet promoted
+    #[serde(default)]
+    pub pending: Option<ApiKeyVersion>,
+    /// The version which was active before the last promotion
+    #[serde(default)]
+    pub previous: Option<ApiKeyVersion>,
+    /// Unix time in seconds at which the grace period of the previous version ends
+    #[serde(default)]
+    pub previous_usable_until: Option<u64>,
+    /// How the service rotates the active version itself, if it does
+    #[serde(default)]
+    pub rotation: Option<KeyRotation>,
+    /// Where the api key may be placed in requests, if it is restricted
+    #[serde(default)]
+    pub injection: Option<InjectionRule>,
+    /// Which requests the api key may be used for, if it is restricted
+    #[serde(default)]
+    pub policy: Option<RequestPolicy>,
+    /// The injection rule the pending version was deployed with, which replaces `injection` when
+    /// it is promoted. If not given, the pending version has the same rule as the active version
+    #[serde(default)]
+    pub pending_injection: Option<InjectionRule>,
+    /// The request policy the pending version was deployed with, which replaces `policy` when it
+    /// is promoted. If not given, the pending version has the same policy as the active version
+    #[serde(default)]
+    pub pending_policy: Option<RequestPolicy>,
+}
+
+/// Schedule for rotating an api key with a provider's API
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyRotation {
+    /// How to rotate the key
+    pub recipe: RotationRecipe,
+    /// Unix time in seconds at which to next rotate the key
+    pub next_rotation_at: u64,
+}
+
+/// A single version of a stored api key
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeyVersion {
+    /// Version number, which increases with each deployment to the same api key ID
+    pub version: u32,
+    /// The secret api key
+    pub api_key: SecretString,
+    /// Unix time in seconds at which this version was deployed
+    pub deployed_at: u64,
+    /// Unix time in seconds at which this version expires, if it does
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+/// A secret which is wiped from memory when dropped, and is not shown in debug output
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct SecretString(Zeroizing<String>);
+
+impl SecretString {
+    /// Get the secret. Any copies made of it will not be wiped, so this should be used without
+    /// copying where possible
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(secret: String) -> Self {
+        Self(Zeroizing::new(secret))
+    }
+}
+
+impl From<Zeroizing<String>> for SecretString {
+    fn from(secret: Zeroizing<String>) -> Self {
+        Self(secret)
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretString(..)")
+    }
+}
+
+impl Serialize for SecretString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self::from)
+    }
+}
+
+impl ApiKeyVersion {
+    /// Whether this version has expired at the given unix time in seconds
+    pub fn is_expired(&self, current_timestamp: u64) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= current_timestamp)
+    }
+}
+
+impl ApiKeyEntry {
+    /// Create an entry with only an active version
+    pub fn new(active: ApiKeyVersion) -> Self {
+        Self {
+            active,
+            pending: None,
+            previous: None,
+            previous_usable_until: None,
+            rotation: None,
+            injection: None,
+            policy: None,
+            pending_injection: None,
+            pending_policy: None,
+        }
+    }
+
+    /// Whether the active version has expired at the given unix time in seconds
+    pub fn is_expired(&self, current_timestamp: u64) -> bool {
+        self.active.is_expired(current_timestamp)
+    }
+
+    /// Whether the service should rotate the active version at the given unix time in seconds
+    pub fn is_due_for_rotation(&self, current_timestamp: u64) -> bool {
+        self.rotation
+            .as_ref()
+            .is_some_and(|rotation| rotation.next_rotation_at <= current_timestamp)
+            && !self.is_expired(current_timestamp)
+    }
+
+    /// The version number to give the next version deployed
+    pub fn next_version(&self) -> u32 {
+        [
+            Some(&self.active),
+            self.pending.as_ref(),
+            self.previous.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|version| version.version)
+        .max()
+        .unwrap_or_default()
+            + 1
+    }
+
+    /// The previous version, if it is within its grace period
+    pub fn usable_previous(&self, current_timestamp: u64) -> Option<&ApiKeyVersion> {
+        let usable_until = self.previous_usable_until?;
+        self.previous.as_ref().filter(|previous| {
+            usable_until > current_timestamp && !previous.is_expired(current_timestamp)
+        })
+    }
+
+    /// Get the version to use for a request, or an error if the requested version is not
+    /// available. Another version is never used instead, so that trying out a version cannot
+    /// silently use a different one.
+    pub fn select(
+        &self,
+        key_version: KeyVersion,
+        current_timestamp: u64,
+    ) -> Result<&ApiKeyVersion, Err> {
+        match key_version {
+            KeyVersion::Active => Ok(&self.active),
+            KeyVersion::Pending => self.pending.as_ref().ok_or(Err::NoPendingVersion),
+            KeyVersion::Previous => self
+                .usable_previous(current_timestamp)
+                .ok_or(Err::NoPreviousVersion),
+        }
+    }
+
+    /// Where the given version may be placed in requests, if it is restricted
+    pub fn injection_for(&self, key_version: KeyVersion) -> Option<&InjectionRule> {
+        match key_version {
+            KeyVersion::Pending => self.pending_injection.as_ref().or(self.injection.as_ref()),
+            KeyVersion::Active | KeyVersion::Previous => self.injection.as_ref(),
+        }
+    }
+
+    /// Which requests the given version may be used for, if it is restricted
+    pub fn policy_for(&self, key_version: KeyVersion) -> Option<&RequestPolicy> {
+        match key_version {
+            KeyVersion::Pending => self.pending_policy.as_ref().or(self.policy.as_ref()),
+            KeyVersion::Active | KeyVersion::Previous => self.policy.as_ref(),
+        }
+    }
+
+    /// Add a pending version, with the injection rule and request policy it should have once
+    /// promoted if they differ from those of the active version. Replaces any existing pending
+    /// version.
+    pub fn set_pending(
+        &mut self,
+        pending: ApiKeyVersion,
+        injection: Option<InjectionRule>,
+        policy: Option<RequestPolicy>,
+    ) {
+        self.pending = Some(pending);
+        self.pending_injection = injection;
+        self.pending_policy = policy;
+    }
+
+    /// Make the pending version active, together with any injection rule and request policy it
+    /// was deployed with, keeping the currently active version usable for the given number of
+    /// seconds
+    pub fn promote(&mut self, grace_period: u64, current_timestamp: u64) -> Result<(), Err> {
+        let pending = self.pending.take().ok_or(Err::NoPendingVersion)?;
+        if let Some(rule) = self.pending_injection.take() {
+            self.injection = Some(rule);
+        }
+        if let Some(policy) = self.pending_policy.take() {
+            self.policy = Some(policy);
+        }
+        let previous = std::mem::replace(&mut self.active, pending);
+        self.previous = Some(previous);
+        self.previous_usable_until = Some(current_timestamp.saturating_add(grace_period));
+        Ok(())
+    }
+
+    /// Restore the previous version if it is within its grace period, discarding the active
+    /// version
+    pub fn rollback(&mut self, current_timestamp: u64) -> Result<(), Err> {
+        if self.usable_previous(current_timestamp).is_none() {
+            return Err(Err::NoPreviousVersion);
+        }
+        self.active = self.previous.take().ok_or(Err::NoPreviousVersion)?;
+        self.previous_usable_until = None;
+        Ok(())
+    }
+
+    /// Remove a pending version which has expired, and a previous version whose grace period has
+    /// ended, returning how many were removed
+    pub fn remove_expired_versions(&mut self, current_timestamp: u64) -> usize {
+        let mut removed = 0;
+        if self
+            .pending
+            .as_ref()
+            .is_some_and(|pending| pending.is_expired(current_timestamp))
+        {
+            self.pending = None;
+            self.pending_injection = None;
+            self.pending_policy = None;
+            removed += 1;
+        }
+        if self.previous.is_some() && self.usable_previous(current_timestamp).is_none() {
+            self.previous = None;
+            self.previous_usable_until = None;
+            removed += 1;
+        }
+        removed
+    }
+
+    /// The number of versions held
+    pub fn version_count(&self) -> usize {
+        1 + usize::from(self.pending.is_some()) + usize::from(self.previous.is_some())
+    }
+}
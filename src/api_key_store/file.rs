@@ -0,0 +1,100 @@
+use super::{
+    ApiKeyEntry, ApiKeyId, ApiKeyStore, InMemoryApiKeyStore,
+    memory::{ApiKeys, remove_expired},
+};
+use crate::{
+    errors::Err,
+    sealing::{SealedFile, TamperPolicy},
+};
+use async_trait::async_trait;
+use std::sync::Arc;
+use zeroize::Zeroizing;
+
+/// Api key storage which is held in memory and written through to a sealed file on every change.
+/// Changes are only made in memory once they have been persisted, so that memory and the file
+/// agree even if persisting fails.
+pub struct FileApiKeyStore {
+    api_keys: InMemoryApiKeyStore,
+    sealed_file: Arc<SealedFile>,
+}
+
+impl FileApiKeyStore {
+    /// Open the store, loading any api keys which were previously persisted to the file
+    pub fn open(sealed_file: SealedFile, tamper_policy: TamperPolicy) -> Result<Self, Err> {
+        let api_keys = match sealed_file.load() {
+            Ok(Some(plaintext)) => {
+                let entries: Vec<(ApiKeyId, ApiKeyEntry)> = serde_json::from_slice(&plaintext)?;
+                entries.into_iter().collect()
+            }
+            Ok(None) => Default::default(),
+            Err(Err::SealedDataTampered) if tamper_policy == TamperPolicy::StartEmpty => {
+                tracing::error!("Persisted api keys failed authentication - starting with none");
+                Default::default()
+            }
+            Err(error) => return Err(error),
+        };
+
+        Ok(Self {
+            api_keys: InMemoryApiKeyStore::from_api_keys(api_keys),
+            sealed_file: Arc::new(sealed_file),
+        })
+    }
+
+    /// Seal and write the given api keys to the file. This is done on a blocking thread, as it
+    /// waits for the file to reach the disk.
+    async fn persist(&self, api_keys: &ApiKeys) -> Result<(), Err> {
+        let entries: Vec<_> = api_keys.iter().collect();
+        let plaintext = Zeroizing::new(serde_json::to_vec(&entries)?);
+        let sealed_file = self.sealed_file.clone();
+        tokio::task::spawn_blocking(move || sealed_file.store(&plaintext)).await?
+    }
+}
+
+#[async_trait]
+impl ApiKeyStore for FileApiKeyStore {
+    async fn get(&self, id: &ApiKeyId) -> Result<Option<ApiKeyEntry>, Err> {
+        self.api_keys.get(id).await
+    }
+
+    async fn put(&self, id: ApiKeyId, entry: ApiKeyEntry) -> Result<(), Err> {
+        // The lock is held while persisting so that writes to the file happen in the same order
+        let mut api_keys = self.api_keys.write().await;
+        let mut updated = api_keys.clone();
+        updated.insert(id, entry);
+        self.persist(&updated).await?;
+        *api_keys = updated;
+        Ok(())
+    }
+
+    async fn delete(&self, id: &ApiKeyId) -> Result<(), Err> {
+        let mut api_keys = self.api_keys.write().await;
+        let mut updated = api_keys.clone();
+        updated.remove(id);
+        self.persist(&updated).await?;
+        *api_keys = updated;
+        Ok(())
+    }
+
+    async fn list(&self, account_id: &[u8; 32]) -> Result<Vec<(ApiKeyId, ApiKeyEntry)>, Err> {
+        self.api_keys.list(account_id).await
+    }
+
+    async fn list_all(&self) -> Result<Vec<(ApiKeyId, ApiKeyEntry)>, Err> {
+        self.api_keys.list_all().await
+    }
+
+    async fn delete_expired(&self, current_timestamp: u64) -> Result<usize, Err> {
+        let mut api_keys = self.api_keys.write().await;
+        let mut updated = api_keys.clone();
+        let purged = remove_expired(&mut updated, current_timestamp);
+        if purged > 0 {
+            self.persist(&updated).await?;
+            *api_keys = updated;
+        }
+        Ok(purged)
+    }
+
+    async fn list_due_for_rotation(&self, current_timestamp: u64) -> Result<Vec<ApiKeyId>, Err> {
+        self.api_keys.list_due_for_rotation(current_timestamp).await
+    }
+}
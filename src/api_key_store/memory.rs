@@ -0,0 +1,89 @@
+use super::{ApiKeyEntry, ApiKeyId, ApiKeyStore};
+use crate::errors::Err;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use tokio::sync::{RwLock, RwLockWriteGuard};
+
+/// Map of api key ID to api key
+pub(super) type ApiKeys = HashMap<ApiKeyId, ApiKeyEntry>;
+
+/// Api key storage which only lives in memory
+#[derive(Default)]
+pub struct InMemoryApiKeyStore {
+    api_keys: RwLock<ApiKeys>,
+}
+
+impl InMemoryApiKeyStore {
+    pub(super) fn from_api_keys(api_keys: ApiKeys) -> Self {
+        Self {
+            api_keys: RwLock::new(api_keys),
+        }
+    }
+
+    /// Get write access to the underlying map
+    pub(super) async fn write(&self) -> RwLockWriteGuard<'_, ApiKeys> {
+        self.api_keys.write().await
+    }
+}
+
+/// Remove all expired api key versions from the given map, returning how many were removed
+pub(super) fn remove_expired(api_keys: &mut ApiKeys, current_timestamp: u64) -> usize {
+    let mut removed = 0;
+    api_keys.retain(|_, entry| {
+        if entry.is_expired(current_timestamp) {
+            removed += entry.version_count();
+            false
+        } else {
+            removed += entry.remove_expired_versions(current_timestamp);
+            true
+        }
+    });
+    removed
+}
+
+#[async_trait]
+impl ApiKeyStore for InMemoryApiKeyStore {
+    async fn get(&self, id: &ApiKeyId) -> Result<Option<ApiKeyEntry>, Err> {
+        Ok(self.api_keys.read().await.get(id).cloned())
+    }
+
+    async fn put(&self, id: ApiKeyId, entry: ApiKeyEntry) -> Result<(), Err> {
+        self.write().await.insert(id, entry);
+        Ok(())
+    }
+
+    async fn delete(&self, id: &ApiKeyId) -> Result<(), Err> {
+        self.write().await.remove(id);
+        Ok(())
+    }
+
+    async fn list(&self, account_id: &[u8; 32]) -> Result<Vec<(ApiKeyId, ApiKeyEntry)>, Err> {
+        let api_keys = self.api_keys.read().await;
+        Ok(api_keys
+            .iter()
+            .filter(|(id, _)| &id.account_id == account_id)
+            .map(|(id, entry)| (id.clone(), entry.clone()))
+            .collect())
+    }
+
+    async fn list_all(&self) -> Result<Vec<(ApiKeyId, ApiKeyEntry)>, Err> {
+        let api_keys = self.api_keys.read().await;
+        Ok(api_keys
+            .iter()
+            .map(|(id, entry)| (id.clone(), entry.clone()))
+            .collect())
+    }
+
+    async fn delete_expired(&self, current_timestamp: u64) -> Result<usize, Err> {
+        Ok(remove_expired(&mut *self.write().await, current_timestamp))
+    }
+
+    async fn list_due_for_rotation(&self, current_timestamp: u64) -> Result<Vec<ApiKeyId>, Err> {
+        let api_keys = self.api_keys.read().await;
+        Ok(api_keys
+            .iter()
+            .filter(|(_, entry)| entry.is_due_for_rotation(current_timestamp))
+            .map(|(id, _)| id.clone())
+            .collect())
+    }
+}
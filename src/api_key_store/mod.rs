@@ -0,0 +1,95 @@
+//! Storage backends for deployed api keys
+//!
+//! Api keys are held as [SecretString]s, so they are wiped from memory when deleted, replaced or
+//! expired. The README describes where else plaintext keys may be found in memory.
+mod entry;
+mod file;
+mod memory;
+
+#[cfg(test)]
+mod tests;
+
+pub use entry::{ApiKeyEntry, ApiKeyVersion, KeyRotation, SecretString};
+pub use file::FileApiKeyStore;
+pub use memory::InMemoryApiKeyStore;
+
+use crate::{api_keys::api::get_current_timestamp, errors::Err};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+
+/// Which [ApiKeyStore] implementation to use
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum ApiKeyStoreType {
+    /// Hold api keys in memory only. They will be lost on restart
+    Memory,
+    /// Hold api keys in memory and write them through to a sealed file
+    #[default]
+    File,
+}
+
+/// Identifies a stored api key
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ApiKeyId {
+    /// Account ID of the owner
+    pub account_id: [u8; 32],
+    /// The service the key is used with, as given by [crate::api_keys::service::service_from_url]
+    pub service: String,
+    /// Name of the secret, as services may need several
+    pub secret_name: String,
+}
+
+/// A storage backend for api keys
+#[async_trait]
+pub trait ApiKeyStore: Send + Sync {
+    /// Get an api key, returning `None` if there is no such key
+    async fn get(&self, id: &ApiKeyId) -> Result<Option<ApiKeyEntry>, Err>;
+
+    /// Store an api key, replacing any existing key with the same ID
+    async fn put(&self, id: ApiKeyId, entry: ApiKeyEntry) -> Result<(), Err>;
+
+    /// Remove an api key. This is not an error if there is no such key
+    async fn delete(&self, id: &ApiKeyId) -> Result<(), Err>;
+
+    /// List the api keys of a given account
+    async fn list(&self, account_id: &[u8; 32]) -> Result<Vec<(ApiKeyId, ApiKeyEntry)>, Err>;
+
+    /// List the api keys of every account
+    async fn list_all(&self) -> Result<Vec<(ApiKeyId, ApiKeyEntry)>, Err>;
+
+    /// Remove all api keys whose active version has expired at the given unix time
+    /// in seconds, as well as expired pending versions and previous versions whose grace period
+    /// has ended, returning how many versions were removed
+    async fn delete_expired(&self, current_timestamp: u64) -> Result<usize, Err>;
+
+    /// List the IDs of api keys which the service should rotate at the given unix time in seconds
+    async fn list_due_for_rotation(&self, current_timestamp: u64) -> Result<Vec<ApiKeyId>, Err>;
+}
+
+/// How often to check for and remove expired api keys
+pub const PURGE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Periodically remove expired api keys from the given store. This runs forever, so should be
+/// spawned as a task.
+///
+/// The given update lock is held while purging, so that a handler which read an api key before it
+/// was purged cannot write the expired key back.
+pub async fn purge_expired_api_keys(api_keys: Arc<dyn ApiKeyStore>, update_lock: Arc<Mutex<()>>) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let result = match get_current_timestamp() {
+            Ok(current_timestamp) => {
+                let _update_guard = update_lock.lock().await;
+                api_keys.delete_expired(current_timestamp).await
+            }
+            Err(error) => Err(error),
+        };
+        match result {
+            Ok(0) => {}
+            Ok(purged) => tracing::info!("Removed {purged} expired api keys"),
+            Err(error) => tracing::error!("Failed to remove expired api keys: {error}"),
+        }
+    }
+}
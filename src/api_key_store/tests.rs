@@ -0,0 +1,342 @@
+use super::{
+    ApiKeyEntry, ApiKeyId, ApiKeyStore, ApiKeyVersion, FileApiKeyStore, InMemoryApiKeyStore,
+    KeyRotation,
+};
+use crate::{
+    errors::Err,
+    sealing::{SealedFile, TamperPolicy, get_sealing_key},
+    test_helpers::temporary_path,
+};
+use entropy_api_key_service_shared::{
+    HttpMethod, InjectionRule, KeyVersion, RequestPolicy, RotationRecipe, RotationRequest,
+};
+use std::path::Path;
+
+fn open_file_store(path: &Path, tamper_policy: TamperPolicy) -> Result<FileApiKeyStore, Err> {
+    FileApiKeyStore::open(
+        SealedFile::new(path.to_path_buf(), get_sealing_key().unwrap()),
+        tamper_policy,
+    )
+}
+
+fn api_key_id(account_id: [u8; 32], secret_name: &str) -> ApiKeyId {
+    ApiKeyId {
+        account_id,
+        service: "https://api.example.com/".to_string(),
+        secret_name: secret_name.to_string(),
+    }
+}
+
+fn api_key_version(version: u32, api_key: &str) -> ApiKeyVersion {
+    ApiKeyVersion {
+        version,
+        api_key: api_key.to_string().into(),
+        deployed_at: 1000,
+        expires_at: None,
+    }
+}
+
+fn api_key_entry(api_key: &str) -> ApiKeyEntry {
+    ApiKeyEntry::new(api_key_version(1, api_key))
+}
+
+fn rotation_recipe() -> RotationRecipe {
+    RotationRecipe {
+        interval: 3600,
+        create: RotationRequest {
+            http_verb: HttpMethod::Post,
+            api_url: "https://api.example.com/keys".to_string(),
+            http_headers: vec![],
+            request_body: String::new(),
+        },
+        new_key_pointer: "/key".to_string(),
+        revoke: None,
+    }
+}
+
+/// Checks the behaviour which all api key stores should have
+async fn check_api_key_store(store: &dyn ApiKeyStore) {
+    let id = api_key_id([1; 32], "default");
+    assert!(store.get(&id).await.unwrap().is_none());
+
+    store
+        .put(id.clone(), api_key_entry("some-secret"))
+        .await
+        .unwrap();
+    store
+        .put(
+            api_key_id([2; 32], "default"),
+            api_key_entry("other-secret"),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        store.get(&id).await.unwrap(),
+        Some(api_key_entry("some-secret"))
+    );
+    assert_eq!(
+        store.list(&[1; 32]).await.unwrap(),
+        vec![(id.clone(), api_key_entry("some-secret"))]
+    );
+    assert_eq!(store.list_all().await.unwrap().len(), 2);
+
+    store.delete(&id).await.unwrap();
+    assert!(store.get(&id).await.unwrap().is_none());
+    assert!(store.list(&[1; 32]).await.unwrap().is_empty());
+    assert_eq!(store.list(&[2; 32]).await.unwrap().len(), 1);
+
+    let expiring_id = api_key_id([1; 32], "expiring");
+    let mut expiring_entry = api_key_entry("expiring-secret");
+    expiring_entry.active.expires_at = Some(2000);
+    store
+        .put(expiring_id.clone(), expiring_entry.clone())
+        .await
+        .unwrap();
+    assert_eq!(store.delete_expired(1999).await.unwrap(), 0);
+    assert_eq!(store.get(&expiring_id).await.unwrap(), Some(expiring_entry));
+    assert_eq!(store.delete_expired(2000).await.unwrap(), 1);
+    assert!(store.get(&expiring_id).await.unwrap().is_none());
+    assert_eq!(store.list(&[2; 32]).await.unwrap().len(), 1);
+
+    // Expired pending versions and previous versions past their grace period are removed, but
+    // the active version is kept
+    let mut rotating_entry = api_key_entry("old-secret");
+    rotating_entry.pending = Some(api_key_version(2, "new-secret"));
+    rotating_entry.promote(500, 1000).unwrap();
+    rotating_entry.pending = Some(ApiKeyVersion {
+        expires_at: Some(1600),
+        ..api_key_version(3, "newer-secret")
+    });
+    store
+        .put(expiring_id.clone(), rotating_entry.clone())
+        .await
+        .unwrap();
+    assert_eq!(store.delete_expired(1499).await.unwrap(), 0);
+    assert_eq!(store.delete_expired(1600).await.unwrap(), 2);
+    assert_eq!(
+        store.get(&expiring_id).await.unwrap(),
+        Some(ApiKeyEntry::new(api_key_version(2, "new-secret")))
+    );
+
+    // Keys with a rotation recipe are listed once they are due
+    let rotated_id = api_key_id([1; 32], "rotated");
+    let rotated_entry = ApiKeyEntry {
+        rotation: Some(KeyRotation {
+            recipe: rotation_recipe(),
+            next_rotation_at: 3000,
+        }),
+        ..api_key_entry("rotated-secret")
+    };
+    store.put(rotated_id.clone(), rotated_entry).await.unwrap();
+    assert!(store.list_due_for_rotation(2999).await.unwrap().is_empty());
+    assert_eq!(
+        store.list_due_for_rotation(3000).await.unwrap(),
+        vec![rotated_id]
+    );
+}
+
+#[tokio::test]
+async fn test_in_memory_api_key_store() {
+    check_api_key_store(&InMemoryApiKeyStore::default()).await;
+}
+
+#[tokio::test]
+async fn test_file_api_key_store() {
+    let path = temporary_path("file-api-key-store");
+    check_api_key_store(&open_file_store(&path, TamperPolicy::Refuse).unwrap()).await;
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_api_keys_persist_across_restarts() {
+    let path = temporary_path("api-keys");
+    let id = api_key_id([1; 32], "default");
+
+    let store = open_file_store(&path, TamperPolicy::Refuse).unwrap();
+    store
+        .put(id.clone(), api_key_entry("some-secret"))
+        .await
+        .unwrap();
+    drop(store);
+
+    let store = open_file_store(&path, TamperPolicy::Refuse).unwrap();
+    assert_eq!(
+        store.get(&id).await.unwrap(),
+        Some(api_key_entry("some-secret"))
+    );
+
+    store.delete(&id).await.unwrap();
+    drop(store);
+
+    let store = open_file_store(&path, TamperPolicy::Refuse).unwrap();
+    assert!(store.get(&id).await.unwrap().is_none());
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_failed_persist_leaves_api_keys_unchanged() {
+    // The directory does not exist, so the file cannot be written
+    let path = temporary_path("missing-directory").join("api-keys");
+    let store = open_file_store(&path, TamperPolicy::Refuse).unwrap();
+    let id = api_key_id([1; 32], "default");
+
+    assert!(
+        store
+            .put(id.clone(), api_key_entry("some-secret"))
+            .await
+            .is_err()
+    );
+    assert!(store.get(&id).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_tampered_api_keys() {
+    let path = temporary_path("tampered-api-keys");
+    let id = api_key_id([1; 32], "default");
+
+    let store = open_file_store(&path, TamperPolicy::Refuse).unwrap();
+    store
+        .put(id.clone(), api_key_entry("some-secret"))
+        .await
+        .unwrap();
+    drop(store);
+
+    let mut sealed = std::fs::read(&path).unwrap();
+    sealed[30] ^= 1;
+    std::fs::write(&path, sealed).unwrap();
+
+    assert!(matches!(
+        open_file_store(&path, TamperPolicy::Refuse),
+        Err(Err::SealedDataTampered)
+    ));
+
+    let store = open_file_store(&path, TamperPolicy::StartEmpty).unwrap();
+    assert!(store.get(&id).await.unwrap().is_none());
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_api_key_versions() {
+    let mut entry = api_key_entry("old-secret");
+    assert_eq!(entry.next_version(), 2);
+    assert!(matches!(
+        entry.promote(100, 1000),
+        Err(Err::NoPendingVersion)
+    ));
+    assert!(matches!(entry.rollback(1000), Err(Err::NoPreviousVersion)));
+
+    entry.pending = Some(api_key_version(2, "new-secret"));
+    assert_eq!(entry.next_version(), 3);
+    assert_eq!(
+        entry
+            .select(KeyVersion::Active, 1000)
+            .unwrap()
+            .api_key
+            .expose(),
+        "old-secret"
+    );
+    assert_eq!(
+        entry
+            .select(KeyVersion::Pending, 1000)
+            .unwrap()
+            .api_key
+            .expose(),
+        "new-secret"
+    );
+    // The active version is not used instead when there is no previous version
+    assert!(matches!(
+        entry.select(KeyVersion::Previous, 1000),
+        Err(Err::NoPreviousVersion)
+    ));
+
+    entry.promote(100, 1000).unwrap();
+    assert!(entry.pending.is_none());
+    assert_eq!(
+        entry
+            .select(KeyVersion::Active, 1000)
+            .unwrap()
+            .api_key
+            .expose(),
+        "new-secret"
+    );
+    assert!(matches!(
+        entry.select(KeyVersion::Pending, 1000),
+        Err(Err::NoPendingVersion)
+    ));
+    assert_eq!(
+        entry
+            .select(KeyVersion::Previous, 1099)
+            .unwrap()
+            .api_key
+            .expose(),
+        "old-secret"
+    );
+    // The grace period has ended
+    assert!(matches!(
+        entry.select(KeyVersion::Previous, 1100),
+        Err(Err::NoPreviousVersion)
+    ));
+    assert!(matches!(entry.rollback(1100), Err(Err::NoPreviousVersion)));
+
+    entry.rollback(1099).unwrap();
+    assert_eq!(entry, api_key_entry("old-secret"));
+    assert!(matches!(entry.rollback(1099), Err(Err::NoPreviousVersion)));
+}
+
+#[test]
+fn test_pending_version_restrictions() {
+    let active_policy = RequestPolicy {
+        paths: vec!["/v1/*".to_string()],
+        ..Default::default()
+    };
+    let pending_policy = RequestPolicy {
+        paths: vec!["/v2/*".to_string()],
+        ..Default::default()
+    };
+    let mut entry = ApiKeyEntry {
+        injection: Some(InjectionRule::Body),
+        policy: Some(active_policy.clone()),
+        ..api_key_entry("old-secret")
+    };
+
+    // The pending version has the active version's rule unless it was deployed with its own
+    entry.set_pending(
+        api_key_version(2, "new-secret"),
+        None,
+        Some(pending_policy.clone()),
+    );
+    assert_eq!(entry.policy_for(KeyVersion::Active), Some(&active_policy));
+    assert_eq!(entry.policy_for(KeyVersion::Pending), Some(&pending_policy));
+    assert_eq!(
+        entry.injection_for(KeyVersion::Pending),
+        Some(&InjectionRule::Body)
+    );
+
+    entry.promote(100, 1000).unwrap();
+    assert_eq!(entry.policy, Some(pending_policy.clone()));
+    assert_eq!(entry.injection, Some(InjectionRule::Body));
+    assert!(entry.pending_policy.is_none());
+
+    // The restrictions of a pending version are removed with it
+    entry.set_pending(
+        ApiKeyVersion {
+            expires_at: Some(1100),
+            ..api_key_version(3, "newer-secret")
+        },
+        None,
+        Some(active_policy),
+    );
+    entry.remove_expired_versions(1100);
+    assert!(entry.pending.is_none());
+    assert!(entry.pending_policy.is_none());
+    assert_eq!(entry.policy_for(KeyVersion::Pending), Some(&pending_policy));
+}
+
+#[test]
+fn test_secrets_are_not_shown_in_debug_output() {
+    let entry = api_key_entry("some-secret");
+    assert!(!format!("{entry:?}").contains("some-secret"));
+    assert_eq!(entry.active.api_key.expose(), "some-secret");
+}
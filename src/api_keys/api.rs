@@ -1,67 +1,481 @@
+use super::{
+    backup::{from_backup, to_backup},
+    injection::{check_injection_rule, check_placeholders_allowed, uses_secret},
+    policy::{check_request_allowed, check_request_policy},
+    rotation::check_rotation_recipe,
+    service::{longest_matching_service, service_from_url},
+    substitution::Substitutions,
+    upstream::{FollowRedirects, UpstreamOptions, send_with_substitutions},
+};
 use crate::{
-    app_state::AppState, errors::Err, DeleteApiKeyInfo, DeployApiKeyInfo, SendApiKeyMessage,
+    DeleteApiKeyInfo, DeployApiKeyInfo, SendApiKeyMessage,
+    api_key_store::{ApiKeyEntry, ApiKeyId, ApiKeyVersion, KeyRotation, SecretString},
+    app_state::AppState,
+    errors::Err,
+    response::EncryptedResponse,
+};
+use axum::{Json, extract::State};
+use entropy_api_key_service_shared::{
+    ApiKeyBackup, ApiKeyDetails, ApiKeyVersionDetails, ApiResponse, DEFAULT_SECRET_NAME,
+    DELETE_SECRET_ROUTE, DEPLOY_API_KEY_ROUTE, EXPORT_API_KEYS_ROUTE, ExportApiKeysInfo,
+    IMPORT_API_KEYS_ROUTE, ImportApiKeysInfo, LIST_API_KEYS_ROUTE, ListApiKeysInfo,
+    MAKE_REQUEST_ROUTE, PROMOTE_API_KEY_ROUTE, PromoteApiKeyInfo, ROLLBACK_API_KEY_ROUTE,
+    RollbackApiKeyInfo, api_key_fingerprint, backup_associated_data, is_valid_secret_name,
 };
-use axum::{extract::State, http::StatusCode, Json};
-use entropy_api_key_service_shared::API_KEY_PLACEHOLDER;
-use entropy_protocol::sign_and_encrypt::EncryptedSignedMessage;
-use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
-use std::time::{SystemTime, UNIX_EPOCH};
+use entropy_protocol::sign_and_encrypt::{EncryptedSignedMessage, SignedMessage};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use subxt::utils::AccountId32 as SubxtAccountId32;
 use url::Url;
+use zeroize::Zeroize;
 
 /// Defines the maximum allowed time difference for an api call in seconds
 pub const TIME_BUFFER: u64 = 20;
 
+/// Timeout for each request made on behalf of a user, including each redirect followed, so that
+/// an unresponsive upstream service cannot hold connections open indefinitely
+pub const MAKE_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
 pub async fn deploy_api_key(
     State(app_state): State<AppState>,
     Json(encrypted_msg): Json<EncryptedSignedMessage>,
-) -> Result<StatusCode, Err> {
-    let signed_message = encrypted_msg.decrypt(&app_state.x25519_secret, &[])?;
+) -> Result<EncryptedResponse, Err> {
+    let associated_data = app_state.associated_data(DEPLOY_API_KEY_ROUTE);
+    let mut signed_message =
+        encrypted_msg.decrypt(&app_state.identity.x25519_secret, &associated_data)?;
+    let result = handle_deploy_api_key(&app_state, &signed_message).await;
+    // The decrypted request contains the secret
+    signed_message.message.0.zeroize();
+    app_state.encrypt_response(&signed_message, result, &associated_data)
+}
 
+async fn handle_deploy_api_key(
+    app_state: &AppState,
+    signed_message: &SignedMessage,
+) -> Result<(), Err> {
     let user_api_key_info: DeployApiKeyInfo = serde_json::from_slice(&signed_message.message.0)?;
+    // Wrapped straight away, so that it is wiped however we return
+    let api_key = SecretString::from(user_api_key_info.api_key);
 
     let request_author = SubxtAccountId32(*signed_message.account_id().as_ref());
     let current_timestamp = get_current_timestamp()?;
 
     check_stale(user_api_key_info.timestamp, current_timestamp).await?;
-    let api_url = Url::parse(&user_api_key_info.api_url)?
-        .host_str()
-        .ok_or(Err::UrlHost)?
-        .to_string();
+    app_state
+        .check_replay(
+            &request_author.0,
+            &signed_message.message.0,
+            user_api_key_info.timestamp,
+            current_timestamp,
+        )
+        .await?;
+    let api_key_id = ApiKeyId {
+        account_id: request_author.0,
+        service: service_from_url(&Url::parse(&user_api_key_info.api_url)?)?,
+        secret_name: secret_name_or_default(user_api_key_info.secret_name)?,
+    };
+    let expires_at = user_api_key_info
+        .expiry
+        .map(|expiry| expiry.expires_at(current_timestamp));
+    if expires_at.is_some_and(|expires_at| expires_at <= current_timestamp) {
+        return Err(Err::ExpiryInPast);
+    }
+    let rotation = match user_api_key_info.rotation {
+        Some(recipe) => {
+            check_rotation_recipe(&recipe, &api_key_id.service)?;
+            Some(KeyRotation {
+                next_rotation_at: current_timestamp.saturating_add(recipe.interval),
+                recipe,
+            })
+        }
+        None => None,
+    };
+    if let Some(rule) = &user_api_key_info.injection {
+        check_injection_rule(rule)?;
+    }
+    if let Some(policy) = &user_api_key_info.policy {
+        check_request_policy(policy)?;
+    }
 
-    app_state.write_to_api_keys((request_author.0, api_url), user_api_key_info.api_key)?;
+    let _update_guard = app_state.api_key_update_lock.lock().await;
+    let existing_entry = app_state.read_from_api_keys(&api_key_id).await?;
+    let version = ApiKeyVersion {
+        version: existing_entry
+            .as_ref()
+            .map_or(1, |entry| entry.next_version()),
+        api_key,
+        deployed_at: current_timestamp,
+        expires_at,
+    };
+    let entry = if user_api_key_info.pending {
+        let mut entry = existing_entry.ok_or(Err::NoSuchApiKey)?;
+        // The injection rule and policy of the active version stay in place until the pending
+        // version is promoted
+        entry.set_pending(
+            version,
+            user_api_key_info.injection,
+            user_api_key_info.policy,
+        );
+        if rotation.is_some() {
+            entry.rotation = rotation;
+        }
+        entry
+    } else {
+        // Restrictions are kept unless they are replaced, so that redeploying a key cannot lift
+        // them by leaving them out
+        let (injection, policy) = existing_entry
+            .map(|entry| (entry.injection, entry.policy))
+            .unwrap_or_default();
+        ApiKeyEntry {
+            rotation,
+            injection: user_api_key_info.injection.or(injection),
+            policy: user_api_key_info.policy.or(policy),
+            ..ApiKeyEntry::new(version)
+        }
+    };
+    app_state.write_to_api_keys(api_key_id, entry).await?;
 
-    Ok(StatusCode::OK)
+    Ok(())
+}
+
+pub async fn promote_api_key(
+    State(app_state): State<AppState>,
+    Json(encrypted_msg): Json<EncryptedSignedMessage>,
+) -> Result<EncryptedResponse, Err> {
+    let associated_data = app_state.associated_data(PROMOTE_API_KEY_ROUTE);
+    let signed_message =
+        encrypted_msg.decrypt(&app_state.identity.x25519_secret, &associated_data)?;
+    let result = handle_promote_api_key(&app_state, &signed_message).await;
+    app_state.encrypt_response(&signed_message, result, &associated_data)
+}
+
+async fn handle_promote_api_key(
+    app_state: &AppState,
+    signed_message: &SignedMessage,
+) -> Result<(), Err> {
+    let user_promote_info: PromoteApiKeyInfo = serde_json::from_slice(&signed_message.message.0)?;
+    let request_author = SubxtAccountId32(*signed_message.account_id().as_ref());
+
+    let current_timestamp = get_current_timestamp()?;
+    check_stale(user_promote_info.timestamp, current_timestamp).await?;
+    app_state
+        .check_replay(
+            &request_author.0,
+            &signed_message.message.0,
+            user_promote_info.timestamp,
+            current_timestamp,
+        )
+        .await?;
+
+    let api_key_id = ApiKeyId {
+        account_id: request_author.0,
+        service: service_from_url(&Url::parse(&user_promote_info.api_url)?)?,
+        secret_name: secret_name_or_default(user_promote_info.secret_name)?,
+    };
+
+    let _update_guard = app_state.api_key_update_lock.lock().await;
+    let mut entry = app_state
+        .read_from_api_keys(&api_key_id)
+        .await?
+        .ok_or(Err::NoSuchApiKey)?;
+    entry.promote(user_promote_info.grace_period, current_timestamp)?;
+    app_state.write_to_api_keys(api_key_id, entry).await?;
+
+    Ok(())
+}
+
+pub async fn rollback_api_key(
+    State(app_state): State<AppState>,
+    Json(encrypted_msg): Json<EncryptedSignedMessage>,
+) -> Result<EncryptedResponse, Err> {
+    let associated_data = app_state.associated_data(ROLLBACK_API_KEY_ROUTE);
+    let signed_message =
+        encrypted_msg.decrypt(&app_state.identity.x25519_secret, &associated_data)?;
+    let result = handle_rollback_api_key(&app_state, &signed_message).await;
+    app_state.encrypt_response(&signed_message, result, &associated_data)
+}
+
+async fn handle_rollback_api_key(
+    app_state: &AppState,
+    signed_message: &SignedMessage,
+) -> Result<(), Err> {
+    let user_rollback_info: RollbackApiKeyInfo = serde_json::from_slice(&signed_message.message.0)?;
+    let request_author = SubxtAccountId32(*signed_message.account_id().as_ref());
+
+    let current_timestamp = get_current_timestamp()?;
+    check_stale(user_rollback_info.timestamp, current_timestamp).await?;
+    app_state
+        .check_replay(
+            &request_author.0,
+            &signed_message.message.0,
+            user_rollback_info.timestamp,
+            current_timestamp,
+        )
+        .await?;
+
+    let api_key_id = ApiKeyId {
+        account_id: request_author.0,
+        service: service_from_url(&Url::parse(&user_rollback_info.api_url)?)?,
+        secret_name: secret_name_or_default(user_rollback_info.secret_name)?,
+    };
+
+    let _update_guard = app_state.api_key_update_lock.lock().await;
+    let mut entry = app_state
+        .read_from_api_keys(&api_key_id)
+        .await?
+        .ok_or(Err::NoSuchApiKey)?;
+    entry.rollback(current_timestamp)?;
+    app_state.write_to_api_keys(api_key_id, entry).await?;
+
+    Ok(())
 }
 
 pub async fn delete_secret(
     State(app_state): State<AppState>,
     Json(encrypted_msg): Json<EncryptedSignedMessage>,
-) -> Result<StatusCode, Err> {
-    let signed_message = encrypted_msg.decrypt(&app_state.x25519_secret, &[])?;
+) -> Result<EncryptedResponse, Err> {
+    let associated_data = app_state.associated_data(DELETE_SECRET_ROUTE);
+    let signed_message =
+        encrypted_msg.decrypt(&app_state.identity.x25519_secret, &associated_data)?;
+    let result = handle_delete_secret(&app_state, &signed_message).await;
+    app_state.encrypt_response(&signed_message, result, &associated_data)
+}
 
+async fn handle_delete_secret(
+    app_state: &AppState,
+    signed_message: &SignedMessage,
+) -> Result<(), Err> {
     let user_api_key_info: DeleteApiKeyInfo = serde_json::from_slice(&signed_message.message.0)?;
     let request_author = SubxtAccountId32(*signed_message.account_id().as_ref());
 
     let current_timestamp = get_current_timestamp()?;
     check_stale(user_api_key_info.timestamp, current_timestamp).await?;
+    app_state
+        .check_replay(
+            &request_author.0,
+            &signed_message.message.0,
+            user_api_key_info.timestamp,
+            current_timestamp,
+        )
+        .await?;
+
+    let service = service_from_url(&Url::parse(&user_api_key_info.api_url)?)?;
+    if let Some(secret_name) = &user_api_key_info.secret_name
+        && !is_valid_secret_name(secret_name)
+    {
+        return Err(Err::InvalidSecretName);
+    }
+
+    // Taken before listing, so that a secret deployed meanwhile is either listed or deployed after
+    // the deletion
+    let _update_guard = app_state.api_key_update_lock.lock().await;
+    // If no secret name is given, delete all secrets for this service
+    let api_key_ids: Vec<ApiKeyId> = match user_api_key_info.secret_name {
+        Some(secret_name) => vec![ApiKeyId {
+            account_id: request_author.0,
+            service,
+            secret_name,
+        }],
+        None => app_state
+            .api_keys
+            .list(&request_author.0)
+            .await?
+            .into_iter()
+            .filter_map(|(id, _)| (id.service == service).then_some(id))
+            .collect(),
+    };
+    for api_key_id in api_key_ids {
+        app_state.delete_from_api_keys(&api_key_id).await?;
+    }
+
+    Ok(())
+}
 
-    let api_url = Url::parse(&user_api_key_info.api_url)?
-        .host_str()
-        .ok_or(Err::UrlHost)?
-        .to_string();
+pub async fn list_api_keys(
+    State(app_state): State<AppState>,
+    Json(encrypted_msg): Json<EncryptedSignedMessage>,
+) -> Result<EncryptedResponse, Err> {
+    let associated_data = app_state.associated_data(LIST_API_KEYS_ROUTE);
+    let signed_message =
+        encrypted_msg.decrypt(&app_state.identity.x25519_secret, &associated_data)?;
+    let result = handle_list_api_keys(&app_state, &signed_message).await;
+    app_state.encrypt_response(&signed_message, result, &associated_data)
+}
+
+async fn handle_list_api_keys(
+    app_state: &AppState,
+    signed_message: &SignedMessage,
+) -> Result<Vec<ApiKeyDetails>, Err> {
+    let user_list_info: ListApiKeysInfo = serde_json::from_slice(&signed_message.message.0)?;
+    let request_author = SubxtAccountId32(*signed_message.account_id().as_ref());
+
+    let current_timestamp = get_current_timestamp()?;
+    check_stale(user_list_info.timestamp, current_timestamp).await?;
+    app_state
+        .check_replay(
+            &request_author.0,
+            &signed_message.message.0,
+            user_list_info.timestamp,
+            current_timestamp,
+        )
+        .await?;
 
-    app_state.delete_from_api_keys((request_author.0, api_url))?;
+    let mut api_keys: Vec<ApiKeyDetails> = app_state
+        .api_keys
+        .list(&request_author.0)
+        .await?
+        .into_iter()
+        .map(|(id, entry)| {
+            let version_details = |version: &ApiKeyVersion, usable_until| ApiKeyVersionDetails {
+                version: version.version,
+                deployed_at: version.deployed_at,
+                expires_at: version.expires_at,
+                fingerprint: api_key_fingerprint(&id.account_id, version.api_key.expose()),
+                usable_until,
+            };
+            ApiKeyDetails {
+                fingerprint: api_key_fingerprint(&id.account_id, entry.active.api_key.expose()),
+                deployed_at: entry.active.deployed_at,
+                expires_at: entry.active.expires_at,
+                version: entry.active.version,
+                pending: entry
+                    .pending
+                    .as_ref()
+                    .map(|pending| version_details(pending, None)),
+                previous: entry
+                    .usable_previous(current_timestamp)
+                    .map(|previous| version_details(previous, entry.previous_usable_until)),
+                next_rotation_at: entry
+                    .rotation
+                    .as_ref()
+                    .map(|rotation| rotation.next_rotation_at),
+                injection: entry.injection,
+                policy: entry.policy,
+                pending_injection: entry.pending_injection,
+                pending_policy: entry.pending_policy,
+                service: id.service,
+                secret_name: id.secret_name,
+            }
+        })
+        .collect();
+    api_keys.sort_by(|a, b| (&a.service, &a.secret_name).cmp(&(&b.service, &b.secret_name)));
 
-    Ok(StatusCode::OK)
+    Ok(api_keys)
+}
+
+pub async fn export_api_keys(
+    State(app_state): State<AppState>,
+    Json(encrypted_msg): Json<EncryptedSignedMessage>,
+) -> Result<EncryptedResponse, Err> {
+    let associated_data = app_state.associated_data(EXPORT_API_KEYS_ROUTE);
+    let signed_message =
+        encrypted_msg.decrypt(&app_state.identity.x25519_secret, &associated_data)?;
+    let result = handle_export_api_keys(&app_state, &signed_message).await;
+    app_state.encrypt_response(&signed_message, result, &associated_data)
+}
+
+async fn handle_export_api_keys(
+    app_state: &AppState,
+    signed_message: &SignedMessage,
+) -> Result<EncryptedSignedMessage, Err> {
+    let user_export_info: ExportApiKeysInfo = serde_json::from_slice(&signed_message.message.0)?;
+    let request_author = SubxtAccountId32(*signed_message.account_id().as_ref());
+
+    let current_timestamp = get_current_timestamp()?;
+    check_stale(user_export_info.timestamp, current_timestamp).await?;
+    app_state
+        .check_replay(
+            &request_author.0,
+            &signed_message.message.0,
+            user_export_info.timestamp,
+            current_timestamp,
+        )
+        .await?;
+
+    let backup = ApiKeyBackup {
+        exported_at: current_timestamp,
+        api_keys: app_state
+            .api_keys
+            .list(&request_author.0)
+            .await?
+            .iter()
+            .map(|(id, entry)| to_backup(id, entry))
+            .collect(),
+    };
+    // The plaintext backup is moved into the message to be encrypted rather than copied, so that
+    // no copy of it is left here
+    let payload = serde_json::to_vec(&backup)?;
+
+    Ok(EncryptedSignedMessage::new(
+        &app_state.identity.pair,
+        payload,
+        &user_export_info.backup_x25519_public_key,
+        &backup_associated_data(&request_author.0),
+    )?)
+}
+
+pub async fn import_api_keys(
+    State(app_state): State<AppState>,
+    Json(encrypted_msg): Json<EncryptedSignedMessage>,
+) -> Result<EncryptedResponse, Err> {
+    let associated_data = app_state.associated_data(IMPORT_API_KEYS_ROUTE);
+    let mut signed_message =
+        encrypted_msg.decrypt(&app_state.identity.x25519_secret, &associated_data)?;
+    let result = handle_import_api_keys(&app_state, &signed_message).await;
+    // The decrypted request contains the secrets
+    signed_message.message.0.zeroize();
+    app_state.encrypt_response(&signed_message, result, &associated_data)
+}
+
+async fn handle_import_api_keys(
+    app_state: &AppState,
+    signed_message: &SignedMessage,
+) -> Result<usize, Err> {
+    let user_import_info: ImportApiKeysInfo = serde_json::from_slice(&signed_message.message.0)?;
+    let request_author = SubxtAccountId32(*signed_message.account_id().as_ref());
+
+    let current_timestamp = get_current_timestamp()?;
+    // Converted straight away, so that the secrets are wiped however we return
+    let api_keys = user_import_info
+        .api_keys
+        .into_iter()
+        .map(|backup| from_backup(request_author.0, backup, current_timestamp))
+        .collect::<Result<Vec<_>, Err>>();
+    check_stale(user_import_info.timestamp, current_timestamp).await?;
+    app_state
+        .check_replay(
+            &request_author.0,
+            &signed_message.message.0,
+            user_import_info.timestamp,
+            current_timestamp,
+        )
+        .await?;
+
+    // Nothing is imported unless every api key is valid
+    let api_keys: Vec<_> = api_keys?.into_iter().flatten().collect();
+    let imported = api_keys.len();
+    let _update_guard = app_state.api_key_update_lock.lock().await;
+    for (api_key_id, entry) in api_keys {
+        app_state.write_to_api_keys(api_key_id, entry).await?;
+    }
+
+    Ok(imported)
 }
 
 pub async fn make_request(
     State(app_state): State<AppState>,
     Json(encrypted_msg): Json<EncryptedSignedMessage>,
-) -> Result<(StatusCode, String), Err> {
-    let signed_message = encrypted_msg.decrypt(&app_state.x25519_secret, &[])?;
+) -> Result<EncryptedResponse, Err> {
+    let associated_data = app_state.associated_data(MAKE_REQUEST_ROUTE);
+    let signed_message =
+        encrypted_msg.decrypt(&app_state.identity.x25519_secret, &associated_data)?;
+    let result = handle_make_request(&app_state, &signed_message).await;
+    app_state.encrypt_response(&signed_message, result, &associated_data)
+}
 
+async fn handle_make_request(
+    app_state: &AppState,
+    signed_message: &SignedMessage,
+) -> Result<ApiResponse, Err> {
     let user_make_request_info: SendApiKeyMessage =
         serde_json::from_slice(&signed_message.message.0)?;
 
@@ -69,44 +483,124 @@ pub async fn make_request(
     let current_timestamp = get_current_timestamp()?;
 
     check_stale(user_make_request_info.timestamp, current_timestamp).await?;
+    app_state
+        .check_replay(
+            &request_author.0,
+            &signed_message.message.0,
+            user_make_request_info.timestamp,
+            current_timestamp,
+        )
+        .await?;
 
     let url_parsed = Url::parse(&user_make_request_info.api_url)?;
-    let url_host = url_parsed.host_str().ok_or(Err::UrlHost)?.to_string();
-    let api_key_info = app_state
-        .read_from_api_keys(&(request_author.0, url_host))?
-        .ok_or(Err::UrlEmpty)?;
-
-    let client = reqwest::Client::new();
-    let url = user_make_request_info
-        .api_url
-        .replace(API_KEY_PLACEHOLDER, &api_key_info);
-
-    let mut headers = HeaderMap::new();
-    for (key, value) in &user_make_request_info.http_headers {
-        let first = key.replace(API_KEY_PLACEHOLDER, &api_key_info);
-        let second = value.replace(API_KEY_PLACEHOLDER, &api_key_info);
-
-        let header_name = HeaderName::from_bytes(first.as_bytes())?;
-        let header_value = HeaderValue::from_str(&second)?;
-        headers.insert(header_name, header_value);
+    let api_keys = app_state.api_keys.list(&request_author.0).await?;
+    let service = longest_matching_service(
+        api_keys.iter().map(|(id, _)| id.service.as_str()),
+        &url_parsed,
+    )
+    .ok_or(Err::UrlEmpty)?;
+    let service_api_keys: Vec<_> = api_keys
+        .iter()
+        .filter(|(id, _)| id.service == service)
+        .map(|(id, entry)| {
+            let version = entry.select(user_make_request_info.key_version, current_timestamp);
+            (id.secret_name.as_str(), entry, version)
+        })
+        .collect();
+    // Secrets without the requested version still get a substitution here, so that requests
+    // which use them can be found and refused below
+    let substitutions = Substitutions::new(service_api_keys.iter().map(
+        |(secret_name, entry, version)| {
+            let version = version.as_ref().map_or(&entry.active, |version| *version);
+            (*secret_name, version.api_key.expose())
+        },
+    ));
+    // Requests using a secret which has no such version, or whose version has expired but is
+    // not yet purged, are refused
+    for (secret_name, _, version) in service_api_keys {
+        if uses_secret(
+            secret_name,
+            &substitutions,
+            &user_make_request_info.api_url,
+            &user_make_request_info.http_headers,
+            &user_make_request_info.request_body,
+        ) && version?.is_expired(current_timestamp)
+        {
+            return Err(Err::ApiKeyExpired);
+        }
     }
 
-    let response = match user_make_request_info.http_verb.as_str() {
-        "get" => Ok(client.get(url).headers(headers).send().await?),
-        "post" => {
-            let result = client
-                .post(url)
-                .headers(headers)
-                .body(user_make_request_info.request_body)
-                .send()
-                .await?;
-
-            Ok(result)
+    // Whether the request uses a secret which has a request policy
+    let mut uses_policy = false;
+    for (id, entry) in api_keys.iter().filter(|(id, _)| id.service == service) {
+        if let Some(rule) = entry.injection_for(user_make_request_info.key_version) {
+            check_placeholders_allowed(
+                &id.secret_name,
+                rule,
+                &substitutions,
+                &user_make_request_info.api_url,
+                &user_make_request_info.http_headers,
+                &user_make_request_info.request_body,
+            )?;
         }
-        _ => Err(Err::UnsupportedHttpVerb),
-    }?;
+        if let Some(policy) = entry.policy_for(user_make_request_info.key_version) {
+            check_request_allowed(
+                &id.secret_name,
+                policy,
+                &substitutions,
+                user_make_request_info.http_verb,
+                &url_parsed,
+                &user_make_request_info.http_headers,
+                &user_make_request_info.request_body,
+            )
+            .inspect_err(|error| {
+                tracing::warn!(
+                    "Refused request from {} to {}: {error}",
+                    hex::encode(request_author.0),
+                    id.service
+                )
+            })?;
+            uses_policy |= uses_secret(
+                &id.secret_name,
+                &substitutions,
+                &user_make_request_info.api_url,
+                &user_make_request_info.http_headers,
+                &user_make_request_info.request_body,
+            );
+        }
+    }
 
-    Ok((StatusCode::OK, response.text().await?))
+    // Redirects are not checked against request policies, so are not followed when one applies
+    let follow_redirects = if uses_policy {
+        FollowRedirects::None
+    } else if user_make_request_info.follow_redirects {
+        FollowRedirects::All
+    } else {
+        FollowRedirects::SameOrigin
+    };
+    send_with_substitutions(
+        &app_state.configuration.egress_policy,
+        UpstreamOptions {
+            timeout: Some(MAKE_REQUEST_TIMEOUT),
+            follow_redirects,
+        },
+        service,
+        &substitutions,
+        user_make_request_info.http_verb,
+        &user_make_request_info.api_url,
+        &user_make_request_info.http_headers,
+        &user_make_request_info.request_body,
+    )
+    .await
+}
+
+/// Get the given secret name, or [DEFAULT_SECRET_NAME] if none is given, checking that it is valid
+fn secret_name_or_default(secret_name: Option<String>) -> Result<String, Err> {
+    let secret_name = secret_name.unwrap_or(DEFAULT_SECRET_NAME.to_string());
+    if !is_valid_secret_name(&secret_name) {
+        return Err(Err::InvalidSecretName);
+    }
+    Ok(secret_name)
 }
 
 // Get current timestamp
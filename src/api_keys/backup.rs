@@ -0,0 +1,101 @@
+//! Converting stored api keys to and from the form they take in backups
+use super::{
+    injection::check_injection_rule, policy::check_request_policy, rotation::check_rotation_recipe,
+    service::service_from_url,
+};
+use crate::{
+    api_key_store::{ApiKeyEntry, ApiKeyId, ApiKeyVersion, KeyRotation},
+    errors::Err,
+};
+use entropy_api_key_service_shared::{BackupApiKey, BackupApiKeyVersion, is_valid_secret_name};
+use url::Url;
+use zeroize::Zeroizing;
+
+/// Get the form of a stored api key held in a backup. Previous versions are not included, as they
+/// are only kept for a short grace period.
+pub fn to_backup(id: &ApiKeyId, entry: &ApiKeyEntry) -> BackupApiKey {
+    let backup_version = |version: &ApiKeyVersion| BackupApiKeyVersion {
+        api_key: Zeroizing::new(version.api_key.expose().to_string()),
+        expires_at: version.expires_at,
+    };
+    BackupApiKey {
+        service: id.service.clone(),
+        secret_name: id.secret_name.clone(),
+        active: backup_version(&entry.active),
+        pending: entry.pending.as_ref().map(backup_version),
+        rotation: entry
+            .rotation
+            .as_ref()
+            .map(|rotation| rotation.recipe.clone()),
+        injection: entry.injection.clone(),
+        policy: entry.policy.clone(),
+        pending_injection: entry.pending_injection.clone(),
+        pending_policy: entry.pending_policy.clone(),
+    }
+}
+
+/// Check an api key from a backup and convert it to the form in which it is stored, as if it was
+/// deployed now. Returns `None` if its active version has expired.
+pub fn from_backup(
+    account_id: [u8; 32],
+    backup: BackupApiKey,
+    current_timestamp: u64,
+) -> Result<Option<(ApiKeyId, ApiKeyEntry)>, Err> {
+    if service_from_url(&Url::parse(&backup.service)?)? != backup.service {
+        return Err(Err::InvalidBackup(format!(
+            "{} is not a valid service",
+            backup.service
+        )));
+    }
+    if !is_valid_secret_name(&backup.secret_name) {
+        return Err(Err::InvalidSecretName);
+    }
+    if let Some(recipe) = &backup.rotation {
+        check_rotation_recipe(recipe, &backup.service)?;
+    }
+    for rule in [&backup.injection, &backup.pending_injection]
+        .into_iter()
+        .flatten()
+    {
+        check_injection_rule(rule)?;
+    }
+    for policy in [&backup.policy, &backup.pending_policy]
+        .into_iter()
+        .flatten()
+    {
+        check_request_policy(policy)?;
+    }
+
+    let mut entry = ApiKeyEntry::new(ApiKeyVersion {
+        version: 1,
+        api_key: backup.active.api_key.into(),
+        deployed_at: current_timestamp,
+        expires_at: backup.active.expires_at,
+    });
+    if entry.is_expired(current_timestamp) {
+        return Ok(None);
+    }
+    if let Some(pending) = backup.pending {
+        let pending = ApiKeyVersion {
+            version: 2,
+            api_key: pending.api_key.into(),
+            deployed_at: current_timestamp,
+            expires_at: pending.expires_at,
+        };
+        entry.set_pending(pending, backup.pending_injection, backup.pending_policy);
+    }
+    entry.rotation = backup.rotation.map(|recipe| KeyRotation {
+        next_rotation_at: current_timestamp.saturating_add(recipe.interval),
+        recipe,
+    });
+    entry.injection = backup.injection;
+    entry.policy = backup.policy;
+    entry.remove_expired_versions(current_timestamp);
+
+    let id = ApiKeyId {
+        account_id,
+        service: backup.service,
+        secret_name: backup.secret_name,
+    };
+    Ok(Some((id, entry)))
+}
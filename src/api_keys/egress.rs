@@ -0,0 +1,176 @@
+//! Checking the destinations of outbound requests, so that users cannot reach the internal network
+//! of the service
+use crate::errors::Err;
+use serde::Deserialize;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+use url::{Host, Url};
+
+/// A destination which may be reached even though it is not public
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum AllowedDestination {
+    /// Any port of an address
+    Address(IpAddr),
+    /// One port of an address
+    SocketAddress(SocketAddr),
+}
+
+impl AllowedDestination {
+    fn matches(&self, address: &SocketAddr) -> bool {
+        match self {
+            AllowedDestination::Address(ip) => *ip == address.ip(),
+            AllowedDestination::SocketAddress(socket_address) => socket_address == address,
+        }
+    }
+}
+
+impl FromStr for AllowedDestination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(socket_address) = s.parse() {
+            return Ok(AllowedDestination::SocketAddress(socket_address));
+        }
+        s.parse()
+            .map(AllowedDestination::Address)
+            .map_err(|_| format!("{s} is not an IP address, or an IP address and port"))
+    }
+}
+
+impl TryFrom<String> for AllowedDestination {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Which destinations outbound requests may be made to. Only public addresses are allowed, apart
+/// from those which are explicitly allowed.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EgressPolicy {
+    pub allowed: Vec<AllowedDestination>,
+}
+
+impl EgressPolicy {
+    /// Check whether a request may be made to the given address
+    pub fn allows(&self, address: &SocketAddr) -> bool {
+        is_public(&address.ip())
+            || self
+                .allowed
+                .iter()
+                .any(|destination| destination.matches(address))
+    }
+
+    /// Resolve the host of a URL once, returning an address which requests to it may be made to.
+    /// Every address the host resolves to must be allowed, so that which one is used does not
+    /// matter.
+    pub async fn resolve(&self, url: &Url) -> Result<SocketAddr, Err> {
+        let port = url.port_or_known_default().ok_or(Err::UrlHost)?;
+        let addresses: Vec<SocketAddr> = match url.host().ok_or(Err::UrlHost)? {
+            Host::Ipv4(ip) => vec![SocketAddr::new(ip.into(), port)],
+            Host::Ipv6(ip) => vec![SocketAddr::new(ip.into(), port)],
+            Host::Domain(domain) => tokio::net::lookup_host((domain, port))
+                .await
+                // The host may contain a substituted secret, so it is not reported
+                .map_err(|_| {
+                    Err::EgressDenied("Cannot resolve the host of the request".to_string())
+                })?
+                .collect(),
+        };
+        if let Some(denied) = addresses.iter().find(|address| !self.allows(address)) {
+            return Err(Err::EgressDenied(format!(
+                "{} is not a public address",
+                denied.ip()
+            )));
+        }
+        addresses.first().copied().ok_or_else(|| {
+            Err::EgressDenied("The host of the request has no addresses".to_string())
+        })
+    }
+
+    /// Get an HTTP client for requests to the given URL. Its host is resolved and checked once, and
+    /// connections are pinned to the checked address so that DNS cannot be used to rebind it.
+    /// Redirects are not followed, as they would not be checked.
+    pub async fn client_for(
+        &self,
+        url: &Url,
+        timeout: Option<Duration>,
+    ) -> Result<reqwest::Client, Err> {
+        let address = self.resolve(url).await?;
+        let mut builder = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            // A proxy would make its own connections, which would not be pinned
+            .no_proxy();
+        if let Some(Host::Domain(domain)) = url.host() {
+            builder = builder.resolve(domain, address);
+        }
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// Whether an address is publicly routable, rather than loopback, private, link local or otherwise
+/// reserved
+pub fn is_public(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(ip) => is_public_ipv4(&ip),
+            None => is_public_ipv6(ip),
+        },
+    }
+}
+
+/// The IPv4 address embedded in an IPv4 mapped, IPv4 compatible or 6to4 address, which is where a
+/// request to it could end up
+fn embedded_ipv4(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    match ip.segments() {
+        // 2002::/16 - 6to4
+        [0x2002, high, low, ..] => Some(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low))),
+        // ::ffff:0:0/96 and ::/96, which includes the unspecified and loopback addresses
+        _ => ip.to_ipv4(),
+    }
+}
+
+fn is_public_ipv4(ip: &Ipv4Addr) -> bool {
+    let [first, second, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        // Includes cloud metadata services on 169.254.169.254
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // 0.0.0.0/8 - this network
+        || first == 0
+        // 100.64.0.0/10 - shared address space
+        || (first == 100 && (64..128).contains(&second))
+        // 192.0.0.0/24 - protocol assignments
+        || (first == 192 && second == 0 && ip.octets()[2] == 0)
+        // 198.18.0.0/15 - benchmarking
+        || (first == 198 && (18..20).contains(&second))
+        // 240.0.0.0/4 - reserved
+        || first >= 240)
+}
+
+fn is_public_ipv6(ip: &Ipv6Addr) -> bool {
+    let [first, second, ..] = ip.segments();
+    // 2000::/3 - global unicast. Addresses outside it are loopback, link local, unique local,
+    // multicast, discard only (100::/64), IPv4/IPv6 translation (64:ff9b::/96, which could reach
+    // private IPv4 addresses) or otherwise reserved
+    (first & 0xe000) == 0x2000
+        // 2001::/23 - protocol assignments, including Teredo (2001::/32), which tunnels to IPv4
+        && !(first == 0x2001 && second < 0x0200)
+        // 2001:db8::/32 - documentation
+        && !(first == 0x2001 && second == 0x0db8)
+        // 3fff::/20 - documentation
+        && !(first == 0x3fff && second < 0x1000)
+}
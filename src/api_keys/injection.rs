@@ -0,0 +1,152 @@
+//! Checking that the placeholders for secrets with an injection rule are only placed where the
+//! rule allows
+use super::substitution::Substitutions;
+use crate::errors::Err;
+use entropy_api_key_service_shared::{
+    API_KEY_PLACEHOLDER, DEFAULT_SECRET_NAME, INJECTION_FORMAT_KEY, InjectionRule,
+    api_key_placeholder,
+};
+use reqwest::header::HeaderName;
+
+/// Check an injection rule given when deploying a secret
+pub fn check_injection_rule(rule: &InjectionRule) -> Result<(), Err> {
+    match rule {
+        InjectionRule::Header { name, format } => {
+            if HeaderName::from_bytes(name.as_bytes()).is_err() {
+                return Err(Err::InvalidInjectionRule(format!(
+                    "{name} is not a valid header name"
+                )));
+            }
+            if !format.contains(INJECTION_FORMAT_KEY) {
+                return Err(Err::InvalidInjectionRule(format!(
+                    "header format must contain {INJECTION_FORMAT_KEY}"
+                )));
+            }
+        }
+        InjectionRule::QueryParameter { name } => {
+            if name.is_empty() {
+                return Err(Err::InvalidInjectionRule(
+                    "query parameter name must not be empty".to_string(),
+                ));
+            }
+        }
+        InjectionRule::Body => {}
+    }
+    Ok(())
+}
+
+/// Check that the placeholders for the secret with the given name are only placed in a request
+/// where its injection rule allows
+pub fn check_placeholders_allowed(
+    secret_name: &str,
+    rule: &InjectionRule,
+    substitutions: &Substitutions<'_>,
+    api_url: &str,
+    http_headers: &[(String, String)],
+    request_body: &str,
+) -> Result<(), Err> {
+    let placeholders = placeholders_for(secret_name);
+    let count = |input: &str| count_placeholders(substitutions, &placeholders, input);
+
+    let placed = count_in_request(
+        substitutions,
+        &placeholders,
+        api_url,
+        http_headers,
+        request_body,
+    );
+    let allowed = match rule {
+        InjectionRule::Header { name, format } => http_headers
+            .iter()
+            .filter(|(header_name, value)| {
+                header_name.eq_ignore_ascii_case(name)
+                    && placeholders.iter().any(|placeholder| {
+                        *value == format.replace(INJECTION_FORMAT_KEY, placeholder)
+                    })
+            })
+            .map(|(_, value)| count(value))
+            .sum(),
+        InjectionRule::QueryParameter { name } => raw_query_parameters(api_url)
+            .filter(|(parameter, value)| {
+                parameter == name && placeholders.iter().any(|placeholder| placeholder == value)
+            })
+            .count(),
+        InjectionRule::Body => count(request_body),
+    };
+
+    if placed != allowed {
+        return Err(Err::InjectionNotAllowed {
+            secret_name: secret_name.to_string(),
+            rule: rule.clone(),
+        });
+    }
+    Ok(())
+}
+
+/// Get the placeholders which stand for the secret with the given name
+fn placeholders_for(secret_name: &str) -> Vec<String> {
+    let mut placeholders = vec![api_key_placeholder(secret_name)];
+    if secret_name == DEFAULT_SECRET_NAME {
+        placeholders.push(API_KEY_PLACEHOLDER.to_string());
+    }
+    placeholders
+}
+
+/// Count how many of the given placeholders would be replaced in the input
+fn count_placeholders(
+    substitutions: &Substitutions<'_>,
+    placeholders: &[String],
+    input: &str,
+) -> usize {
+    substitutions
+        .placeholders_in(input)
+        .into_iter()
+        .filter(|placeholder| placeholders.iter().any(|own| own == placeholder))
+        .count()
+}
+
+/// Count how many of the given placeholders would be replaced anywhere in a request
+fn count_in_request(
+    substitutions: &Substitutions<'_>,
+    placeholders: &[String],
+    api_url: &str,
+    http_headers: &[(String, String)],
+    request_body: &str,
+) -> usize {
+    let count = |input: &str| count_placeholders(substitutions, placeholders, input);
+    count(api_url)
+        + http_headers
+            .iter()
+            .map(|(name, value)| count(name) + count(value))
+            .sum::<usize>()
+        + count(request_body)
+}
+
+/// Whether a request places a placeholder for the secret with the given name anywhere
+pub(super) fn uses_secret(
+    secret_name: &str,
+    substitutions: &Substitutions<'_>,
+    api_url: &str,
+    http_headers: &[(String, String)],
+    request_body: &str,
+) -> bool {
+    count_in_request(
+        substitutions,
+        &placeholders_for(secret_name),
+        api_url,
+        http_headers,
+        request_body,
+    ) > 0
+}
+
+/// Get the query parameters of a URL as they are written, without decoding them, as placeholders
+/// are substituted before the URL is parsed
+fn raw_query_parameters(url: &str) -> impl Iterator<Item = (&str, &str)> {
+    let without_fragment = url.split_once('#').map_or(url, |(url, _)| url);
+    let query = without_fragment
+        .split_once('?')
+        .map_or("", |(_, query)| query);
+    query
+        .split('&')
+        .filter_map(|parameter| parameter.split_once('='))
+}
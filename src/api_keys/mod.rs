@@ -1,4 +1,12 @@
 pub mod api;
+pub mod backup;
+pub mod egress;
+pub mod injection;
+pub mod policy;
+pub mod rotation;
+pub mod service;
+pub mod substitution;
+pub mod upstream;
 
 #[cfg(test)]
 mod tests;
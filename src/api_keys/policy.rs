@@ -0,0 +1,113 @@
+//! Checking that requests made with a secret are allowed by its request policy
+use super::{injection::uses_secret, substitution::Substitutions};
+use crate::errors::Err;
+use entropy_api_key_service_shared::{HttpMethod, RequestPolicy};
+use url::Url;
+
+/// Check a request policy given when deploying a secret
+pub fn check_request_policy(policy: &RequestPolicy) -> Result<(), Err> {
+    if let Some(path) = policy.paths.iter().find(|path| !path.starts_with('/')) {
+        return Err(Err::InvalidRequestPolicy(format!(
+            "path {path} must begin with /"
+        )));
+    }
+    Ok(())
+}
+
+/// Check that a request which places the placeholder for the secret with the given name is allowed
+/// by its policy. Requests which do not use the secret are not restricted.
+pub fn check_request_allowed(
+    secret_name: &str,
+    policy: &RequestPolicy,
+    substitutions: &Substitutions<'_>,
+    http_verb: HttpMethod,
+    api_url: &Url,
+    http_headers: &[(String, String)],
+    request_body: &str,
+) -> Result<(), Err> {
+    if !uses_secret(
+        secret_name,
+        substitutions,
+        api_url.as_str(),
+        http_headers,
+        request_body,
+    ) {
+        return Ok(());
+    }
+    let violation = |reason: String| Err::PolicyViolation {
+        secret_name: secret_name.to_string(),
+        reason,
+    };
+
+    if !policy.methods.is_empty() && !policy.methods.contains(&http_verb) {
+        return Err(violation(format!(
+            "method {} is not allowed",
+            http_verb.as_str()
+        )));
+    }
+    // The path is checked as parsed, so that dot segments cannot be used to leave an allowed path
+    let path = api_url.path();
+    if !policy.paths.is_empty() && !policy.paths.iter().any(|glob| glob_matches(glob, path)) {
+        return Err(violation(format!("path {path} is not allowed")));
+    }
+    if let Some(allowed) = &policy.query_parameters
+        && let Some((name, _)) = api_url
+            .query_pairs()
+            .find(|(name, _)| !allowed.iter().any(|allowed| allowed == name))
+    {
+        return Err(violation(format!("query parameter {name} is not allowed")));
+    }
+    if let Some(max_body_size) = policy.max_body_size
+        && request_body.len() > max_body_size
+    {
+        return Err(violation(format!(
+            "body of {} bytes is larger than {max_body_size} bytes",
+            request_body.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Whether a path matches a glob, where `*` matches anything within a path segment and `**`
+/// matches anything, including several segments
+pub fn glob_matches(glob: &str, path: &str) -> bool {
+    let path = path.as_bytes();
+    // Which positions in the path the part of the glob matched so far can end at
+    let mut matched = vec![false; path.len() + 1];
+    matched[0] = true;
+    let mut glob = glob.as_bytes();
+    while !glob.is_empty() {
+        let mut next = vec![false; path.len() + 1];
+        glob = match glob {
+            [b'*', b'*', rest @ ..] => {
+                let mut reachable = false;
+                for (position, matched) in matched.iter().enumerate() {
+                    reachable |= matched;
+                    next[position] = reachable;
+                }
+                rest
+            }
+            [b'*', rest @ ..] => {
+                let mut reachable = false;
+                for (position, matched) in matched.iter().enumerate() {
+                    // A single `*` cannot match across segments
+                    if position > 0 && path[position - 1] == b'/' {
+                        reachable = false;
+                    }
+                    reachable |= matched;
+                    next[position] = reachable;
+                }
+                rest
+            }
+            [literal, rest @ ..] => {
+                for position in 0..path.len() {
+                    next[position + 1] = matched[position] && path[position] == *literal;
+                }
+                rest
+            }
+            [] => break,
+        };
+        matched = next;
+    }
+    matched[path.len()]
+}
@@ -0,0 +1,312 @@
+//! Rotating api keys with a provider's API, so that new keys are only ever seen by the service
+use super::{
+    api::get_current_timestamp,
+    egress::EgressPolicy,
+    service::service_matches,
+    substitution::Substitutions,
+    upstream::{FollowRedirects, UpstreamOptions, send_with_substitutions},
+};
+use crate::{
+    api_key_store::{ApiKeyId, ApiKeyVersion, SecretString},
+    app_state::AppState,
+    errors::Err,
+};
+use entropy_api_key_service_shared::{
+    ApiResponse, MIN_ROTATION_INTERVAL, OLD_API_KEY_PLACEHOLDER, RotationRecipe, RotationRequest,
+};
+use std::time::Duration;
+use url::Url;
+use zeroize::Zeroize;
+
+/// How often to check for api keys which are due to be rotated
+pub const ROTATION_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Number of seconds to wait before retrying a rotation which failed
+pub const ROTATION_RETRY_DELAY: u64 = 300;
+
+/// Timeout for each request made while rotating, so that an unresponsive provider cannot hold up
+/// rotation of other keys for long
+const ROTATION_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Check a rotation recipe given when deploying a key for the given service
+pub fn check_rotation_recipe(recipe: &RotationRecipe, service: &str) -> Result<(), Err> {
+    if recipe.interval < MIN_ROTATION_INTERVAL {
+        return Err(Err::InvalidRotationRecipe(format!(
+            "interval must be at least {MIN_ROTATION_INTERVAL} seconds"
+        )));
+    }
+    if !recipe.new_key_pointer.starts_with('/') {
+        return Err(Err::InvalidRotationRecipe(
+            "new key pointer must be a non-empty JSON pointer".to_string(),
+        ));
+    }
+    // Secrets must only be sent to the service they were deployed for
+    for request in std::iter::once(&recipe.create).chain(&recipe.revoke) {
+        if !service_matches(service, &Url::parse(&request.api_url)?) {
+            return Err(Err::InvalidRotationRecipe(format!(
+                "{} is not under {service}",
+                request.api_url
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Periodically rotate api keys which are due to be rotated. This runs forever, so should be
+/// spawned as a task.
+pub async fn rotate_api_keys(app_state: AppState) {
+    let mut interval = tokio::time::interval(ROTATION_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let result = match get_current_timestamp() {
+            Ok(current_timestamp) => rotate_due_api_keys(&app_state, current_timestamp).await,
+            Err(error) => Err(error),
+        };
+        match result {
+            Ok(0) => {}
+            Ok(rotated) => tracing::info!("Rotated {rotated} api keys"),
+            Err(error) => tracing::error!("Failed to rotate api keys: {error}"),
+        }
+    }
+}
+
+/// Rotate all api keys which are due at the given unix time in seconds, returning how many were
+/// rotated. Keys which fail to rotate are logged, and tried again after [ROTATION_RETRY_DELAY].
+pub async fn rotate_due_api_keys(
+    app_state: &AppState,
+    current_timestamp: u64,
+) -> Result<usize, Err> {
+    let egress_policy = &app_state.configuration.egress_policy;
+    let mut rotated = 0;
+    for id in app_state
+        .api_keys
+        .list_due_for_rotation(current_timestamp)
+        .await?
+    {
+        match rotate_api_key(app_state, egress_policy, &id, current_timestamp).await {
+            Ok(true) => rotated += 1,
+            Ok(false) => {}
+            Err(error) => tracing::error!(
+                "Failed to rotate api key {} for {}: {error}",
+                id.secret_name,
+                id.service
+            ),
+        }
+    }
+    Ok(rotated)
+}
+
+/// Rotate an api key if it is still due, returning whether it was rotated
+async fn rotate_api_key(
+    app_state: &AppState,
+    egress_policy: &EgressPolicy,
+    id: &ApiKeyId,
+    current_timestamp: u64,
+) -> Result<bool, Err> {
+    // Requests to the provider are made without holding the update lock, so that a slow provider
+    // cannot block other updates. The entry is read again under the lock before it is changed.
+    let Some(due_entry) = app_state.read_from_api_keys(id).await? else {
+        return Ok(false);
+    };
+    let recipe = match &due_entry.rotation {
+        Some(rotation) if due_entry.is_due_for_rotation(current_timestamp) => {
+            rotation.recipe.clone()
+        }
+        _ => return Ok(false),
+    };
+
+    // Other secrets for the same service may also be used in the rotation requests
+    let service_api_keys = app_state.api_keys.list(&id.account_id).await?;
+    let other_secrets: Vec<_> = service_api_keys
+        .iter()
+        .filter(|(other_id, _)| {
+            other_id.service == id.service && other_id.secret_name != id.secret_name
+        })
+        .map(|(other_id, other_entry)| {
+            (
+                other_id.secret_name.as_str(),
+                other_entry.active.api_key.expose(),
+            )
+        })
+        .collect();
+
+    let substitutions = Substitutions::new(
+        other_secrets
+            .iter()
+            .copied()
+            .chain([(id.secret_name.as_str(), due_entry.active.api_key.expose())]),
+    );
+    let created = create_api_key(egress_policy, &id.service, &recipe, &substitutions).await;
+
+    let update_guard = app_state.api_key_update_lock.lock().await;
+    // If the key was changed while the request was made, the change is kept and the result of
+    // this rotation is discarded
+    let mut entry = match app_state.read_from_api_keys(id).await? {
+        Some(entry) if entry.active == due_entry.active && entry.rotation == due_entry.rotation => {
+            entry
+        }
+        _ => {
+            drop(update_guard);
+            let discarded_api_key = created?;
+            // The discarded key would otherwise stay valid with the provider without anyone
+            // knowing it, so it is revoked with the key which created it
+            let result = match &recipe.revoke {
+                Some(revoke) => {
+                    revoke_api_key(
+                        egress_policy,
+                        id,
+                        revoke,
+                        &other_secrets,
+                        due_entry.active.api_key.expose(),
+                        discarded_api_key.expose(),
+                    )
+                    .await
+                }
+                None => Err("the rotation recipe has no revoke request".to_string()),
+            };
+            match result {
+                Ok(()) => tracing::warn!(
+                    "Api key {} for {} changed while it was being rotated, so the new key was \
+                     revoked",
+                    id.secret_name,
+                    id.service
+                ),
+                Err(error) => tracing::error!(
+                    "Api key {} for {} changed while it was being rotated, but the new key could \
+                     not be revoked: {error}",
+                    id.secret_name,
+                    id.service
+                ),
+            }
+            return Ok(false);
+        }
+    };
+    let new_api_key = match created {
+        Ok(new_api_key) => new_api_key,
+        Err(error) => {
+            if let Some(rotation) = &mut entry.rotation {
+                rotation.next_rotation_at = current_timestamp.saturating_add(ROTATION_RETRY_DELAY);
+            }
+            app_state.write_to_api_keys(id.clone(), entry).await?;
+            return Err(error);
+        }
+    };
+
+    // The new key is stored before the old one is revoked, so that a failure cannot leave us
+    // with no usable key
+    let new_version = ApiKeyVersion {
+        version: entry.next_version(),
+        api_key: new_api_key,
+        deployed_at: current_timestamp,
+        expires_at: entry.active.expires_at,
+    };
+    let old_version = std::mem::replace(&mut entry.active, new_version);
+    if let Some(rotation) = &mut entry.rotation {
+        rotation.next_rotation_at = current_timestamp.saturating_add(recipe.interval);
+    }
+    app_state
+        .write_to_api_keys(id.clone(), entry.clone())
+        .await?;
+    drop(update_guard);
+
+    if let Some(revoke) = &recipe.revoke
+        && let Err(error) = revoke_api_key(
+            egress_policy,
+            id,
+            revoke,
+            &other_secrets,
+            entry.active.api_key.expose(),
+            old_version.api_key.expose(),
+        )
+        .await
+    {
+        tracing::error!(
+            "Rotated api key {} for {} but the old key could not be revoked: {error}",
+            id.secret_name,
+            id.service
+        );
+    }
+
+    Ok(true)
+}
+
+/// Make the revoke request of a rotation recipe, authenticated with one key, to revoke another.
+/// Returns why the key could not be revoked if it fails.
+async fn revoke_api_key(
+    egress_policy: &EgressPolicy,
+    id: &ApiKeyId,
+    revoke: &RotationRequest,
+    other_secrets: &[(&str, &str)],
+    api_key: &str,
+    revoked_api_key: &str,
+) -> Result<(), String> {
+    let substitutions = Substitutions::new(
+        other_secrets
+            .iter()
+            .copied()
+            .chain([(id.secret_name.as_str(), api_key)]),
+    )
+    .with_placeholder(OLD_API_KEY_PLACEHOLDER, revoked_api_key);
+    match send_rotation_request(egress_policy, &id.service, revoke, &substitutions).await {
+        Ok(response) if response.is_success() => Ok(()),
+        Ok(response) => Err(format!(
+            "the revoke request gave status {}",
+            response.status
+        )),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+/// Make the create request of a rotation recipe, returning the new key from its response
+async fn create_api_key(
+    egress_policy: &EgressPolicy,
+    service: &str,
+    recipe: &RotationRecipe,
+    substitutions: &Substitutions<'_>,
+) -> Result<SecretString, Err> {
+    let mut response =
+        send_rotation_request(egress_policy, service, &recipe.create, substitutions).await?;
+    if !response.is_success() {
+        return Err(Err::RotationFailed(format!(
+            "create request gave status {}",
+            response.status
+        )));
+    }
+    let body: Result<serde_json::Value, _> = serde_json::from_slice(&response.body);
+    response.body.zeroize();
+    let mut body =
+        body.map_err(|_| Err::RotationFailed("create response is not JSON".to_string()))?;
+    // The new key is moved out of the response rather than copied
+    match body
+        .pointer_mut(&recipe.new_key_pointer)
+        .map(serde_json::Value::take)
+    {
+        Some(serde_json::Value::String(new_api_key)) => Ok(new_api_key.into()),
+        _ => Err(Err::RotationFailed(
+            "new key not found in create response".to_string(),
+        )),
+    }
+}
+
+/// Make one of the requests of a rotation recipe
+async fn send_rotation_request(
+    egress_policy: &EgressPolicy,
+    service: &str,
+    request: &RotationRequest,
+    substitutions: &Substitutions<'_>,
+) -> Result<ApiResponse, Err> {
+    send_with_substitutions(
+        egress_policy,
+        UpstreamOptions {
+            timeout: Some(ROTATION_REQUEST_TIMEOUT),
+            follow_redirects: FollowRedirects::SameOrigin,
+        },
+        service,
+        substitutions,
+        request.http_verb,
+        &request.api_url,
+        &request.http_headers,
+        &request.request_body,
+    )
+    .await
+}
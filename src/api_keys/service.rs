@@ -0,0 +1,48 @@
+//! Identifying which service an api key may be used with
+use crate::errors::Err;
+use url::Url;
+
+/// Get the identifier of the service given by a URL, which is its origin (scheme, host and port)
+/// followed by its path. An api key deployed for this service will only be used for requests to
+/// the same origin, with a path which falls under this path.
+pub fn service_from_url(url: &Url) -> Result<String, Err> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(Err::UnsupportedUrlScheme);
+    }
+    url.host_str().ok_or(Err::UrlHost)?;
+    Ok(format!(
+        "{}{}",
+        url.origin().ascii_serialization(),
+        url.path()
+    ))
+}
+
+/// Check whether a request to the given URL falls under the given service
+pub fn service_matches(service: &str, url: &Url) -> bool {
+    let Ok(service) = Url::parse(service) else {
+        return false;
+    };
+    if service.origin() != url.origin() {
+        return false;
+    }
+    let prefix = service.path();
+    let path = url.path();
+    match path.strip_prefix(prefix) {
+        // Only match whole path segments, so that `/v2` does not match `/v2beta`
+        Some(remainder) => {
+            prefix.ends_with('/') || remainder.is_empty() || remainder.starts_with('/')
+        }
+        None => false,
+    }
+}
+
+/// Of the given services, find the one with the longest path which the given URL falls under
+pub fn longest_matching_service<'a>(
+    services: impl IntoIterator<Item = &'a str>,
+    url: &Url,
+) -> Option<&'a str> {
+    services
+        .into_iter()
+        .filter(|service| service_matches(service, url))
+        .max_by_key(|service| service.len())
+}
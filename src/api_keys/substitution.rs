@@ -0,0 +1,245 @@
+//! Replacing placeholders in requests with deployed secrets
+use base64::{
+    Engine,
+    prelude::{BASE64_STANDARD_NO_PAD, BASE64_URL_SAFE_NO_PAD},
+};
+use entropy_api_key_service_shared::{
+    API_KEY_PLACEHOLDER, DEFAULT_SECRET_NAME, REDACTED_SECRET, api_key_placeholder,
+};
+use std::borrow::Cow;
+use zeroize::{Zeroize, Zeroizing};
+
+/// The part which all placeholders begin with
+const PLACEHOLDER_START: &str = "xxxREPLACE_ME";
+
+/// The shortest base64 encoded part of a secret which is searched for, as shorter parts are likely
+/// to be found by chance
+const MIN_BASE64_FORM_LENGTH: usize = 8;
+
+/// How secrets are encoded when substituted, depending on the context of the placeholder
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    /// The secret is inserted as is
+    Raw,
+    /// The placeholder is inside a JSON string, so the secret is JSON-escaped
+    Json,
+    /// The placeholder is inside form-urlencoded data, so the secret is form-urlencoded
+    FormUrlEncoded,
+}
+
+impl Encoding {
+    /// Choose the encoding for a request body from its `Content-Type` header
+    pub fn from_content_type(content_type: Option<&str>) -> Self {
+        let Some(content_type) = content_type else {
+            return Self::Raw;
+        };
+        let media_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        if media_type == "application/json" || media_type.ends_with("+json") {
+            Self::Json
+        } else if media_type == "application/x-www-form-urlencoded" {
+            Self::FormUrlEncoded
+        } else {
+            Self::Raw
+        }
+    }
+
+    /// Encode a secret for this context
+    fn encode<'b>(&self, secret: &'b str) -> Cow<'b, str> {
+        match self {
+            Self::Raw => Cow::Borrowed(secret),
+            Self::Json => {
+                let quoted = serde_json::Value::from(secret).to_string();
+                Cow::Owned(quoted[1..quoted.len() - 1].to_string())
+            }
+            Self::FormUrlEncoded => {
+                Cow::Owned(url::form_urlencoded::byte_serialize(secret.as_bytes()).collect())
+            }
+        }
+    }
+}
+
+/// A set of placeholders and the secrets they should be replaced with
+pub struct Substitutions<'a> {
+    /// Pairs of (placeholder, secret), longest placeholder first
+    placeholders: Vec<(String, &'a str)>,
+}
+
+impl<'a> Substitutions<'a> {
+    /// Create substitutions from (secret name, secret) pairs. The secret named
+    /// [DEFAULT_SECRET_NAME] may also be referred to with [API_KEY_PLACEHOLDER]
+    pub fn new(secrets: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        let mut placeholders = Vec::new();
+        for (secret_name, secret) in secrets {
+            if secret_name == DEFAULT_SECRET_NAME {
+                placeholders.push((API_KEY_PLACEHOLDER.to_string(), secret));
+            }
+            placeholders.push((api_key_placeholder(secret_name), secret));
+        }
+        placeholders.sort_by_key(|(placeholder, _)| std::cmp::Reverse(placeholder.len()));
+        Self { placeholders }
+    }
+
+    /// Add a placeholder which is not derived from a secret name, such as
+    /// [entropy_api_key_service_shared::OLD_API_KEY_PLACEHOLDER]
+    pub fn with_placeholder(mut self, placeholder: &str, secret: &'a str) -> Self {
+        self.placeholders.push((placeholder.to_string(), secret));
+        self.placeholders
+            .sort_by_key(|(placeholder, _)| std::cmp::Reverse(placeholder.len()));
+        self
+    }
+
+    /// Replace all placeholders in the given input with the raw secrets
+    pub fn apply(&self, input: &str) -> String {
+        self.apply_encoded(input, Encoding::Raw)
+    }
+
+    /// Replace all placeholders in a request body, encoding the secrets according to the body's
+    /// `Content-Type` header
+    pub fn apply_to_body(&self, body: &str, content_type: Option<&str>) -> String {
+        self.apply_encoded(body, Encoding::from_content_type(content_type))
+    }
+
+    /// Replace all placeholders in the given input with the secrets, encoded with the given
+    /// encoding. This is done in a single pass, so that placeholders appearing in the secrets
+    /// themselves are not replaced.
+    pub fn apply_encoded(&self, input: &str, encoding: Encoding) -> String {
+        let mut output = String::with_capacity(input.len());
+        let mut remaining = input;
+        while let Some(position) = remaining.find(PLACEHOLDER_START) {
+            output.push_str(&remaining[..position]);
+            remaining = &remaining[position..];
+            match self
+                .placeholders
+                .iter()
+                .find(|(placeholder, _)| remaining.starts_with(placeholder.as_str()))
+            {
+                Some((placeholder, secret)) => {
+                    let encoded = encoding.encode(secret);
+                    output.push_str(&encoded);
+                    if let Cow::Owned(mut encoded) = encoded {
+                        encoded.zeroize();
+                    }
+                    remaining = &remaining[placeholder.len()..];
+                }
+                None => {
+                    output.push_str(PLACEHOLDER_START);
+                    remaining = &remaining[PLACEHOLDER_START.len()..];
+                }
+            }
+        }
+        output.push_str(remaining);
+        output
+    }
+
+    /// Get the placeholders in the given input which would be replaced, in order
+    pub fn placeholders_in(&self, input: &str) -> Vec<&str> {
+        let mut found = Vec::new();
+        let mut remaining = input;
+        while let Some(position) = remaining.find(PLACEHOLDER_START) {
+            remaining = &remaining[position..];
+            match self
+                .placeholders
+                .iter()
+                .find(|(placeholder, _)| remaining.starts_with(placeholder.as_str()))
+            {
+                Some((placeholder, _)) => {
+                    found.push(placeholder.as_str());
+                    remaining = &remaining[placeholder.len()..];
+                }
+                None => remaining = &remaining[PLACEHOLDER_START.len()..],
+            }
+        }
+        found
+    }
+
+    /// Whether the given input contains any of the secrets, in any of the forms searched for by
+    /// [Self::redact_bytes]
+    pub fn contains_secret(&self, input: &str) -> bool {
+        self.searched_forms().iter().any(|form| {
+            input
+                .as_bytes()
+                .windows(form.len())
+                .any(|window| window == &form[..])
+        })
+    }
+
+    /// Replace any of the secrets appearing in the given input with [REDACTED_SECRET]
+    pub fn redact(&self, input: &str) -> String {
+        String::from_utf8_lossy(&self.redact_bytes(input.as_bytes())).into_owned()
+    }
+
+    /// Replace any of the secrets appearing in the given input with [REDACTED_SECRET]. Secrets
+    /// are found as is, JSON-escaped, URL-encoded and base64-encoded, as upstream services may
+    /// echo them in any of these forms.
+    pub fn redact_bytes(&self, input: &[u8]) -> Vec<u8> {
+        let mut output = Zeroizing::new(input.to_vec());
+        for form in self.searched_forms() {
+            output = Zeroizing::new(replace_all(&output, &form, REDACTED_SECRET.as_bytes()));
+        }
+        std::mem::take(&mut *output)
+    }
+
+    /// All forms of all of the secrets, longest first, in case one contains another
+    fn searched_forms(&self) -> Vec<Zeroizing<Vec<u8>>> {
+        let mut forms: Vec<_> = self
+            .placeholders
+            .iter()
+            .map(|(_, secret)| *secret)
+            .filter(|secret| !secret.is_empty())
+            .flat_map(searched_forms)
+            .collect();
+        forms.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a[..].cmp(&b[..])));
+        forms.dedup();
+        forms
+    }
+}
+
+/// The forms in which a secret is searched for in data from upstream services
+fn searched_forms(secret: &str) -> Vec<Zeroizing<Vec<u8>>> {
+    let form_urlencoded = Encoding::FormUrlEncoded.encode(secret).into_owned();
+    let mut forms = vec![
+        Zeroizing::new(secret.as_bytes().to_vec()),
+        Zeroizing::new(Encoding::Json.encode(secret).into_owned().into_bytes()),
+        // URLs may encode spaces as %20 rather than +
+        Zeroizing::new(form_urlencoded.replace('+', "%20").into_bytes()),
+        Zeroizing::new(form_urlencoded.into_bytes()),
+    ];
+
+    // Base64 encodes groups of three bytes, so a secret within some base64 encoded data, such as
+    // basic authentication credentials, is encoded differently depending on its offset. For each
+    // offset, only the characters which depend on the secret alone are searched for.
+    for engine in [&BASE64_STANDARD_NO_PAD, &BASE64_URL_SAFE_NO_PAD] {
+        for offset in 0..3 {
+            let mut data = Zeroizing::new(vec![0u8; offset]);
+            data.extend_from_slice(secret.as_bytes());
+            let encoded = Zeroizing::new(engine.encode(&*data));
+            let start = (8 * offset).div_ceil(6);
+            let end = 8 * data.len() / 6;
+            if end >= start + MIN_BASE64_FORM_LENGTH {
+                forms.push(Zeroizing::new(encoded.as_bytes()[start..end].to_vec()));
+            }
+        }
+    }
+    forms
+}
+
+/// Replace all occurrences of a pattern, which must not be empty
+fn replace_all(input: &[u8], pattern: &[u8], replacement: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut position = 0;
+    while position < input.len() {
+        if input[position..].starts_with(pattern) {
+            output.extend_from_slice(replacement);
+            position += pattern.len();
+        } else {
+            output.push(input[position]);
+            position += 1;
+        }
+    }
+    output
+}
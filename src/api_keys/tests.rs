@@ -1,8 +1,42 @@
 use serial_test::serial;
 
-use super::api::{TIME_BUFFER, check_stale};
-use crate::test_helpers::{make_test_client, setup_client};
+use super::{
+    api::{TIME_BUFFER, check_stale, get_current_timestamp},
+    egress::{AllowedDestination, EgressPolicy, is_public},
+    injection::check_placeholders_allowed,
+    policy::glob_matches,
+    rotation::rotate_due_api_keys,
+    service::{longest_matching_service, service_from_url, service_matches},
+    substitution::{Encoding, Substitutions},
+};
+use crate::{
+    api_key_store::{ApiKeyEntry, ApiKeyId, ApiKeyVersion},
+    test_helpers::{
+        INITIAL_ROTATING_API_KEY, OTHER_ORIGIN, ROTATING_SLOW_CREATE_DELAY,
+        VALID_API_KEY_WITH_SPECIAL_CHARACTERS, make_test_client, setup_client,
+    },
+};
+use axum::{
+    Json, Router,
+    http::{StatusCode, Uri},
+};
+use base64::{
+    Engine,
+    prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD},
+};
+use entropy_api_key_service_client::{
+    ApiKeyServiceClient, BackupApiKey, BackupApiKeyVersion, DeployOptions, Expiry, HttpMethod,
+    INJECTION_FORMAT_KEY, InjectionRule, KeyVersion, MakeRequestOptions, OLD_API_KEY_PLACEHOLDER,
+    REDACTED_SECRET, RequestPolicy, RotationRecipe, RotationRequest, StaticSecret, X25519PublicKey,
+    errors::ClientError,
+};
+use entropy_api_key_service_shared::{
+    API_KEY_PLACEHOLDER, DEFAULT_SECRET_NAME, DELETE_SECRET_ROUTE, DEPLOY_API_KEY_ROUTE,
+    DeleteApiKeyInfo, DeployApiKeyInfo, ErrorCode, ErrorResponse, MAKE_REQUEST_ROUTE, NONCE_LENGTH,
+    api_key_fingerprint, api_key_placeholder, associated_data,
+};
 use entropy_protocol::sign_and_encrypt::EncryptedSignedMessage;
+use rand_core::OsRng;
 use reqwest::{Body, Method, Url};
 use sp_core::Pair;
 use sp_keyring::sr25519::Keyring;
@@ -24,17 +58,17 @@ async fn test_deploy_change_and_delte_api_key() {
         .await
         .unwrap();
 
-    let api_url_mock = Url::parse(&api_url)
-        .unwrap()
-        .host_str()
-        .unwrap()
-        .to_string();
+    let api_key_id = default_api_key_id(&one, &Url::parse(&api_url).unwrap());
 
     assert_eq!(
         app_state
-            .read_from_api_keys(&(one.pair().public().0, api_url_mock.clone()))
+            .read_from_api_keys(&api_key_id)
+            .await
             .unwrap()
-            .unwrap(),
+            .unwrap()
+            .active
+            .api_key
+            .expose(),
         api_key
     );
 
@@ -45,9 +79,13 @@ async fn test_deploy_change_and_delte_api_key() {
 
     assert_eq!(
         app_state
-            .read_from_api_keys(&(one.pair().public().0, api_url_mock.clone()))
+            .read_from_api_keys(&api_key_id)
+            .await
             .unwrap()
-            .unwrap(),
+            .unwrap()
+            .active
+            .api_key
+            .expose(),
         api_key_2
     );
 
@@ -55,12 +93,581 @@ async fn test_deploy_change_and_delte_api_key() {
 
     assert!(
         app_state
-            .read_from_api_keys(&(one.pair().public().0, api_url_mock))
+            .read_from_api_keys(&api_key_id)
+            .await
             .unwrap()
             .is_none(),
     );
 }
 
+#[tokio::test]
+#[serial]
+async fn test_list_api_keys() {
+    let app_state = setup_client().await;
+    let one = Keyring::One;
+    let client = make_test_client(&app_state, &one);
+
+    assert!(client.list().await.unwrap().is_empty());
+
+    client
+        .deploy_named_api_key(
+            "client_secret".to_string(),
+            "some-secret".to_string(),
+            "https://api.example.com/v2".to_string(),
+        )
+        .await
+        .unwrap();
+    client
+        .deploy_api_key(
+            "other-secret".to_string(),
+            "https://api.example.com".to_string(),
+        )
+        .await
+        .unwrap();
+    // Another user's keys are not listed
+    make_test_client(&app_state, &Keyring::Two)
+        .deploy_api_key(
+            "some-secret".to_string(),
+            "https://api.example.com".to_string(),
+        )
+        .await
+        .unwrap();
+
+    let api_keys = client.list().await.unwrap();
+    assert_eq!(api_keys.len(), 2);
+
+    assert_eq!(api_keys[0].service, "https://api.example.com/");
+    assert_eq!(api_keys[0].secret_name, DEFAULT_SECRET_NAME);
+    assert_eq!(
+        api_keys[0].fingerprint,
+        api_key_fingerprint(&one.pair().public().0, "other-secret")
+    );
+
+    assert_eq!(api_keys[1].service, "https://api.example.com/v2");
+    assert_eq!(api_keys[1].secret_name, "client_secret");
+    assert_eq!(
+        api_keys[1].fingerprint,
+        api_key_fingerprint(&one.pair().public().0, "some-secret")
+    );
+    assert!(
+        api_keys[1]
+            .deployed_at
+            .abs_diff(get_current_timestamp().unwrap())
+            < TIME_BUFFER
+    );
+
+    // The fingerprint does not reveal the secret, and differs between users
+    assert!(!api_keys[1].fingerprint.contains("some-secret"));
+    assert_ne!(
+        api_keys[1].fingerprint,
+        api_key_fingerprint(&Keyring::Two.pair().public().0, "some-secret")
+    );
+
+    client
+        .delete_api_key("https://api.example.com".to_string())
+        .await
+        .unwrap();
+    assert_eq!(client.list().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_export_and_import_api_keys() {
+    let app_state = setup_client().await;
+    let one = Keyring::One;
+    let client = make_test_client(&app_state, &one);
+
+    client
+        .deploy_named_api_key(
+            "client_secret".to_string(),
+            "some-secret".to_string(),
+            "https://api.example.com/v2".to_string(),
+        )
+        .await
+        .unwrap();
+    client
+        .deploy_with_options(
+            "other-secret".to_string(),
+            "https://api.example.com".to_string(),
+            DeployOptions {
+                expiry: Some(Expiry::Ttl(3600)),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    let api_keys = client.list().await.unwrap();
+
+    let backup_secret = StaticSecret::random_from_rng(OsRng);
+    let backup = client
+        .export(X25519PublicKey::from(&backup_secret).to_bytes())
+        .await
+        .unwrap();
+    // The backup can only be read with the backup key, not by the service
+    assert!(
+        backup
+            .decrypt(&app_state.identity.x25519_secret, &[])
+            .is_err()
+    );
+
+    assert!(matches!(
+        client
+            .delete_named_api_key("../default".to_string(), api_keys[0].service.clone())
+            .await,
+        Err(ClientError::InvalidSecretName)
+    ));
+    for api_key in &api_keys {
+        client
+            .delete_named_api_key(api_key.secret_name.clone(), api_key.service.clone())
+            .await
+            .unwrap();
+    }
+    assert!(client.list().await.unwrap().is_empty());
+
+    assert!(matches!(
+        client
+            .import(&backup, &StaticSecret::random_from_rng(OsRng))
+            .await,
+        Err(ClientError::EncryptionOrAuthentication(_))
+    ));
+    // Another user cannot import the backup, even with the backup key
+    assert!(matches!(
+        make_test_client(&app_state, &Keyring::Two)
+            .import(&backup, &backup_secret)
+            .await,
+        Err(ClientError::EncryptionOrAuthentication(_))
+    ));
+
+    assert_eq!(client.import(&backup, &backup_secret).await.unwrap(), 2);
+    let imported = client.list().await.unwrap();
+    assert_eq!(imported.len(), 2);
+    for (imported, original) in imported.iter().zip(&api_keys) {
+        assert_eq!(imported.service, original.service);
+        assert_eq!(imported.secret_name, original.secret_name);
+        assert_eq!(imported.fingerprint, original.fingerprint);
+        assert_eq!(imported.expires_at, original.expires_at);
+    }
+
+    // Imported keys are checked in the same way as deployed keys
+    let invalid_service = BackupApiKey {
+        service: "https://api.example.com/v2?query".to_string(),
+        secret_name: DEFAULT_SECRET_NAME.to_string(),
+        active: BackupApiKeyVersion {
+            api_key: "some-secret".to_string().into(),
+            expires_at: None,
+        },
+        pending: None,
+        rotation: None,
+        injection: None,
+        policy: None,
+        pending_injection: None,
+        pending_policy: None,
+    };
+    assert!(matches!(
+        client.import_api_keys(vec![invalid_service]).await,
+        Err(ClientError::InvalidBackup(_))
+    ));
+}
+
+#[tokio::test]
+#[serial]
+async fn test_api_key_expiry() {
+    let app_state = setup_client().await;
+    let one = Keyring::One;
+    let client = make_test_client(&app_state, &one);
+    let api_url = "http://127.0.0.1:3002/protected?api-key=xxxREPLACE_MExxx";
+
+    let now = get_current_timestamp().unwrap();
+    let options = |expiry| DeployOptions {
+        expiry: Some(expiry),
+        ..Default::default()
+    };
+    assert!(matches!(
+        client
+            .deploy_with_options(
+                "some-secret".to_string(),
+                "http://127.0.0.1:3002".to_string(),
+                options(Expiry::At(now - 1)),
+            )
+            .await,
+        Err(ClientError::ExpiryInPast)
+    ));
+
+    client
+        .deploy_with_options(
+            "some-secret".to_string(),
+            "http://127.0.0.1:3002".to_string(),
+            options(Expiry::Ttl(600)),
+        )
+        .await
+        .unwrap();
+    let api_keys = client.list().await.unwrap();
+    assert_eq!(api_keys[0].expires_at, Some(api_keys[0].deployed_at + 600));
+
+    let request = reqwest::Request::new(Method::GET, Url::parse(api_url).unwrap());
+    let response = client.make_request(request, vec![]).await.unwrap();
+    assert_eq!(response.status, 200);
+
+    // Make the key expire
+    let api_key_id = default_api_key_id(&one, &Url::parse(api_url).unwrap());
+    let mut entry = app_state
+        .read_from_api_keys(&api_key_id)
+        .await
+        .unwrap()
+        .unwrap();
+    entry.active.expires_at = Some(now);
+    app_state
+        .write_to_api_keys(api_key_id.clone(), entry)
+        .await
+        .unwrap();
+
+    let request = reqwest::Request::new(Method::GET, Url::parse(api_url).unwrap());
+    assert!(matches!(
+        client.make_request(request, vec![]).await,
+        Err(ClientError::ApiKeyExpired)
+    ));
+    // Requests which do not use the expired key may still be made
+    let request = reqwest::Request::new(
+        Method::GET,
+        Url::parse("http://127.0.0.1:3002/protected").unwrap(),
+    );
+    assert!(client.make_request(request, vec![]).await.is_ok());
+
+    // Once purged, the key is gone
+    assert_eq!(app_state.api_keys.delete_expired(now).await.unwrap(), 1);
+    assert!(
+        app_state
+            .read_from_api_keys(&api_key_id)
+            .await
+            .unwrap()
+            .is_none()
+    );
+    let request = reqwest::Request::new(Method::GET, Url::parse(api_url).unwrap());
+    assert!(matches!(
+        client.make_request(request, vec![]).await,
+        Err(ClientError::NoKeyForService)
+    ));
+}
+
+#[tokio::test]
+#[serial]
+async fn test_api_key_rotation_and_rollback() {
+    let app_state = setup_client().await;
+    let one = Keyring::One;
+    let client = make_test_client(&app_state, &one);
+    let api_url = "http://127.0.0.1:3002".to_string();
+    let request = || {
+        reqwest::Request::new(
+            Method::GET,
+            Url::parse("http://127.0.0.1:3002/protected?api-key=xxxREPLACE_MExxx").unwrap(),
+        )
+    };
+    let pending = DeployOptions {
+        pending: true,
+        ..Default::default()
+    };
+
+    // A pending version can only be added to an existing key
+    assert!(matches!(
+        client
+            .deploy_with_options("some-secret".to_string(), api_url.clone(), pending.clone())
+            .await,
+        Err(ClientError::NoKeyForService)
+    ));
+
+    client
+        .deploy_api_key("wrong-secret".to_string(), api_url.clone())
+        .await
+        .unwrap();
+    client
+        .deploy_with_options("some-secret".to_string(), api_url.clone(), pending)
+        .await
+        .unwrap();
+
+    let api_keys = client.list().await.unwrap();
+    assert_eq!(api_keys[0].version, 1);
+    assert_eq!(api_keys[0].pending.as_ref().unwrap().version, 2);
+
+    // The pending version can be tried out before promoting it
+    let response = client.make_request(request(), vec![]).await.unwrap();
+    assert_eq!(response.status, 401);
+    let response = client
+        .make_request_with_key_version(request(), vec![], KeyVersion::Pending)
+        .await
+        .unwrap();
+    assert_eq!(response.status, 200);
+
+    client
+        .promote_api_key(None, api_url.clone(), 600)
+        .await
+        .unwrap();
+    let response = client.make_request(request(), vec![]).await.unwrap();
+    assert_eq!(response.status, 200);
+    // The old version is still usable during the grace period
+    let response = client
+        .make_request_with_key_version(request(), vec![], KeyVersion::Previous)
+        .await
+        .unwrap();
+    assert_eq!(response.status, 401);
+
+    let api_keys = client.list().await.unwrap();
+    assert_eq!(api_keys[0].version, 2);
+    assert!(api_keys[0].pending.is_none());
+    let previous = api_keys[0].previous.as_ref().unwrap();
+    assert_eq!(previous.version, 1);
+    assert_eq!(
+        previous.fingerprint,
+        api_key_fingerprint(&one.pair().public().0, "wrong-secret")
+    );
+    let expected_usable_until = get_current_timestamp().unwrap() + 600;
+    assert!(
+        previous
+            .usable_until
+            .unwrap()
+            .abs_diff(expected_usable_until)
+            < TIME_BUFFER
+    );
+
+    client
+        .rollback_api_key(None, api_url.clone())
+        .await
+        .unwrap();
+    let response = client.make_request(request(), vec![]).await.unwrap();
+    assert_eq!(response.status, 401);
+
+    // The active version is not used instead of a version which does not exist
+    assert!(matches!(
+        client
+            .make_request_with_key_version(request(), vec![], KeyVersion::Pending)
+            .await,
+        Err(ClientError::NoPendingVersion)
+    ));
+    assert!(matches!(
+        client
+            .make_request_with_key_version(request(), vec![], KeyVersion::Previous)
+            .await,
+        Err(ClientError::NoPreviousVersion)
+    ));
+
+    assert!(matches!(
+        client.rollback_api_key(None, api_url.clone()).await,
+        Err(ClientError::NoPreviousVersion)
+    ));
+    assert!(matches!(
+        client.promote_api_key(None, api_url, 600).await,
+        Err(ClientError::NoPendingVersion)
+    ));
+}
+
+#[tokio::test]
+#[serial]
+async fn test_provider_managed_rotation() {
+    let app_state = setup_client().await;
+    let one = Keyring::One;
+    let client = make_test_client(&app_state, &one);
+    let api_url = "http://127.0.0.1:3002/rotating".to_string();
+    let bearer = vec![(
+        "Authorization".to_string(),
+        "Bearer xxxREPLACE_MExxx".to_string(),
+    )];
+    let recipe = RotationRecipe {
+        interval: 3600,
+        create: RotationRequest {
+            http_verb: HttpMethod::Post,
+            api_url: "http://127.0.0.1:3002/rotating/keys".to_string(),
+            http_headers: bearer.clone(),
+            request_body: String::new(),
+        },
+        new_key_pointer: "/data/secret".to_string(),
+        revoke: Some(RotationRequest {
+            http_verb: HttpMethod::Post,
+            api_url: "http://127.0.0.1:3002/rotating/revoke".to_string(),
+            http_headers: [
+                bearer,
+                vec![("Content-Type".to_string(), "application/json".to_string())],
+            ]
+            .concat(),
+            request_body: format!("{{\"key\": \"{OLD_API_KEY_PLACEHOLDER}\"}}"),
+        }),
+    };
+    let request = || {
+        let mut request = reqwest::Request::new(
+            Method::GET,
+            Url::parse("http://127.0.0.1:3002/rotating/protected").unwrap(),
+        );
+        request.headers_mut().insert(
+            "Authorization",
+            "Bearer xxxREPLACE_MExxx".try_into().unwrap(),
+        );
+        request
+    };
+
+    // Secrets may only be sent to the service they were deployed for
+    let outside_service = DeployOptions {
+        rotation: Some(RotationRecipe {
+            create: RotationRequest {
+                api_url: "http://127.0.0.1:3002/protected".to_string(),
+                ..recipe.create.clone()
+            },
+            ..recipe.clone()
+        }),
+        ..Default::default()
+    };
+    assert!(matches!(
+        client
+            .deploy_with_options(
+                INITIAL_ROTATING_API_KEY.to_string(),
+                api_url.clone(),
+                outside_service
+            )
+            .await,
+        Err(ClientError::InvalidRotationRecipe(_))
+    ));
+
+    // The new key must be taken from within the create response
+    let empty_pointer = DeployOptions {
+        rotation: Some(RotationRecipe {
+            new_key_pointer: String::new(),
+            ..recipe.clone()
+        }),
+        ..Default::default()
+    };
+    assert!(matches!(
+        client
+            .deploy_with_options(
+                INITIAL_ROTATING_API_KEY.to_string(),
+                api_url.clone(),
+                empty_pointer
+            )
+            .await,
+        Err(ClientError::InvalidRotationRecipe(_))
+    ));
+
+    let options = DeployOptions {
+        rotation: Some(recipe),
+        ..Default::default()
+    };
+    client
+        .deploy_with_options(INITIAL_ROTATING_API_KEY.to_string(), api_url, options)
+        .await
+        .unwrap();
+    let response = client.make_request(request(), vec![]).await.unwrap();
+    assert_eq!(response.status, 200);
+
+    let current_timestamp = get_current_timestamp().unwrap();
+    let api_keys = client.list().await.unwrap();
+    let next_rotation_at = api_keys[0].next_rotation_at.unwrap();
+    assert!(next_rotation_at.abs_diff(current_timestamp + 3600) < TIME_BUFFER);
+
+    // Nothing is rotated before it is due
+    assert_eq!(
+        rotate_due_api_keys(&app_state, current_timestamp)
+            .await
+            .unwrap(),
+        0
+    );
+    assert_eq!(
+        rotate_due_api_keys(&app_state, next_rotation_at)
+            .await
+            .unwrap(),
+        1
+    );
+
+    let api_keys = client.list().await.unwrap();
+    assert_eq!(api_keys[0].version, 2);
+    assert_ne!(
+        api_keys[0].fingerprint,
+        api_key_fingerprint(&one.pair().public().0, INITIAL_ROTATING_API_KEY)
+    );
+    assert_eq!(api_keys[0].next_rotation_at, Some(next_rotation_at + 3600));
+
+    // The new key works, and the old one has been revoked
+    let response = client.make_request(request(), vec![]).await.unwrap();
+    assert_eq!(response.status, 200);
+    let response = reqwest::Client::new()
+        .get("http://127.0.0.1:3002/rotating/protected")
+        .bearer_auth(INITIAL_ROTATING_API_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 401);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_key_changed_during_rotation() {
+    let app_state = setup_client().await;
+    let one = Keyring::One;
+    let client = make_test_client(&app_state, &one);
+    let api_url = "http://127.0.0.1:3002/rotating".to_string();
+    let bearer = vec![(
+        "Authorization".to_string(),
+        "Bearer xxxREPLACE_MExxx".to_string(),
+    )];
+    let options = DeployOptions {
+        rotation: Some(RotationRecipe {
+            interval: 3600,
+            create: RotationRequest {
+                http_verb: HttpMethod::Post,
+                api_url: "http://127.0.0.1:3002/rotating/slow-keys".to_string(),
+                http_headers: bearer.clone(),
+                request_body: String::new(),
+            },
+            new_key_pointer: "/data/secret".to_string(),
+            revoke: Some(RotationRequest {
+                http_verb: HttpMethod::Post,
+                api_url: "http://127.0.0.1:3002/rotating/revoke".to_string(),
+                http_headers: [
+                    bearer,
+                    vec![("Content-Type".to_string(), "application/json".to_string())],
+                ]
+                .concat(),
+                request_body: format!("{{\"key\": \"{OLD_API_KEY_PLACEHOLDER}\"}}"),
+            }),
+        }),
+        ..Default::default()
+    };
+    let accepted_key_count = || async {
+        reqwest::get("http://127.0.0.1:3002/rotating/keys")
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap()
+    };
+
+    client
+        .deploy_with_options(
+            INITIAL_ROTATING_API_KEY.to_string(),
+            api_url.clone(),
+            options.clone(),
+        )
+        .await
+        .unwrap();
+    let next_rotation_at = client.list().await.unwrap()[0].next_rotation_at.unwrap();
+
+    // The key is redeployed while the provider creates a new one
+    let rotation = tokio::spawn({
+        let app_state = app_state.clone();
+        async move { rotate_due_api_keys(&app_state, next_rotation_at).await }
+    });
+    tokio::time::sleep(ROTATING_SLOW_CREATE_DELAY / 2).await;
+    client
+        .deploy_with_options(INITIAL_ROTATING_API_KEY.to_string(), api_url, options)
+        .await
+        .unwrap();
+    assert_eq!(rotation.await.unwrap().unwrap(), 0);
+
+    // The redeployed key is kept, and the key created for the rotation has been revoked
+    let api_keys = client.list().await.unwrap();
+    assert_eq!(api_keys[0].version, 2);
+    assert_eq!(
+        api_keys[0].fingerprint,
+        api_key_fingerprint(&one.pair().public().0, INITIAL_ROTATING_API_KEY)
+    );
+    assert_eq!(accepted_key_count().await, "1");
+}
+
 #[tokio::test]
 #[serial]
 async fn test_make_request_get() {
@@ -70,9 +677,11 @@ async fn test_make_request_get() {
     let api_key =
         "live_MdrxblW1YgdnmuI3jVSJNLSqcdljuF3T2PDy26hWXk7fROoojH479EkhrDhYJIy4".to_string();
     let api_url = Url::parse(api_url_string).unwrap();
-    let api_url_mock = api_url.host_str().unwrap().to_string();
+    let api_key_id = default_api_key_id(&one, &api_url);
 
-    let _ = app_state.write_to_api_keys((one.pair().public().0, api_url_mock.to_string()), api_key);
+    let _ = app_state
+        .write_to_api_keys(api_key_id, api_key_entry(api_key))
+        .await;
 
     let client = make_test_client(&app_state, &one);
 
@@ -81,8 +690,8 @@ async fn test_make_request_get() {
     *body = Some(Body::wrap("test".to_string()));
     let response = client.make_request(request, vec![]).await.unwrap();
 
-    assert_eq!(response.status(), 200);
-    assert_eq!(&response.text().await.unwrap()[0..10], "[{\"breeds\"");
+    assert_eq!(response.status, 200);
+    assert_eq!(&response.text()[0..10], "[{\"breeds\"");
 }
 
 #[tokio::test]
@@ -93,8 +702,10 @@ async fn test_make_request_get_with_local_test_server() {
     let api_url_string = "http://127.0.0.1:3002/protected?api-key=xxxREPLACE_MExxx";
     let api_key = "some-secret".to_string();
     let api_url = Url::parse(api_url_string).unwrap();
-    let api_url_mock = api_url.host_str().unwrap().to_string();
-    let _ = app_state.write_to_api_keys((one.pair().public().0, api_url_mock), api_key);
+    let api_key_id = default_api_key_id(&one, &api_url);
+    let _ = app_state
+        .write_to_api_keys(api_key_id, api_key_entry(api_key))
+        .await;
 
     let client = make_test_client(&app_state, &one);
 
@@ -103,8 +714,8 @@ async fn test_make_request_get_with_local_test_server() {
     *body = Some(Body::wrap("test".to_string()));
     let response = client.make_request(request, vec![]).await.unwrap();
 
-    assert_eq!(response.status(), 200);
-    assert_eq!(&response.text().await.unwrap(), "Success response");
+    assert_eq!(response.status, 200);
+    assert_eq!(&response.text(), "Success response");
 }
 
 #[tokio::test]
@@ -115,8 +726,10 @@ async fn test_make_request_post_with_local_test_server() {
     let api_url_string = "http://127.0.0.1:3002/protected";
     let api_key = "some-secret".to_string();
     let api_url = Url::parse(api_url_string).unwrap();
-    let api_url_mock = api_url.host_str().unwrap().to_string();
-    let _ = app_state.write_to_api_keys((one.pair().public().0, api_url_mock), api_key);
+    let api_key_id = default_api_key_id(&one, &api_url);
+    let _ = app_state
+        .write_to_api_keys(api_key_id, api_key_entry(api_key))
+        .await;
 
     let client = make_test_client(&app_state, &one);
 
@@ -131,46 +744,1377 @@ async fn test_make_request_post_with_local_test_server() {
         .await
         .unwrap();
 
-    assert_eq!(response.status(), 200);
+    assert_eq!(response.status, 200);
+    assert_eq!(&response.text(), "Succcess response - input was ");
+}
+
+#[tokio::test]
+#[serial]
+async fn test_make_request_returns_upstream_status_and_headers() {
+    let app_state = setup_client().await;
+    let one = Keyring::One;
+    let client = make_test_client(&app_state, &one);
+    client
+        .deploy_api_key(
+            "wrong-secret".to_string(),
+            "http://127.0.0.1:3002".to_string(),
+        )
+        .await
+        .unwrap();
+
+    let request = reqwest::Request::new(
+        Method::GET,
+        Url::parse("http://127.0.0.1:3002/protected?api-key=xxxREPLACE_MExxx").unwrap(),
+    );
+    let response = client.make_request(request, vec![]).await.unwrap();
+    assert_eq!(response.status, 401);
+    assert!(!response.is_success());
+
+    client
+        .deploy_api_key(
+            "some-secret".to_string(),
+            "http://127.0.0.1:3002".to_string(),
+        )
+        .await
+        .unwrap();
+
+    let request = reqwest::Request::new(
+        Method::GET,
+        Url::parse("http://127.0.0.1:3002/protected?api-key=xxxREPLACE_MExxx").unwrap(),
+    );
+    let response = client.make_request(request, vec![]).await.unwrap();
+    assert!(response.is_success());
     assert_eq!(
-        &response.text().await.unwrap(),
-        "Succcess response - input was "
+        response.header("Content-Type"),
+        Some("text/plain; charset=utf-8")
     );
+    assert_eq!(response.body, b"Success response");
 }
 
-// TODO: negative test for deploy key and make request
-// TODO: test post
 #[tokio::test]
-async fn test_stale_check() {
-    let result = check_stale(1, 1).await;
-    assert!(result.is_ok());
+#[serial]
+async fn test_make_request_only_uses_key_for_its_service() {
+    let app_state = setup_client().await;
+    let one = Keyring::One;
+    let client = make_test_client(&app_state, &one);
 
-    let result_server_larger = check_stale(1, 2).await;
-    assert!(result_server_larger.is_ok());
+    // Same host, but a different scheme or path
+    for service in ["https://127.0.0.1:3002", "http://127.0.0.1:3002/other/"] {
+        client
+            .deploy_api_key("some-secret".to_string(), service.to_string())
+            .await
+            .unwrap();
+    }
 
-    let result_user_larger = check_stale(2, 1).await;
-    assert!(result_user_larger.is_ok());
+    let api_url = Url::parse("http://127.0.0.1:3002/protected?api-key=xxxREPLACE_MExxx").unwrap();
+    let request = reqwest::Request::new(Method::GET, api_url.clone());
+    assert!(matches!(
+        client.make_request(request, vec![]).await,
+        Err(ClientError::NoKeyForService)
+    ));
 
-    let fail_stale = check_stale(1, 2 + TIME_BUFFER).await.unwrap_err();
-    assert_eq!(fail_stale.to_string(), "Message is too old".to_string());
+    client
+        .deploy_api_key(
+            "some-secret".to_string(),
+            "http://127.0.0.1:3002".to_string(),
+        )
+        .await
+        .unwrap();
+
+    let request = reqwest::Request::new(Method::GET, api_url);
+    let response = client.make_request(request, vec![]).await.unwrap();
+    assert_eq!(response.status, 200);
 }
 
-// Allowing dead code here because we probably will use this for negative tests
-#[allow(dead_code)]
-pub async fn submit_transaction_request(
-    box_url_and_key: (String, [u8; 32]),
-    signature_request: Vec<u8>,
-    keyring: Keyring,
-) -> std::result::Result<reqwest::Response, reqwest::Error> {
-    let mock_client = reqwest::Client::new();
-    let signed_message =
-        EncryptedSignedMessage::new(&keyring.pair(), signature_request, &box_url_and_key.1, &[])
-            .unwrap();
+#[tokio::test]
+#[serial]
+async fn test_unreachable_upstream_gives_retryable_error() {
+    let app_state = setup_client().await;
+    let one = Keyring::One;
+    let client = make_test_client(&app_state, &one);
 
-    mock_client
-        .post(box_url_and_key.0.clone())
-        .header("Content-Type", "application/json")
-        .body(serde_json::to_string(&signed_message).unwrap())
-        .send()
+    // Nothing is listening on this port
+    let api_url = "http://127.0.0.1:3009";
+    client
+        .deploy_api_key("some-secret".to_string(), api_url.to_string())
         .await
+        .unwrap();
+
+    let request = reqwest::Request::new(Method::GET, Url::parse(api_url).unwrap());
+    let error = client.make_request(request, vec![]).await.unwrap_err();
+    assert!(matches!(&error, ClientError::Upstream(_)));
+    assert!(error.is_retryable());
+
+    // The error does not reveal a secret in the URL of the request
+    let request = reqwest::Request::new(
+        Method::GET,
+        Url::parse(&format!("{api_url}/?api-key={API_KEY_PLACEHOLDER}")).unwrap(),
+    );
+    let error = client.make_request(request, vec![]).await.unwrap_err();
+    let ClientError::Upstream(message) = &error else {
+        panic!("Unexpected error: {error:?}");
+    };
+    assert!(!message.contains("some-secret"));
+}
+
+/// Starts a server which can decrypt requests meant for the given account, as if it held the
+/// service's encryption key, but signs its responses with another account. Requests for
+/// [MAKE_REQUEST_ROUTE] get a signed error response, and others a signed success response.
+async fn start_impostor_service(address: &str, x25519_secret: StaticSecret, account_id: [u8; 32]) {
+    let handler = move |uri: Uri, Json(request): Json<EncryptedSignedMessage>| {
+        let x25519_secret = x25519_secret.clone();
+        async move {
+            let associated_data = associated_data(uri.path(), &account_id);
+            let signed_message = request.decrypt(&x25519_secret, &associated_data).unwrap();
+            let (status, payload) = if uri.path() == MAKE_REQUEST_ROUTE {
+                let error_response = ErrorResponse {
+                    code: ErrorCode::NoKeyForService,
+                    message: "No api key for user url".to_string(),
+                };
+                (
+                    StatusCode::NOT_FOUND,
+                    serde_json::to_vec(&error_response).unwrap(),
+                )
+            } else {
+                (StatusCode::OK, serde_json::to_vec(&()).unwrap())
+            };
+            let response = EncryptedSignedMessage::new(
+                &Keyring::Three.pair(),
+                payload,
+                &signed_message.receiver_x25519.unwrap(),
+                &associated_data,
+            )
+            .unwrap();
+            (status, Json(response))
+        }
+    };
+    let listener = tokio::net::TcpListener::bind(address).await.unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, Router::new().fallback(handler))
+            .await
+            .unwrap();
+    });
+}
+
+#[tokio::test]
+#[serial]
+async fn test_responses_must_be_signed_by_selected_service() {
+    let app_state = setup_client().await;
+    let one = Keyring::One;
+
+    // A response signed by another account is rejected, whether it is a success or an error
+    let x25519_secret = StaticSecret::random_from_rng(OsRng);
+    let x25519_public_key = X25519PublicKey::from(&x25519_secret).to_bytes();
+    let account_id = Keyring::Two.pair().public().0;
+    start_impostor_service("0.0.0.0:3005", x25519_secret, account_id).await;
+    let client = ApiKeyServiceClient::new(
+        "http://127.0.0.1:3005".to_string(),
+        x25519_public_key,
+        account_id,
+        one.pair(),
+    );
+    assert!(matches!(
+        client
+            .deploy_api_key(
+                "some-secret".to_string(),
+                "http://127.0.0.1:3002".to_string(),
+            )
+            .await,
+        Err(ClientError::UnexpectedResponseSigner)
+    ));
+    let request = reqwest::Request::new(
+        Method::GET,
+        Url::parse("http://127.0.0.1:3002/protected?api_key=xxxREPLACE_MExxx").unwrap(),
+    );
+    assert!(matches!(
+        client.make_request(request, vec![]).await,
+        Err(ClientError::UnexpectedResponseSigner)
+    ));
+
+    // When the client expects a different account, the service cannot decrypt the request, so its
+    // error cannot be signed and is not trusted
+    let client = ApiKeyServiceClient::new(
+        "http://127.0.0.1:3001".to_string(),
+        app_state.x25519_public_key(),
+        account_id,
+        one.pair(),
+    );
+    assert!(matches!(
+        client
+            .deploy_api_key(
+                "some-secret".to_string(),
+                "http://127.0.0.1:3002".to_string(),
+            )
+            .await,
+        Err(ClientError::UnauthenticatedResponse(status, _)) if status == 401
+    ));
+}
+
+#[tokio::test]
+#[serial]
+async fn test_replayed_request_is_rejected() {
+    let app_state = setup_client().await;
+    let one = Keyring::One;
+    let api_url = "http://127.0.0.1:3002".to_string();
+
+    let deploy_api_key_info = DeployApiKeyInfo {
+        api_key: "some-secret".to_string(),
+        api_url: api_url.clone(),
+        secret_name: None,
+        expiry: None,
+        pending: false,
+        rotation: None,
+        injection: None,
+        policy: None,
+        timestamp: get_current_timestamp().unwrap(),
+        nonce: [1; NONCE_LENGTH],
+    };
+    let (signed_message, response_secret_key) = EncryptedSignedMessage::new_with_receiver(
+        &one.pair(),
+        serde_json::to_vec(&deploy_api_key_info).unwrap(),
+        &app_state.x25519_public_key(),
+        &app_state.associated_data(DEPLOY_API_KEY_ROUTE),
+    )
+    .unwrap();
+
+    let send = || async {
+        reqwest::Client::new()
+            .post("http://127.0.0.1:3001/deploy-api-key")
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_string(&signed_message).unwrap())
+            .send()
+            .await
+            .unwrap()
+    };
+
+    let response = send().await;
+    assert_eq!(response.status(), 200);
+
+    // The key is deleted, and then an attacker replays the deploy request
+    let client = make_test_client(&app_state, &one);
+    client.delete_api_key(api_url.clone()).await.unwrap();
+
+    let response = send().await;
+    assert_eq!(response.status(), 409);
+    let encrypted_response: EncryptedSignedMessage = response.json().await.unwrap();
+    let signed_response = encrypted_response
+        .decrypt(
+            &response_secret_key,
+            &app_state.associated_data(DEPLOY_API_KEY_ROUTE),
+        )
+        .unwrap();
+    let error_response: ErrorResponse = serde_json::from_slice(&signed_response.message.0).unwrap();
+    assert_eq!(error_response.code, ErrorCode::Replayed);
+
+    let api_key_id = default_api_key_id(&one, &Url::parse(&api_url).unwrap());
+    assert!(
+        app_state
+            .read_from_api_keys(&api_key_id)
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    // Making the same request twice with the client is fine as each has a new nonce
+    client
+        .deploy_api_key("some-secret".to_string(), api_url.clone())
+        .await
+        .unwrap();
+    client
+        .deploy_api_key("some-secret".to_string(), api_url)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+#[serial]
+async fn test_request_for_other_route_or_server_is_rejected() {
+    let app_state = setup_client().await;
+    let one = Keyring::One;
+    let api_url = "http://127.0.0.1:3002".to_string();
+
+    let client = make_test_client(&app_state, &one);
+    client
+        .deploy_api_key("some-secret".to_string(), api_url.clone())
+        .await
+        .unwrap();
+
+    let delete_api_key_info = DeleteApiKeyInfo {
+        api_url: api_url.clone(),
+        secret_name: None,
+        timestamp: get_current_timestamp().unwrap(),
+        nonce: [1; NONCE_LENGTH],
+    };
+    let send = |associated_data: Vec<u8>| {
+        let (signed_message, _) = EncryptedSignedMessage::new_with_receiver(
+            &one.pair(),
+            serde_json::to_vec(&delete_api_key_info).unwrap(),
+            &app_state.x25519_public_key(),
+            &associated_data,
+        )
+        .unwrap();
+        async move {
+            reqwest::Client::new()
+                .post(format!("http://127.0.0.1:3001{DELETE_SECRET_ROUTE}"))
+                .header("Content-Type", "application/json")
+                .body(serde_json::to_string(&signed_message).unwrap())
+                .send()
+                .await
+                .unwrap()
+        }
+    };
+
+    // A message made for a different route
+    let response = send(app_state.associated_data(DEPLOY_API_KEY_ROUTE)).await;
+    assert_eq!(response.status(), 401);
+
+    // A message made for a different server
+    let response = send(associated_data(
+        DELETE_SECRET_ROUTE,
+        &Keyring::Two.pair().public().0,
+    ))
+    .await;
+    assert_eq!(response.status(), 401);
+    let error_response: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error_response.code, ErrorCode::Unauthenticated);
+
+    let api_key_id = default_api_key_id(&one, &Url::parse(&api_url).unwrap());
+    assert!(
+        app_state
+            .read_from_api_keys(&api_key_id)
+            .await
+            .unwrap()
+            .is_some()
+    );
+
+    // The correct associated data is accepted
+    let response = send(app_state.associated_data(DELETE_SECRET_ROUTE)).await;
+    assert_eq!(response.status(), 200);
+    assert!(
+        app_state
+            .read_from_api_keys(&api_key_id)
+            .await
+            .unwrap()
+            .is_none()
+    );
+}
+
+#[test]
+fn test_service_matching() {
+    let service = |url: &str| service_from_url(&Url::parse(url).unwrap()).unwrap();
+    let url = |url: &str| Url::parse(url).unwrap();
+
+    assert_eq!(
+        service("https://api.example.com"),
+        "https://api.example.com/"
+    );
+    assert_eq!(
+        service("https://api.example.com:443/v2/"),
+        "https://api.example.com/v2/"
+    );
+    assert_eq!(
+        service("http://api.example.com:8080/v2"),
+        "http://api.example.com:8080/v2"
+    );
+    assert!(service_from_url(&url("ftp://api.example.com")).is_err());
+
+    let root = service("https://api.example.com");
+    assert!(service_matches(
+        &root,
+        &url("https://api.example.com/anything")
+    ));
+    assert!(!service_matches(
+        &root,
+        &url("http://api.example.com/anything")
+    ));
+    assert!(!service_matches(
+        &root,
+        &url("https://api.example.com:8080/anything")
+    ));
+    assert!(!service_matches(
+        &root,
+        &url("https://other.example.com/anything")
+    ));
+
+    let v2 = service("https://api.example.com/v2");
+    assert!(service_matches(&v2, &url("https://api.example.com/v2")));
+    assert!(service_matches(
+        &v2,
+        &url("https://api.example.com/v2/users")
+    ));
+    assert!(!service_matches(
+        &v2,
+        &url("https://api.example.com/v2beta")
+    ));
+    assert!(!service_matches(&v2, &url("https://api.example.com/v1")));
+
+    let services = [root.as_str(), v2.as_str()];
+    let longest = |request_url: &str| longest_matching_service(services, &url(request_url));
+    assert_eq!(
+        longest("https://api.example.com/v2/users"),
+        Some(v2.as_str())
+    );
+    assert_eq!(
+        longest("https://api.example.com/v1/users"),
+        Some(root.as_str())
+    );
+    assert_eq!(longest("http://api.example.com/v2/users"), None);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_make_request_with_named_secrets() {
+    let app_state = setup_client().await;
+    let one = Keyring::One;
+    let client = make_test_client(&app_state, &one);
+    let service = "http://127.0.0.1:3002".to_string();
+
+    client
+        .deploy_named_api_key(
+            "key_id".to_string(),
+            "some-secret".to_string(),
+            service.clone(),
+        )
+        .await
+        .unwrap();
+    client
+        .deploy_named_api_key(
+            "key_secret".to_string(),
+            "other-secret".to_string(),
+            service.clone(),
+        )
+        .await
+        .unwrap();
+
+    let mut request = reqwest::Request::new(
+        Method::POST,
+        Url::parse("http://127.0.0.1:3002/protected").unwrap(),
+    );
+    *request.body_mut() = Some(Body::wrap(api_key_placeholder("key_secret")));
+    let response = client
+        .make_request(
+            request,
+            vec![("api-key".to_string(), api_key_placeholder("key_id"))],
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(
+        &response.text(),
+        "Succcess response - input was other-secret"
+    );
+
+    // Deleting without a secret name deletes all secrets for the service
+    client.delete_api_key(service).await.unwrap();
+    assert!(
+        app_state
+            .api_keys
+            .list(&one.pair().public().0)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+#[serial]
+async fn test_make_request_with_all_methods() {
+    let app_state = setup_client().await;
+    let one = Keyring::One;
+    let client = make_test_client(&app_state, &one);
+    client
+        .deploy_api_key(
+            "some-secret".to_string(),
+            "http://127.0.0.1:3002".to_string(),
+        )
+        .await
+        .unwrap();
+
+    for (method, expected_response) in [
+        (Method::GET, "GET "),
+        (Method::POST, "POST test"),
+        (Method::PUT, "PUT test"),
+        (Method::PATCH, "PATCH test"),
+        (Method::DELETE, "DELETE test"),
+        (Method::OPTIONS, "OPTIONS test"),
+        (Method::HEAD, ""),
+    ] {
+        let mut request = reqwest::Request::new(
+            method,
+            Url::parse("http://127.0.0.1:3002/echo-method").unwrap(),
+        );
+        *request.body_mut() = Some(Body::wrap("test".to_string()));
+        let response = client
+            .make_request(
+                request,
+                vec![("api-key".to_string(), "xxxREPLACE_MExxx".to_string())],
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status, 200);
+        assert_eq!(&response.text(), expected_response);
+    }
+
+    let request = reqwest::Request::new(
+        Method::TRACE,
+        Url::parse("http://127.0.0.1:3002/echo-method").unwrap(),
+    );
+    assert!(client.make_request(request, vec![]).await.is_err());
+}
+
+#[test]
+fn test_substitutions() {
+    let substitutions = Substitutions::new([
+        (DEFAULT_SECRET_NAME, "secret-0"),
+        ("key", "secret-1"),
+        ("key_id", "xxxREPLACE_ME:keyxxx"),
+    ]);
+
+    assert_eq!(
+        substitutions.apply("a=xxxREPLACE_MExxx&b=xxxREPLACE_ME:defaultxxx"),
+        "a=secret-0&b=secret-0"
+    );
+    assert_eq!(
+        substitutions.apply("xxxREPLACE_ME:keyxxx xxxREPLACE_ME:key_idxxx"),
+        "secret-1 xxxREPLACE_ME:keyxxx"
+    );
+    assert_eq!(
+        substitutions.apply("xxxREPLACE_ME:unknownxxx"),
+        "xxxREPLACE_ME:unknownxxx"
+    );
+}
+
+#[tokio::test]
+#[serial]
+async fn test_make_request_with_key_in_json_body() {
+    let app_state = setup_client().await;
+    let one = Keyring::One;
+    let client = make_test_client(&app_state, &one);
+    client
+        .deploy_api_key(
+            VALID_API_KEY_WITH_SPECIAL_CHARACTERS.to_string(),
+            "http://127.0.0.1:3002".to_string(),
+        )
+        .await
+        .unwrap();
+
+    let mut request = reqwest::Request::new(
+        Method::POST,
+        Url::parse("http://127.0.0.1:3002/json-body-auth").unwrap(),
+    );
+    *request.body_mut() = Some(Body::wrap(
+        r#"{"jsonrpc":"2.0","api_key":"xxxREPLACE_MExxx"}"#.to_string(),
+    ));
+    let response = client
+        .make_request(
+            request,
+            vec![("Content-Type".to_string(), "application/json".to_string())],
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status, 200);
+    assert_eq!(&response.text(), "Success response");
+}
+
+#[tokio::test]
+#[serial]
+async fn test_make_request_with_key_in_form_body() {
+    let app_state = setup_client().await;
+    let one = Keyring::One;
+    let client = make_test_client(&app_state, &one);
+    client
+        .deploy_api_key(
+            VALID_API_KEY_WITH_SPECIAL_CHARACTERS.to_string(),
+            "http://127.0.0.1:3002".to_string(),
+        )
+        .await
+        .unwrap();
+
+    let mut request = reqwest::Request::new(
+        Method::POST,
+        Url::parse("http://127.0.0.1:3002/form-body-auth").unwrap(),
+    );
+    *request.body_mut() = Some(Body::wrap(
+        "grant_type=client_credentials&client_secret=xxxREPLACE_MExxx".to_string(),
+    ));
+    let response = client
+        .make_request(
+            request,
+            vec![(
+                "Content-Type".to_string(),
+                "application/x-www-form-urlencoded".to_string(),
+            )],
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status, 200);
+    assert_eq!(&response.text(), "Success response");
+}
+
+#[test]
+fn test_substitution_encodings() {
+    let substitutions = Substitutions::new([(DEFAULT_SECRET_NAME, "a \"b\"&c=d+e")]);
+    let input = "xxxREPLACE_MExxx";
+
+    assert_eq!(
+        Encoding::from_content_type(Some("application/json; charset=utf-8")),
+        Encoding::Json
+    );
+    assert_eq!(
+        Encoding::from_content_type(Some("application/vnd.api+json")),
+        Encoding::Json
+    );
+    assert_eq!(
+        Encoding::from_content_type(Some("application/x-www-form-urlencoded")),
+        Encoding::FormUrlEncoded
+    );
+    assert_eq!(
+        Encoding::from_content_type(Some("text/plain")),
+        Encoding::Raw
+    );
+    assert_eq!(Encoding::from_content_type(None), Encoding::Raw);
+
+    assert_eq!(substitutions.apply(input), "a \"b\"&c=d+e");
+    assert_eq!(
+        substitutions.apply_encoded(input, Encoding::Json),
+        r#"a \"b\"&c=d+e"#
+    );
+    assert_eq!(
+        substitutions.apply_encoded(input, Encoding::FormUrlEncoded),
+        "a+%22b%22%26c%3Dd%2Be"
+    );
+}
+
+/// An api key entry with only an active version, deployed now
+fn api_key_entry(api_key: String) -> ApiKeyEntry {
+    ApiKeyEntry::new(ApiKeyVersion {
+        version: 1,
+        api_key: api_key.into(),
+        deployed_at: get_current_timestamp().unwrap(),
+        expires_at: None,
+    })
+}
+
+/// The ID of the default secret of the given account for the service of the given URL
+fn default_api_key_id(keyring: &Keyring, url: &Url) -> ApiKeyId {
+    ApiKeyId {
+        account_id: keyring.pair().public().0,
+        service: service_from_url(url).unwrap(),
+        secret_name: DEFAULT_SECRET_NAME.to_string(),
+    }
+}
+
+// TODO: negative test for deploy key and make request
+// TODO: test post
+#[tokio::test]
+async fn test_stale_check() {
+    let result = check_stale(1, 1).await;
+    assert!(result.is_ok());
+
+    let result_server_larger = check_stale(1, 2).await;
+    assert!(result_server_larger.is_ok());
+
+    let result_user_larger = check_stale(2, 1).await;
+    assert!(result_user_larger.is_ok());
+
+    let fail_stale = check_stale(1, 2 + TIME_BUFFER).await.unwrap_err();
+    assert_eq!(fail_stale.to_string(), "Message is too old".to_string());
+}
+
+// Allowing dead code here because we probably will use this for negative tests
+#[allow(dead_code)]
+pub async fn submit_transaction_request(
+    box_url_and_key: (String, [u8; 32]),
+    signature_request: Vec<u8>,
+    keyring: Keyring,
+) -> std::result::Result<reqwest::Response, reqwest::Error> {
+    let mock_client = reqwest::Client::new();
+    let signed_message =
+        EncryptedSignedMessage::new(&keyring.pair(), signature_request, &box_url_and_key.1, &[])
+            .unwrap();
+
+    mock_client
+        .post(box_url_and_key.0.clone())
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_string(&signed_message).unwrap())
+        .send()
+        .await
+}
+
+#[tokio::test]
+#[serial]
+async fn test_requests_to_non_public_addresses_are_refused() {
+    let app_state = setup_client().await;
+    let one = Keyring::One;
+    let client = make_test_client(&app_state, &one);
+
+    for api_url in [
+        // The service itself
+        "http://127.0.0.1:3001",
+        // Cloud metadata service
+        "http://169.254.169.254/latest/meta-data",
+        "http://10.0.0.1",
+        "http://[::1]:3002",
+        // IPv4 mapped loopback
+        "http://[::ffff:127.0.0.1]:3001",
+        // Resolves to loopback
+        "http://localhost:3001",
+    ] {
+        client
+            .deploy_api_key("some-secret".to_string(), api_url.to_string())
+            .await
+            .unwrap();
+        let request = reqwest::Request::new(Method::GET, Url::parse(api_url).unwrap());
+        let error = client.make_request(request, vec![]).await.unwrap_err();
+        assert!(
+            matches!(&error, ClientError::EgressDenied(_)),
+            "{api_url} was not refused: {error:?}"
+        );
+        assert!(!error.is_retryable());
+    }
+}
+
+#[test]
+fn test_egress_policy() {
+    let public = [
+        "1.1.1.1",
+        "93.184.216.34",
+        "2606:4700:4700::1111",
+        "2002:101:101::1",
+    ];
+    let not_public = [
+        "127.0.0.1",
+        "10.1.2.3",
+        "172.16.0.1",
+        "192.168.1.1",
+        "169.254.169.254",
+        "0.0.0.0",
+        "100.64.0.1",
+        "255.255.255.255",
+        "::1",
+        "::",
+        "fd00::1",
+        "fe80::1",
+        "::ffff:10.0.0.1",
+        // IPv4 compatible
+        "::10.0.0.1",
+        "::127.0.0.1",
+        // 6to4
+        "2002:a00:1::1",
+        "2002:7f00:1::1",
+        "2002:a9fe:a9fe::1",
+        "64:ff9b::a00:1",
+        // IPv4 translated
+        "::ffff:0:a00:1",
+        // Teredo
+        "2001:0:4136:e378:8000:63bf:f5ff:fffe",
+        "2001:2::1",
+        "2001:db8::1",
+        "3fff::1",
+        "100::1",
+        "5f00::1",
+        "fec0::1",
+        "ff02::1",
+    ];
+    for ip in public {
+        assert!(is_public(&ip.parse().unwrap()), "{ip}");
+    }
+    for ip in not_public {
+        assert!(!is_public(&ip.parse().unwrap()), "{ip}");
+    }
+
+    let policy = EgressPolicy {
+        allowed: vec![
+            "127.0.0.1:3002".parse().unwrap(),
+            "10.0.0.1".parse().unwrap(),
+        ],
+    };
+    assert!(policy.allows(&"1.1.1.1:443".parse().unwrap()));
+    assert!(policy.allows(&"127.0.0.1:3002".parse().unwrap()));
+    assert!(!policy.allows(&"127.0.0.1:3001".parse().unwrap()));
+    assert!(policy.allows(&"10.0.0.1:80".parse().unwrap()));
+    assert!(policy.allows(&"10.0.0.1:8080".parse().unwrap()));
+    assert!(!policy.allows(&"10.0.0.2:80".parse().unwrap()));
+    assert!("not-an-address".parse::<AllowedDestination>().is_err());
+}
+
+#[tokio::test]
+#[serial]
+async fn test_redirects() {
+    let app_state = setup_client().await;
+    let one = Keyring::One;
+    let client = make_test_client(&app_state, &one);
+
+    client
+        .deploy_api_key(
+            "some-secret".to_string(),
+            "http://127.0.0.1:3002".to_string(),
+        )
+        .await
+        .unwrap();
+
+    // Redirects within the same origin are followed with the secret
+    let request = reqwest::Request::new(
+        Method::GET,
+        Url::parse("http://127.0.0.1:3002/moved?api-key=xxxREPLACE_MExxx").unwrap(),
+    );
+    let response = client.make_request(request, vec![]).await.unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.text(), "Success response");
+    assert_eq!(
+        response.url,
+        format!("http://127.0.0.1:3002/protected?api-key={REDACTED_SECRET}")
+    );
+    assert_eq!(
+        response.redirects,
+        vec![format!(
+            "http://127.0.0.1:3002/moved?api-key={REDACTED_SECRET}"
+        )]
+    );
+
+    // Redirects to another origin are not followed by default
+    let moved_host_url = "http://127.0.0.1:3002/moved-host?api-key=xxxREPLACE_MExxx&page=2";
+    let headers = vec![
+        ("api-key".to_string(), API_KEY_PLACEHOLDER.to_string()),
+        ("accept".to_string(), "application/json".to_string()),
+    ];
+    let request = reqwest::Request::new(Method::GET, Url::parse(moved_host_url).unwrap());
+    let response = client.make_request(request, headers.clone()).await.unwrap();
+    assert_eq!(response.status, 307);
+    assert!(response.redirects.is_empty());
+
+    // When following them, headers and query parameters containing the secret are removed
+    let request = reqwest::Request::new(Method::GET, Url::parse(moved_host_url).unwrap());
+    let options = MakeRequestOptions {
+        follow_redirects: true,
+        ..Default::default()
+    };
+    let response = client
+        .make_request_with_options(request, headers, options)
+        .await
+        .unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.url, format!("{OTHER_ORIGIN}/echo-request?page=2"));
+    assert_eq!(response.redirects.len(), 1);
+    let echoed: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+    assert_eq!(echoed["uri"], "/echo-request?page=2");
+    assert!(echoed["headers"].get("api-key").is_none());
+    assert_eq!(echoed["headers"]["accept"], "application/json");
+    assert!(!response.text().contains("some-secret"));
+
+    // Redirects within the same origin which leave the service a secret was deployed for are
+    // treated in the same way
+    client
+        .deploy_api_key(
+            "moved-secret".to_string(),
+            "http://127.0.0.1:3002/moved".to_string(),
+        )
+        .await
+        .unwrap();
+    let moved_url = "http://127.0.0.1:3002/moved?api-key=xxxREPLACE_MExxx";
+    let request = reqwest::Request::new(Method::GET, Url::parse(moved_url).unwrap());
+    let response = client.make_request(request, vec![]).await.unwrap();
+    assert_eq!(response.status, 301);
+    assert!(response.redirects.is_empty());
+
+    let request = reqwest::Request::new(Method::GET, Url::parse(moved_url).unwrap());
+    let options = MakeRequestOptions {
+        follow_redirects: true,
+        ..Default::default()
+    };
+    let response = client
+        .make_request_with_options(request, vec![], options)
+        .await
+        .unwrap();
+    // The secret was removed, so is not accepted
+    assert_eq!(response.status, 401);
+    assert_eq!(response.url, "http://127.0.0.1:3002/protected");
+    assert_eq!(response.redirects.len(), 1);
+}
+
+#[tokio::test]
+#[serial]
+async fn test_secrets_are_redacted_from_responses() {
+    let app_state = setup_client().await;
+    let one = Keyring::One;
+    let client = make_test_client(&app_state, &one);
+
+    let api_url = "http://127.0.0.1:3002".to_string();
+    client
+        .deploy_api_key("some-secret".to_string(), api_url.clone())
+        .await
+        .unwrap();
+    client
+        .deploy_named_api_key(
+            "special".to_string(),
+            VALID_API_KEY_WITH_SPECIAL_CHARACTERS.to_string(),
+            api_url,
+        )
+        .await
+        .unwrap();
+
+    // The test server echoes the query string and headers in the body, and the api-key header in
+    // a response header
+    let request = reqwest::Request::new(
+        Method::GET,
+        Url::parse("http://127.0.0.1:3002/echo-request?api-key=xxxREPLACE_MExxx").unwrap(),
+    );
+    let headers = vec![
+        ("api-key".to_string(), API_KEY_PLACEHOLDER.to_string()),
+        ("x-special".to_string(), api_key_placeholder("special")),
+    ];
+    let response = client.make_request(request, headers).await.unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("x-echoed-api-key"), Some(REDACTED_SECRET));
+    let echoed: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+    assert_eq!(
+        echoed["uri"],
+        format!("/echo-request?api-key={REDACTED_SECRET}")
+    );
+    assert_eq!(echoed["headers"]["api-key"], REDACTED_SECRET);
+    // The secret is JSON-escaped in the response
+    assert_eq!(echoed["headers"]["x-special"], REDACTED_SECRET);
+    assert_eq!(
+        response.url,
+        format!("http://127.0.0.1:3002/echo-request?api-key={REDACTED_SECRET}")
+    );
+}
+
+#[test]
+fn test_redaction() {
+    let secret = VALID_API_KEY_WITH_SPECIAL_CHARACTERS;
+    let substitutions = Substitutions::new([(DEFAULT_SECRET_NAME, secret)]);
+
+    let form_urlencoded: String = url::form_urlencoded::byte_serialize(secret.as_bytes()).collect();
+    let echoed_forms = [
+        secret.to_string(),
+        serde_json::to_string(secret).unwrap(),
+        form_urlencoded.clone(),
+        form_urlencoded.replace('+', "%20"),
+        BASE64_STANDARD.encode(secret),
+        BASE64_URL_SAFE_NO_PAD.encode(secret),
+        // The secret is encoded at different offsets within base64 encoded data
+        BASE64_STANDARD.encode(format!("user:{secret}")),
+        BASE64_STANDARD.encode(format!("a:{secret}")),
+        BASE64_STANDARD.encode(format!("ab:{secret}")),
+    ];
+    for echoed in echoed_forms {
+        let redacted = substitutions.redact(&format!("invalid key: {echoed}!"));
+        assert!(redacted.starts_with("invalid key: "), "{redacted}");
+        assert!(
+            redacted.contains(REDACTED_SECRET),
+            "{echoed} was not redacted"
+        );
+        assert!(!redacted.contains(&echoed));
+        assert!(substitutions.contains_secret(&echoed));
+    }
+
+    assert_eq!(substitutions.redact("nothing secret"), "nothing secret");
+    assert!(!substitutions.contains_secret("nothing secret"));
+}
+
+#[tokio::test]
+#[serial]
+async fn test_injection_rules() {
+    let app_state = setup_client().await;
+    let one = Keyring::One;
+    let client = make_test_client(&app_state, &one);
+    let api_url = "http://127.0.0.1:3002".to_string();
+    let echo_url = "http://127.0.0.1:3002/echo-request";
+
+    let options = |injection| DeployOptions {
+        injection: Some(injection),
+        ..Default::default()
+    };
+    let is_forbidden =
+        |result: Result<_, ClientError>| matches!(result, Err(ClientError::InjectionNotAllowed(_)));
+
+    // Header formats must contain the key
+    let invalid_rule = InjectionRule::Header {
+        name: "Authorization".to_string(),
+        format: "Bearer".to_string(),
+    };
+    assert!(matches!(
+        client
+            .deploy_with_options(
+                "some-secret".to_string(),
+                api_url.clone(),
+                options(invalid_rule)
+            )
+            .await,
+        Err(ClientError::InvalidInjectionRule(_))
+    ));
+
+    let header_rule = InjectionRule::Header {
+        name: "Authorization".to_string(),
+        format: format!("Bearer {INJECTION_FORMAT_KEY}"),
+    };
+    client
+        .deploy_with_options(
+            "some-secret".to_string(),
+            api_url.clone(),
+            options(header_rule.clone()),
+        )
+        .await
+        .unwrap();
+    assert_eq!(client.list().await.unwrap()[0].injection, Some(header_rule));
+
+    // The key may be placed in the header in the given format
+    let request = reqwest::Request::new(Method::GET, Url::parse(echo_url).unwrap());
+    let headers = vec![(
+        "authorization".to_string(),
+        format!("Bearer {API_KEY_PLACEHOLDER}"),
+    )];
+    let response = client.make_request(request, headers).await.unwrap();
+    assert_eq!(response.status, 200);
+
+    // But not in another format, or anywhere else
+    let request = reqwest::Request::new(Method::GET, Url::parse(echo_url).unwrap());
+    let headers = vec![(
+        "authorization".to_string(),
+        format!("Token {API_KEY_PLACEHOLDER}"),
+    )];
+    assert!(is_forbidden(client.make_request(request, headers).await));
+
+    let request = reqwest::Request::new(
+        Method::GET,
+        Url::parse(&format!("{echo_url}?api_key={API_KEY_PLACEHOLDER}")).unwrap(),
+    );
+    let headers = vec![(
+        "authorization".to_string(),
+        format!("Bearer {API_KEY_PLACEHOLDER}"),
+    )];
+    assert!(is_forbidden(client.make_request(request, headers).await));
+
+    // Named secrets may be restricted to a query parameter
+    let query_rule = InjectionRule::QueryParameter {
+        name: "api_key".to_string(),
+    };
+    let named_options = DeployOptions {
+        secret_name: Some("other".to_string()),
+        injection: Some(query_rule),
+        ..Default::default()
+    };
+    client
+        .deploy_with_options("other-secret".to_string(), api_url.clone(), named_options)
+        .await
+        .unwrap();
+    let other_placeholder = api_key_placeholder("other");
+
+    let request = reqwest::Request::new(
+        Method::GET,
+        Url::parse(&format!("{echo_url}?page=2&api_key={other_placeholder}")).unwrap(),
+    );
+    let response = client.make_request(request, vec![]).await.unwrap();
+    assert_eq!(response.status, 200);
+
+    let request = reqwest::Request::new(
+        Method::GET,
+        Url::parse(&format!("{echo_url}?key={other_placeholder}")).unwrap(),
+    );
+    assert!(is_forbidden(client.make_request(request, vec![]).await));
+
+    let mut request = reqwest::Request::new(Method::POST, Url::parse(echo_url).unwrap());
+    *request.body_mut() = Some(Body::from(format!("{{\"key\": \"{other_placeholder}\"}}")));
+    assert!(is_forbidden(client.make_request(request, vec![]).await));
+}
+
+#[test]
+fn test_check_placeholders_allowed() {
+    let substitutions = Substitutions::new([(DEFAULT_SECRET_NAME, "some-secret")]);
+    let check = |rule: &InjectionRule, api_url: &str, http_headers: &[(String, String)], body| {
+        check_placeholders_allowed(
+            DEFAULT_SECRET_NAME,
+            rule,
+            &substitutions,
+            api_url,
+            http_headers,
+            body,
+        )
+        .is_ok()
+    };
+    let default_placeholder = api_key_placeholder(DEFAULT_SECRET_NAME);
+
+    let header_rule = InjectionRule::Header {
+        name: "X-Api-Key".to_string(),
+        format: INJECTION_FORMAT_KEY.to_string(),
+    };
+    let headers = |value: &str| vec![("x-api-key".to_string(), value.to_string())];
+    assert!(check(&header_rule, "https://example.com", &[], ""));
+    assert!(check(
+        &header_rule,
+        "https://example.com",
+        &headers(API_KEY_PLACEHOLDER),
+        ""
+    ));
+    assert!(check(
+        &header_rule,
+        "https://example.com",
+        &headers(&default_placeholder),
+        ""
+    ));
+    assert!(!check(
+        &header_rule,
+        "https://example.com",
+        &headers(&format!("{API_KEY_PLACEHOLDER}{API_KEY_PLACEHOLDER}")),
+        ""
+    ));
+    assert!(!check(
+        &header_rule,
+        "https://example.com",
+        &[(API_KEY_PLACEHOLDER.to_string(), "x".to_string())],
+        ""
+    ));
+    assert!(!check(
+        &header_rule,
+        "https://example.com",
+        &[],
+        API_KEY_PLACEHOLDER
+    ));
+
+    let query_rule = InjectionRule::QueryParameter {
+        name: "key".to_string(),
+    };
+    let url = |query: &str| format!("https://example.com/path?{query}");
+    assert!(check(
+        &query_rule,
+        &url(&format!("a=1&key={API_KEY_PLACEHOLDER}")),
+        &[],
+        ""
+    ));
+    assert!(!check(
+        &query_rule,
+        &url(&format!("key=a{API_KEY_PLACEHOLDER}")),
+        &[],
+        ""
+    ));
+    assert!(!check(
+        &query_rule,
+        &url(&format!("other={API_KEY_PLACEHOLDER}")),
+        &[],
+        ""
+    ));
+    assert!(!check(
+        &query_rule,
+        &format!("https://example.com/{API_KEY_PLACEHOLDER}?key=1"),
+        &[],
+        ""
+    ));
+    assert!(!check(
+        &query_rule,
+        &format!("{}#key={API_KEY_PLACEHOLDER}", url("a=1")),
+        &[],
+        ""
+    ));
+
+    assert!(check(
+        &InjectionRule::Body,
+        "https://example.com",
+        &[],
+        API_KEY_PLACEHOLDER
+    ));
+    assert!(!check(
+        &InjectionRule::Body,
+        &url(&format!("key={API_KEY_PLACEHOLDER}")),
+        &[],
+        ""
+    ));
+}
+
+#[tokio::test]
+#[serial]
+async fn test_request_policies() {
+    let app_state = setup_client().await;
+    let one = Keyring::One;
+    let client = make_test_client(&app_state, &one);
+    let api_url = "http://127.0.0.1:3002".to_string();
+
+    let options = |policy| DeployOptions {
+        policy: Some(policy),
+        ..Default::default()
+    };
+    let is_policy_violation =
+        |result: Result<_, ClientError>| matches!(result, Err(ClientError::PolicyViolation(_)));
+    let headers = || vec![("api-key".to_string(), API_KEY_PLACEHOLDER.to_string())];
+
+    // Paths must be absolute
+    let invalid_policy = RequestPolicy {
+        paths: vec!["echo-*".to_string()],
+        ..Default::default()
+    };
+    assert!(matches!(
+        client
+            .deploy_with_options(
+                "some-secret".to_string(),
+                api_url.clone(),
+                options(invalid_policy)
+            )
+            .await,
+        Err(ClientError::InvalidRequestPolicy(_))
+    ));
+
+    let policy = RequestPolicy {
+        methods: vec![HttpMethod::Get, HttpMethod::Post],
+        paths: vec!["/echo-*".to_string(), "/protected".to_string()],
+        query_parameters: Some(vec!["page".to_string()]),
+        max_body_size: Some(16),
+    };
+    client
+        .deploy_with_options(
+            "some-secret".to_string(),
+            api_url.clone(),
+            options(policy.clone()),
+        )
+        .await
+        .unwrap();
+    assert_eq!(client.list().await.unwrap()[0].policy, Some(policy));
+
+    let request = |method, url: &str| {
+        reqwest::Request::new(method, Url::parse(&format!("{api_url}{url}")).unwrap())
+    };
+
+    // Requests meeting the policy are made
+    let response = client
+        .make_request(request(Method::GET, "/echo-request?page=2"), headers())
+        .await
+        .unwrap();
+    assert_eq!(response.status, 200);
+    let mut post = request(Method::POST, "/protected");
+    *post.body_mut() = Some(Body::from("small body"));
+    let response = client.make_request(post, headers()).await.unwrap();
+    assert_eq!(response.status, 200);
+
+    // Other methods, paths, query parameters and larger bodies are refused, with an error which says
+    // why
+    let error = client
+        .make_request(request(Method::DELETE, "/protected"), headers())
+        .await
+        .unwrap_err();
+    let ClientError::PolicyViolation(message) = &error else {
+        panic!("Unexpected error: {error:?}");
+    };
+    assert!(message.contains("method DELETE is not allowed"));
+    let refused = [
+        request(Method::GET, "/moved"),
+        request(Method::GET, "/echo-request/../moved"),
+        request(Method::GET, "/echo-request?page=2&other=1"),
+    ];
+    for refused_request in refused {
+        assert!(is_policy_violation(
+            client.make_request(refused_request, headers()).await
+        ));
+    }
+    let mut post = request(Method::POST, "/protected");
+    *post.body_mut() = Some(Body::from("a body which is too large"));
+    assert!(is_policy_violation(
+        client.make_request(post, headers()).await
+    ));
+
+    // Requests which do not use the key are not restricted
+    assert!(
+        client
+            .make_request(request(Method::DELETE, "/echo-method"), vec![])
+            .await
+            .is_ok()
+    );
+
+    // Redirects are not followed, as they could leave the allowed paths
+    let policy = RequestPolicy {
+        paths: vec!["/moved".to_string()],
+        ..Default::default()
+    };
+    client
+        .deploy_with_options("some-secret".to_string(), api_url.clone(), options(policy))
+        .await
+        .unwrap();
+    let response = client
+        .make_request(request(Method::GET, "/moved"), headers())
+        .await
+        .unwrap();
+    assert_eq!(response.status, 301);
+    assert!(response.redirects.is_empty());
+
+    // Redeploying without a policy keeps the existing one
+    client
+        .deploy_api_key("other-secret".to_string(), api_url.clone())
+        .await
+        .unwrap();
+    assert!(is_policy_violation(
+        client
+            .make_request(request(Method::GET, "/echo-request"), headers())
+            .await
+    ));
+
+    // A policy given with a pending version only applies to it until it is promoted
+    let pending_policy = RequestPolicy {
+        paths: vec!["/echo-*".to_string()],
+        ..Default::default()
+    };
+    client
+        .deploy_with_options(
+            "new-secret".to_string(),
+            api_url.clone(),
+            DeployOptions {
+                pending: true,
+                ..options(pending_policy.clone())
+            },
+        )
+        .await
+        .unwrap();
+    let api_keys = client.list().await.unwrap();
+    assert_eq!(api_keys[0].policy.as_ref().unwrap().paths, vec!["/moved"]);
+    assert_eq!(api_keys[0].pending_policy, Some(pending_policy.clone()));
+    assert!(is_policy_violation(
+        client
+            .make_request(request(Method::GET, "/echo-request"), headers())
+            .await
+    ));
+    let response = client
+        .make_request_with_key_version(
+            request(Method::GET, "/echo-request"),
+            headers(),
+            KeyVersion::Pending,
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status, 200);
+    assert!(is_policy_violation(
+        client
+            .make_request_with_key_version(
+                request(Method::GET, "/moved"),
+                headers(),
+                KeyVersion::Pending
+            )
+            .await
+    ));
+
+    client.promote_api_key(None, api_url, 600).await.unwrap();
+    let api_keys = client.list().await.unwrap();
+    assert_eq!(api_keys[0].policy, Some(pending_policy));
+    assert!(api_keys[0].pending_policy.is_none());
+    let response = client
+        .make_request(request(Method::GET, "/echo-request"), headers())
+        .await
+        .unwrap();
+    assert_eq!(response.status, 200);
+}
+
+#[test]
+fn test_glob_matches() {
+    assert!(glob_matches("/v1/images", "/v1/images"));
+    assert!(!glob_matches("/v1/images", "/v1/images/1"));
+    assert!(glob_matches("/v1/images/*", "/v1/images/1"));
+    assert!(glob_matches("/v1/images/*", "/v1/images/"));
+    assert!(!glob_matches("/v1/images/*", "/v1/images/1/delete"));
+    assert!(glob_matches("/v1/*/info", "/v1/images/info"));
+    assert!(glob_matches("/v1/images-*", "/v1/images-large"));
+    assert!(!glob_matches("/v1/*", "/v2/images"));
+    assert!(glob_matches("/v1/**", "/v1/images/1/delete"));
+    assert!(glob_matches("/**/info", "/v1/images/info"));
+    assert!(!glob_matches("/**/info", "/v1/images/info/more"));
+    assert!(glob_matches("**", "/anything/at/all"));
+    assert!(!glob_matches("", "/"));
 }
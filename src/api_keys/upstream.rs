@@ -0,0 +1,242 @@
+//! Sending requests to upstream services, following redirects without sending secrets to other
+//! origins
+use super::{egress::EgressPolicy, service::service_matches, substitution::Substitutions};
+use crate::errors::Err;
+use entropy_api_key_service_shared::{ApiResponse, HttpMethod};
+use reqwest::{
+    Method, StatusCode,
+    header::{
+        AUTHORIZATION, CONTENT_LENGTH, COOKIE, HeaderMap, HeaderName, HeaderValue, LOCATION,
+        PROXY_AUTHORIZATION,
+    },
+};
+use std::time::Duration;
+use url::Url;
+use zeroize::Zeroizing;
+
+/// The most redirects which will be followed for one request
+pub const MAX_REDIRECTS: usize = 10;
+
+/// Headers which carry credentials, so are never sent to another origin
+const CREDENTIAL_HEADERS: [HeaderName; 3] = [AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION];
+
+/// Which redirects to follow
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum FollowRedirects {
+    /// Return redirect responses as they are
+    None,
+    /// Follow redirects within the same origin
+    #[default]
+    SameOrigin,
+    /// Follow redirects within the same origin and to other origins
+    All,
+}
+
+/// Options for requests to upstream services
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UpstreamOptions {
+    /// Timeout for each request, including each redirect followed
+    pub timeout: Option<Duration>,
+    /// Which redirects to follow
+    pub follow_redirects: FollowRedirects,
+}
+
+/// Make an HTTP request, replacing placeholders in its URL, headers and body with secrets. The
+/// request, and each redirect followed, is only made if the egress policy allows its destination.
+///
+/// When following a redirect to another origin, or to a path outside the service which the
+/// secrets were deployed for, headers and query parameters containing secrets are removed. If
+/// secrets would still be sent, such as in the path or body, the redirect is not followed and the
+/// redirect response is returned.
+#[allow(clippy::too_many_arguments)]
+pub async fn send_with_substitutions(
+    egress_policy: &EgressPolicy,
+    options: UpstreamOptions,
+    service: &str,
+    substitutions: &Substitutions<'_>,
+    http_verb: HttpMethod,
+    api_url: &str,
+    http_headers: &[(String, String)],
+    request_body: &str,
+) -> Result<ApiResponse, Err> {
+    // Our copies of the request with secrets substituted are wiped once sent
+    let mut url = Zeroizing::new(substitutions.apply(api_url));
+
+    let content_type = http_headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        .map(|(_, value)| value.as_str());
+
+    let mut headers = HeaderMap::new();
+    // Headers which secrets were substituted into, which are removed on crossing origins
+    let mut secret_headers = Vec::new();
+    for (key, value) in http_headers {
+        let first = Zeroizing::new(substitutions.apply(key));
+        let second = Zeroizing::new(substitutions.apply(value));
+
+        let header_name = HeaderName::from_bytes(first.as_bytes())?;
+        let mut header_value = HeaderValue::from_str(&second)?;
+        header_value.set_sensitive(true);
+        if first.as_str() != key || second.as_str() != value {
+            secret_headers.push(header_name.clone());
+        }
+        headers.insert(header_name, header_value);
+    }
+
+    let mut method =
+        Method::from_bytes(http_verb.as_str().as_bytes()).map_err(|_| Err::UnsupportedHttpVerb)?;
+    let mut body = http_verb
+        .allows_body()
+        .then(|| Zeroizing::new(substitutions.apply_to_body(request_body, content_type)));
+    let body_has_secret = body
+        .as_ref()
+        .is_some_and(|body| body.as_str() != request_body);
+
+    let mut redirects = Vec::new();
+    // The client for the last destination, which is reused while redirects stay on the same host
+    // and port, as its connections are pinned to the address checked for them
+    let mut pinned: Option<(Url, reqwest::Client)> = None;
+    loop {
+        let current = Url::parse(&url)?;
+        let client = match &pinned {
+            Some((pinned_url, client)) if same_destination(pinned_url, &current) => client.clone(),
+            _ => {
+                // The destination is checked after substitution, as secrets may be part of the host
+                let client = egress_policy.client_for(&current, options.timeout).await?;
+                pinned = Some((current.clone(), client.clone()));
+                client
+            }
+        };
+        let mut request = client
+            .request(method.clone(), current.clone())
+            .headers(headers.clone());
+        if let Some(body) = &body {
+            request = request.body(body.to_string());
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|error| upstream_error(error, substitutions))?;
+
+        let Some(mut next) = redirect_location(&response, &current) else {
+            return read_response(response, substitutions, redirects).await;
+        };
+        if options.follow_redirects == FollowRedirects::None || redirects.len() >= MAX_REDIRECTS {
+            return read_response(response, substitutions, redirects).await;
+        }
+
+        // As browsers do, the method of other redirects is changed to GET and the body dropped
+        if matches!(
+            response.status(),
+            StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND | StatusCode::SEE_OTHER
+        ) && method != Method::HEAD
+        {
+            method = Method::GET;
+            body = None;
+        }
+
+        // Leaving the service is treated in the same way as leaving the origin, as secrets may
+        // only be sent to the service they were deployed for
+        if next.origin() != current.origin() || !service_matches(service, &next) {
+            if options.follow_redirects != FollowRedirects::All {
+                return read_response(response, substitutions, redirects).await;
+            }
+            remove_secret_query_parameters(&mut next, substitutions);
+            if substitutions.contains_secret(next.as_str()) || (body.is_some() && body_has_secret) {
+                return read_response(response, substitutions, redirects).await;
+            }
+            for name in secret_headers.iter().chain(&CREDENTIAL_HEADERS) {
+                headers.remove(name);
+            }
+        }
+
+        redirects.push(substitutions.redact(current.as_str()));
+        url = Zeroizing::new(next.into());
+    }
+}
+
+/// Convert an error from sending a request or reading its response. The URL the request was made
+/// to, which may contain secrets, is removed, and any secrets elsewhere in the error are redacted.
+fn upstream_error(error: reqwest::Error, substitutions: &Substitutions<'_>) -> Err {
+    Err::UpstreamRequest(substitutions.redact(&error.without_url().to_string()))
+}
+
+/// Whether requests to two URLs go to the same host and port
+fn same_destination(first: &Url, second: &Url) -> bool {
+    first.host() == second.host() && first.port_or_known_default() == second.port_or_known_default()
+}
+
+/// Get the URL a response redirects to, if it is a redirect to an http or https URL
+fn redirect_location(response: &reqwest::Response, current: &Url) -> Option<Url> {
+    if !matches!(
+        response.status(),
+        StatusCode::MOVED_PERMANENTLY
+            | StatusCode::FOUND
+            | StatusCode::SEE_OTHER
+            | StatusCode::TEMPORARY_REDIRECT
+            | StatusCode::PERMANENT_REDIRECT
+    ) {
+        return None;
+    }
+    let location = response.headers().get(LOCATION)?.to_str().ok()?;
+    let next = current.join(location).ok()?;
+    matches!(next.scheme(), "http" | "https").then_some(next)
+}
+
+/// Remove query parameters whose name or value contains a secret
+fn remove_secret_query_parameters(url: &mut Url, substitutions: &Substitutions<'_>) {
+    let parameters: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+    let kept: Vec<&(String, String)> = parameters
+        .iter()
+        .filter(|(name, value)| {
+            !substitutions.contains_secret(name) && !substitutions.contains_secret(value)
+        })
+        .collect();
+    if kept.len() == parameters.len() {
+        return;
+    }
+    if kept.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(kept);
+    }
+}
+
+/// Read an upstream response, together with the URL it came from and the redirects followed.
+/// Secrets are redacted from it, as upstream services may echo them, for example in error messages.
+async fn read_response(
+    response: reqwest::Response,
+    substitutions: &Substitutions<'_>,
+    redirects: Vec<String>,
+) -> Result<ApiResponse, Err> {
+    let status = response.status().as_u16();
+    let url = substitutions.redact(response.url().as_str());
+    let mut headers: Vec<(String, Vec<u8>)> = response
+        .headers()
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.as_str().to_string(),
+                substitutions.redact_bytes(value.as_bytes()),
+            )
+        })
+        .collect();
+    let body = response
+        .bytes()
+        .await
+        .map_err(|error| upstream_error(error, substitutions))?;
+    let body = substitutions.redact_bytes(&Zeroizing::new(body.to_vec()));
+    // Redaction may have changed the length of the body
+    for (name, value) in &mut headers {
+        if name.as_str() == CONTENT_LENGTH.as_str() {
+            *value = body.len().to_string().into_bytes();
+        }
+    }
+    Ok(ApiResponse {
+        status,
+        headers,
+        body,
+        url,
+        redirects,
+    })
+}
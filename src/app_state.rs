@@ -1,41 +1,103 @@
-use crate::errors::Err;
+use crate::{
+    api_key_store::{
+        ApiKeyEntry, ApiKeyId, ApiKeyStore, ApiKeyStoreType, FileApiKeyStore, InMemoryApiKeyStore,
+    },
+    api_keys::egress::EgressPolicy,
+    errors::Err,
+    identity::ServiceIdentity,
+    migration::PendingMigration,
+    replay_cache::ReplayCache,
+    replication::{ReplicatedApiKeyStore, change_log_path},
+    sealing::{SealedFile, TamperPolicy, get_sealing_key},
+};
+use entropy_api_key_service_shared::associated_data;
 use entropy_client::chain_api::{EntropyConfig, get_api, get_rpc};
+use rand_core::{OsRng, RngCore};
 use serde::Deserialize;
-use sp_core::{Pair, crypto::AccountId32, sr25519};
-use std::{
-    collections::HashMap,
-    sync::{Arc, RwLock},
-};
+use sp_core::{Pair, crypto::AccountId32};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 use subxt::{
     OnlineClient, backend::legacy::LegacyRpcMethods, utils::AccountId32 as SubxtAccountId32,
 };
-use x25519_dalek::StaticSecret;
+use tokio::sync::{Mutex, OnceCell};
+use zeroize::Zeroizing;
 
 /// Application state struct which is cloned and made available to every axum HTTP route handler function
 #[derive(Clone)]
 pub struct AppState {
-    /// Keypair for box id account
-    pub pair: sr25519::Pair,
-    /// Secret encryption key
-    pub x25519_secret: StaticSecret,
+    /// Secret keys of this instance. These are shared rather than cloned into every handler, so
+    /// that only one copy is held in memory
+    pub identity: Arc<ServiceIdentity>,
     /// Configuation containing the chain endpoint
     pub configuration: Configuration,
     /// Storage for api keys
-    pub api_keys: Arc<RwLock<HashMap<([u8; 32], String), String>>>,
+    pub api_keys: Arc<dyn ApiKeyStore>,
+    /// The same storage, with access to the changes which are replicated to other instances
+    pub replica: Arc<ReplicatedApiKeyStore>,
+    /// Recently seen messages, used to reject replayed requests
+    pub replay_cache: Arc<ReplayCache>,
+    /// Held while reading and then writing an api key, so that concurrent changes to its
+    /// versions are not lost
+    pub api_key_update_lock: Arc<Mutex<()>>,
+    /// Key authenticating the nonces given out to new instances, which their migration requests
+    /// must be bound to
+    pub migration_nonce_key: Arc<Zeroizing<[u8; 32]>>,
+    /// Nonces which new instances have used, with the unix time in seconds at which they were
+    /// given out, so that they cannot be used again
+    pub used_migration_nonces: Arc<Mutex<HashMap<[u8; 32], u64>>>,
+    /// Api keys sent to a new instance, which are deleted once it confirms it has them
+    pub pending_migration: Arc<Mutex<Option<PendingMigration>>>,
+    /// Measurement value of the release this instance is running, once it is known
+    pub measurement: Arc<OnceCell<[u8; 32]>>,
 }
 
 impl AppState {
-    /// Setup AppState with given secret keys
-    pub fn new(
+    /// Setup AppState with given secret keys, opening the api key store given in the configuration
+    pub fn new(configuration: Configuration, identity: ServiceIdentity) -> Result<Self, Err> {
+        let account_id = identity.pair.public().0;
+        let replica = match configuration.api_key_store {
+            ApiKeyStoreType::Memory => {
+                ReplicatedApiKeyStore::new(Arc::new(InMemoryApiKeyStore::default()), account_id)
+            }
+            ApiKeyStoreType::File => {
+                let api_keys: Arc<dyn ApiKeyStore> = Arc::new(FileApiKeyStore::open(
+                    SealedFile::new(configuration.storage_path.clone(), get_sealing_key()?),
+                    configuration.tamper_policy,
+                )?);
+                ReplicatedApiKeyStore::open(
+                    api_keys,
+                    account_id,
+                    SealedFile::new(
+                        change_log_path(&configuration.storage_path),
+                        get_sealing_key()?,
+                    ),
+                    configuration.tamper_policy,
+                )?
+            }
+        };
+        Ok(Self::new_with_replica(configuration, identity, replica))
+    }
+
+    /// Setup AppState with given secret keys and api key store
+    pub fn new_with_replica(
         configuration: Configuration,
-        pair: sr25519::Pair,
-        x25519_secret: StaticSecret,
+        identity: ServiceIdentity,
+        replica: ReplicatedApiKeyStore,
     ) -> Self {
+        let replica = Arc::new(replica);
+        let mut migration_nonce_key = Zeroizing::new([0; 32]);
+        OsRng.fill_bytes(migration_nonce_key.as_mut());
         Self {
-            pair,
-            x25519_secret,
+            identity: Arc::new(identity),
             configuration,
-            api_keys: Arc::new(RwLock::new(Default::default())),
+            api_keys: replica.clone(),
+            replica,
+            replay_cache: Default::default(),
+            api_key_update_lock: Default::default(),
+            migration_nonce_key: Arc::new(migration_nonce_key),
+            used_migration_nonces: Default::default(),
+            pending_migration: Default::default(),
+            measurement: Default::default(),
         }
     }
 
@@ -51,66 +113,94 @@ impl AppState {
 
     /// Get the [AccountId32]
     pub fn account_id(&self) -> AccountId32 {
-        AccountId32::new(self.pair.public().0)
+        AccountId32::new(self.identity.pair.public().0)
     }
 
     /// Get the subxt account ID
     pub fn subxt_account_id(&self) -> SubxtAccountId32 {
-        SubxtAccountId32(self.pair.public().0)
+        SubxtAccountId32(self.identity.pair.public().0)
+    }
+
+    /// Get the associated data which requests to, and responses from, the given route of this
+    /// instance are encrypted with
+    pub fn associated_data(&self, route: &str) -> Vec<u8> {
+        associated_data(route, &self.identity.pair.public().0)
     }
 
     /// Get the x25519 public key
     pub fn x25519_public_key(&self) -> [u8; 32] {
-        x25519_dalek::PublicKey::from(&self.x25519_secret).to_bytes()
+        x25519_dalek::PublicKey::from(&self.identity.x25519_secret).to_bytes()
     }
 
     /// Write to api key
-    pub fn write_to_api_keys(&self, key: ([u8; 32], String), value: String) -> Result<(), Err> {
-        self.clear_poisioned_api_keys();
-        let mut api_keys = self
-            .api_keys
-            .write()
-            .map_err(|e| Err::PosionError(e.to_string()))?;
-        api_keys.insert(key, value);
-        Ok(())
+    pub async fn write_to_api_keys(&self, key: ApiKeyId, value: ApiKeyEntry) -> Result<(), Err> {
+        self.api_keys.put(key, value).await
     }
 
     /// Delete from api key
-    pub fn delete_from_api_keys(&self, key: ([u8; 32], String)) -> Result<(), Err> {
-        self.clear_poisioned_api_keys();
-        let mut api_keys = self
-            .api_keys
-            .write()
-            .map_err(|e| Err::PosionError(e.to_string()))?;
-        api_keys.remove(&key);
-        Ok(())
+    pub async fn delete_from_api_keys(&self, key: &ApiKeyId) -> Result<(), Err> {
+        self.api_keys.delete(key).await
     }
 
-    /// Reads from api key will error if no value, call exists_in_request_limit to check
-    pub fn read_from_api_keys(&self, key: &([u8; 32], String)) -> Result<Option<String>, Err> {
-        self.clear_poisioned_api_keys();
-        let api_keys = self
-            .api_keys
-            .read()
-            .map_err(|e| Err::PosionError(e.to_string()))?;
-        Ok(api_keys.get(key).cloned())
+    /// Rejects a request which has already been received, or an account with too many recent
+    /// requests
+    pub async fn check_replay(
+        &self,
+        account_id: &[u8; 32],
+        message: &[u8],
+        timestamp: u64,
+        current_timestamp: u64,
+    ) -> Result<(), Err> {
+        self.replay_cache
+            .check(account_id, message, timestamp, current_timestamp)
+            .await
     }
 
-    /// Clears a poisioned lock from request limit
-    pub fn clear_poisioned_api_keys(&self) {
-        if self.api_keys.is_poisoned() {
-            self.api_keys.clear_poison()
-        }
+    /// Reads from api key, returning `None` if there is no such api key
+    pub async fn read_from_api_keys(&self, key: &ApiKeyId) -> Result<Option<ApiKeyEntry>, Err> {
+        self.api_keys.get(key).await
     }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Configuration {
     pub endpoint: String,
+    /// Which api key store to use
+    pub api_key_store: ApiKeyStoreType,
+    /// Where to persist sealed api keys, when using the file api key store
+    pub storage_path: PathBuf,
+    /// What to do if persisted api keys fail authentication on startup
+    pub tamper_policy: TamperPolicy,
+    /// Measurement values of releases which api keys may be migrated to or from
+    pub migration_measurements: Vec<[u8; 32]>,
+    /// URLs of other instances to replicate api keys with
+    pub replication_peers: Vec<String>,
+    /// Whether to also replicate api keys with the instances registered on chain
+    pub replicate_with_chain_peers: bool,
+    /// Which destinations requests made with api keys may be sent to
+    pub egress_policy: EgressPolicy,
 }
 
 impl Configuration {
+    /// Whether api keys are replicated with other instances
+    pub fn replicates(&self) -> bool {
+        !self.replication_peers.is_empty() || self.replicate_with_chain_peers
+    }
+
+    /// Configuration which holds api keys in memory only, such as for tests
     pub fn new(endpoint: String) -> Configuration {
-        Configuration { endpoint }
+        Configuration {
+            endpoint,
+            api_key_store: ApiKeyStoreType::Memory,
+            storage_path: DEFAULT_STORAGE_PATH.into(),
+            tamper_policy: Default::default(),
+            migration_measurements: Vec::new(),
+            replication_peers: Vec::new(),
+            replicate_with_chain_peers: false,
+            egress_policy: Default::default(),
+        }
     }
 }
+
+/// Default location of the sealed api key file
+pub const DEFAULT_STORAGE_PATH: &str = "api-keys.sealed";
@@ -0,0 +1,105 @@
+//! Quotes which bind the identity of an instance of the service, so that instances can check that
+//! they only send api keys to each other when running an accepted release
+use crate::{app_state::AppState, errors::Err};
+use entropy_client::attestation::create_quote;
+use entropy_shared::attestation::{
+    QuoteContext, QuoteInputData, compute_quote_measurement, verify_pck_certificate_chain,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sp_core::Pair;
+use subxt::utils::AccountId32 as SubxtAccountId32;
+use tdx_quote::Quote;
+
+/// Request for the attested identity of an instance
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IdentityRequest {
+    /// Nonce which the quote must be bound to
+    pub nonce: [u8; 32],
+}
+
+/// The identity of an instance, with a quote binding it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AttestedIdentity {
+    pub account_id: [u8; 32],
+    pub x25519_public_key: [u8; 32],
+    pub tdx_quote: Vec<u8>,
+}
+
+/// Get a quote nonce for a message made at the given unix time in seconds, so that a quote cannot
+/// be reused once the message is stale. The context separates nonces used for different purposes.
+pub fn timestamp_nonce(context: &[u8], timestamp: u64) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(context)
+        .chain_update(timestamp.to_be_bytes())
+        .finalize()
+        .into()
+}
+
+/// Make a quote binding the account ID and x25519 public key of this instance with the given nonce
+pub async fn create_identity_quote(app_state: &AppState, nonce: [u8; 32]) -> Result<Vec<u8>, Err> {
+    create_quote(
+        nonce,
+        app_state.subxt_account_id(),
+        &app_state.x25519_public_key(),
+        QuoteContext::Validate,
+    )
+    .await
+    .map_err(|error| Err::QuoteGeneration(format!("{error:?}")))
+}
+
+/// Get the identity of this instance, with a quote bound to the given nonce
+pub async fn attested_identity(
+    app_state: &AppState,
+    nonce: [u8; 32],
+) -> Result<AttestedIdentity, Err> {
+    Ok(AttestedIdentity {
+        account_id: app_state.identity.pair.public().0,
+        x25519_public_key: app_state.x25519_public_key(),
+        tdx_quote: create_identity_quote(app_state, nonce).await?,
+    })
+}
+
+/// Get the measurement value of the release which made a quote
+pub fn quote_measurement(tdx_quote: &[u8]) -> Result<[u8; 32], Err> {
+    let quote = Quote::from_bytes(tdx_quote)
+        .map_err(|error| Err::QuoteRejected(format!("Cannot parse quote: {error:?}")))?;
+    Ok(compute_quote_measurement(&quote))
+}
+
+/// Check that a quote binds the given account ID, x25519 public key and nonce, and that it was made
+/// by a release with one of the given measurement values
+pub fn verify_identity_quote(
+    tdx_quote: &[u8],
+    account_id: [u8; 32],
+    x25519_public_key: [u8; 32],
+    nonce: [u8; 32],
+    accepted_measurements: &[[u8; 32]],
+) -> Result<(), Err> {
+    let quote = Quote::from_bytes(tdx_quote)
+        .map_err(|error| Err::QuoteRejected(format!("Cannot parse quote: {error:?}")))?;
+
+    let expected_input_data = QuoteInputData::new(
+        SubxtAccountId32(account_id),
+        x25519_public_key,
+        nonce,
+        QuoteContext::Validate,
+    );
+    if quote.report_input_data() != expected_input_data.0 {
+        return Err(Err::QuoteRejected(
+            "Quote input data does not match the given identity".to_string(),
+        ));
+    }
+
+    let measurement = compute_quote_measurement(&quote);
+    if !accepted_measurements.contains(&measurement) {
+        return Err(Err::QuoteRejected(format!(
+            "Measurement value {} is not accepted",
+            hex::encode(measurement)
+        )));
+    }
+
+    verify_pck_certificate_chain(&quote)
+        .map_err(|error| Err::QuoteRejected(format!("Invalid PCK certificate chain: {error:?}")))?;
+    Ok(())
+}
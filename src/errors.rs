@@ -1,9 +1,11 @@
 use thiserror::Error;
 
 use axum::{
+    Json,
     http::StatusCode,
     response::{IntoResponse, Response},
 };
+use entropy_api_key_service_shared::{ErrorCode, ErrorResponse, InjectionRule};
 use entropy_protocol::sign_and_encrypt::EncryptedSignedMessageErr;
 
 #[derive(Debug, Error)]
@@ -14,8 +16,6 @@ pub enum Err {
     EncryptionOrAuthentication(#[from] EncryptedSignedMessageErr),
     #[error("JSON: {0}")]
     Json(#[from] serde_json::Error),
-    #[error("Posion Mutex error: {0}")]
-    PosionError(String),
     #[error("Message is too old")]
     StaleMessage,
     #[error("Error getting block hash")]
@@ -40,7 +40,6 @@ pub enum Err {
     TryFromSlice(#[from] std::array::TryFromSliceError),
     #[error("Substrate: {0}")]
     SubstrateClient(#[from] entropy_client::substrate::SubstrateError),
-    #[cfg(feature = "production")]
     #[error("Quote generation: {0}")]
     QuoteGeneration(String),
     #[error("Cannot encode verifying key: {0}")]
@@ -55,8 +54,52 @@ pub enum Err {
     UrlParse(#[from] url::ParseError),
     #[error("Unable to get hostname from given URL")]
     UrlHost,
+    #[error("Only http and https URLs are supported")]
+    UnsupportedUrlScheme,
+    #[error("Destination is not allowed: {0}")]
+    EgressDenied(String),
     #[error("No api key for user url")]
     UrlEmpty,
+    #[error("Api key for user url has expired")]
+    ApiKeyExpired,
+    #[error("Api key expiry must be in the future")]
+    ExpiryInPast,
+    #[error("No api key with the given service and secret name")]
+    NoSuchApiKey,
+    #[error("No pending version of the api key to promote")]
+    NoPendingVersion,
+    #[error("No previous version of the api key within its grace period")]
+    NoPreviousVersion,
+    #[error("Invalid rotation recipe: {0}")]
+    InvalidRotationRecipe(String),
+    #[error("Invalid injection rule: {0}")]
+    InvalidInjectionRule(String),
+    #[error("Secret {secret_name} may only be placed {rule}")]
+    InjectionNotAllowed {
+        secret_name: String,
+        rule: InjectionRule,
+    },
+    #[error("Invalid request policy: {0}")]
+    InvalidRequestPolicy(String),
+    #[error("Request violates the policy of secret {secret_name}: {reason}")]
+    PolicyViolation { secret_name: String, reason: String },
+    #[error("Invalid backup: {0}")]
+    InvalidBackup(String),
+    #[error("Api key rotation failed: {0}")]
+    RotationFailed(String),
+    #[error("Upstream request: {0}")]
+    UpstreamRequest(String),
+    #[error("Quote rejected: {0}")]
+    QuoteRejected(String),
+    #[error("Migration rejected: {0}")]
+    MigrationRejected(String),
+    #[error("Migration failed: {0}")]
+    MigrationFailed(String),
+    #[error(
+        "Secret names must be 1 to {max} ASCII letters, digits, '_' or '-'",
+        max = entropy_api_key_service_shared::MAX_SECRET_NAME_LENGTH
+    )]
+    InvalidSecretName,
     #[cfg(feature = "production")]
     #[error("Quote parse: {0}")]
     QuoteParse(#[from] tdx_quote::QuoteParseError),
@@ -66,12 +109,127 @@ pub enum Err {
     InvalidHeaderValue(#[from] reqwest::header::InvalidHeaderValue),
     #[error("subxt rpc error: {0}")]
     SubxtRpcError(#[from] subxt::ext::subxt_rpcs::Error),
+    #[error("Sealing: {0}")]
+    Sealing(String),
+    #[error("Sealed data failed authentication - it may have been tampered with")]
+    SealedDataTampered,
+    #[error("IO: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Blocking task failed: {0}")]
+    BlockingTask(#[from] tokio::task::JoinError),
+    #[error("Message has already been received")]
+    ReplayedMessage,
+    #[error("Too many recent messages from this account")]
+    TooManyRecentMessages,
+    #[error("Request did not include a key to encrypt the response to")]
+    NoResponseKey,
+}
+
+impl Err {
+    /// Machine readable code identifying the kind of error
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Err::Json(_) | Err::UnknownContext => ErrorCode::BadRequest,
+            Err::UrlParse(_) => ErrorCode::InvalidUrl,
+            Err::UrlHost => ErrorCode::UrlHasNoHost,
+            Err::UnsupportedUrlScheme => ErrorCode::UnsupportedUrlScheme,
+            Err::UnsupportedHttpVerb => ErrorCode::UnsupportedHttpMethod,
+            Err::InvalidHeaderName(_) | Err::InvalidHeaderValue(_) => ErrorCode::InvalidHeader,
+            Err::InvalidSecretName => ErrorCode::InvalidSecretName,
+            Err::ExpiryInPast => ErrorCode::ExpiryInPast,
+            Err::InvalidRotationRecipe(_) => ErrorCode::InvalidRotationRecipe,
+            Err::InvalidInjectionRule(_) => ErrorCode::InvalidInjectionRule,
+            Err::InvalidRequestPolicy(_) => ErrorCode::InvalidRequestPolicy,
+            Err::InvalidBackup(_) => ErrorCode::InvalidBackup,
+            Err::NoResponseKey => ErrorCode::NoResponseKey,
+            Err::EncryptionOrAuthentication(_) => ErrorCode::Unauthenticated,
+            Err::QuoteRejected(_) => ErrorCode::QuoteRejected,
+            Err::MigrationRejected(_) => ErrorCode::MigrationRejected,
+            Err::StaleMessage => ErrorCode::Stale,
+            Err::EgressDenied(_) => ErrorCode::EgressDenied,
+            Err::InjectionNotAllowed { .. } => ErrorCode::InjectionNotAllowed,
+            Err::PolicyViolation { .. } => ErrorCode::PolicyViolation,
+            Err::UrlEmpty => ErrorCode::NoKeyForService,
+            Err::NoSuchApiKey => ErrorCode::NoSuchApiKey,
+            Err::ApiKeyExpired => ErrorCode::ApiKeyExpired,
+            Err::NoPendingVersion => ErrorCode::NoPendingVersion,
+            Err::NoPreviousVersion => ErrorCode::NoPreviousVersion,
+            Err::ReplayedMessage => ErrorCode::Replayed,
+            Err::TooManyRecentMessages => ErrorCode::RateLimited,
+            Err::HttpRequest(_) | Err::UpstreamRequest(_) => ErrorCode::Upstream,
+            Err::RotationFailed(_) => ErrorCode::RotationFailed,
+            Err::BlockHash
+            | Err::Subxt(_)
+            | Err::NoEvent
+            | Err::BadEvent(_)
+            | Err::TimedOut
+            | Err::Client(_)
+            | Err::SubstrateClient(_)
+            | Err::AttestationRequest(_)
+            | Err::SubxtRpcError(_) => ErrorCode::Unavailable,
+            Err::Mnemonic(_)
+            | Err::SystemTime(_)
+            | Err::TryFromSlice(_)
+            | Err::EncodeVerifyingKey(_)
+            | Err::BadVerifyingKeyLength
+            | Err::Sealing(_)
+            | Err::SealedDataTampered
+            | Err::MigrationFailed(_)
+            | Err::QuoteGeneration(_)
+            | Err::Io(_)
+            | Err::BlockingTask(_) => ErrorCode::Internal,
+            #[cfg(feature = "production")]
+            Err::QuoteParse(_) => ErrorCode::Internal,
+        }
+    }
+
+    /// HTTP status code to respond with
+    pub fn status_code(&self) -> StatusCode {
+        match self.code() {
+            ErrorCode::BadRequest
+            | ErrorCode::InvalidUrl
+            | ErrorCode::UrlHasNoHost
+            | ErrorCode::UnsupportedUrlScheme
+            | ErrorCode::UnsupportedHttpMethod
+            | ErrorCode::InvalidHeader
+            | ErrorCode::InvalidSecretName
+            | ErrorCode::ExpiryInPast
+            | ErrorCode::InvalidRotationRecipe
+            | ErrorCode::InvalidInjectionRule
+            | ErrorCode::InvalidRequestPolicy
+            | ErrorCode::InvalidBackup
+            | ErrorCode::NoResponseKey => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthenticated
+            | ErrorCode::QuoteRejected
+            | ErrorCode::MigrationRejected
+            | ErrorCode::Stale => StatusCode::UNAUTHORIZED,
+            ErrorCode::EgressDenied
+            | ErrorCode::InjectionNotAllowed
+            | ErrorCode::PolicyViolation => StatusCode::FORBIDDEN,
+            ErrorCode::NoKeyForService | ErrorCode::NoSuchApiKey => StatusCode::NOT_FOUND,
+            ErrorCode::ApiKeyExpired => StatusCode::GONE,
+            ErrorCode::NoPendingVersion | ErrorCode::NoPreviousVersion | ErrorCode::Replayed => {
+                StatusCode::CONFLICT
+            }
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::Upstream | ErrorCode::RotationFailed => StatusCode::BAD_GATEWAY,
+            ErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    /// JSON body to respond with
+    pub fn error_response(&self) -> ErrorResponse {
+        ErrorResponse {
+            code: self.code(),
+            message: format!("{self}"),
+        }
+    }
 }
 
 impl IntoResponse for Err {
     fn into_response(self) -> Response {
         tracing::error!("{:?}", format!("{self}"));
-        let body = format!("{self}").into_bytes();
-        (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
+        (self.status_code(), Json(self.error_response())).into_response()
     }
 }
@@ -0,0 +1,99 @@
+//! The long-lived identity of this service instance, which is kept stable across restarts
+use crate::{
+    errors::Err,
+    sealing::{SealedFile, get_sealing_key},
+};
+use hkdf::Hkdf;
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use sp_core::{Pair, sr25519};
+use std::path::PathBuf;
+use x25519_dalek::StaticSecret;
+use zeroize::Zeroizing;
+
+#[cfg(test)]
+mod tests;
+
+/// Default location of the sealed identity file
+pub const DEFAULT_IDENTITY_PATH: &str = "identity.sealed";
+
+/// Environment variable from which a mnemonic may be given when not in production
+#[cfg(not(feature = "production"))]
+pub const MNEMONIC_ENV_VAR: &str = "API_KEY_SERVICE_MNEMONIC";
+
+/// HKDF info string used when deriving the x25519 secret from the identity seed
+const X25519_SECRET_INFO: &[u8] = b"entropy-api-key-service-x25519-secret";
+
+/// The secret keys which identify this service instance on chain and to clients
+pub struct ServiceIdentity {
+    /// Keypair for box id account
+    pub pair: sr25519::Pair,
+    /// Secret encryption key
+    pub x25519_secret: StaticSecret,
+}
+
+impl ServiceIdentity {
+    /// Derive both the sr25519 keypair and the x25519 secret from a 32 byte seed
+    pub fn from_seed(seed: &[u8; 32]) -> Result<Self, Err> {
+        let hkdf = Hkdf::<Sha256>::new(None, seed);
+        let mut x25519_seed = Zeroizing::new([0u8; 32]);
+        hkdf.expand(X25519_SECRET_INFO, x25519_seed.as_mut())
+            .map_err(|_| Err::Sealing("Cannot derive x25519 secret".to_string()))?;
+
+        Ok(Self {
+            pair: sr25519::Pair::from_seed(seed),
+            x25519_secret: StaticSecret::from(*x25519_seed),
+        })
+    }
+
+    /// Derive the identity from a mnemonic, seed or derivation path such as `//Alice`
+    #[cfg(not(feature = "production"))]
+    pub fn from_mnemonic(mnemonic: &str) -> Result<Self, Err> {
+        let (_pair, seed) = <sr25519::Pair as Pair>::from_string_with_seed(mnemonic, None)
+            .map_err(|e| Err::Mnemonic(format!("{e:?}")))?;
+        let seed = Zeroizing::new(seed.ok_or(Err::Mnemonic(
+            "Soft derivation paths cannot be used for the service identity".to_string(),
+        ))?);
+        Self::from_seed(&seed)
+    }
+}
+
+/// Unseal the identity from the given file, or create a new one and seal it there if the file
+/// does not exist
+pub fn load_or_create_identity(sealed_file: &SealedFile) -> Result<ServiceIdentity, Err> {
+    let seed: Zeroizing<[u8; 32]> = match sealed_file.load()? {
+        Some(seed) => Zeroizing::new(seed.as_slice().try_into()?),
+        None => {
+            let mut seed = Zeroizing::new([0u8; 32]);
+            OsRng.fill_bytes(seed.as_mut());
+            sealed_file.store(seed.as_ref())?;
+            tracing::info!("Created new service identity");
+            seed
+        }
+    };
+    ServiceIdentity::from_seed(&seed)
+}
+
+/// Get the identity of this service instance. In production, this is always sealed to the
+/// measurement value of the running image.
+#[cfg(feature = "production")]
+pub fn get_identity(identity_path: PathBuf) -> Result<ServiceIdentity, Err> {
+    load_or_create_identity(&SealedFile::new(identity_path, get_sealing_key()?))
+}
+
+/// Get the identity of this service instance. When not in production, this may be given as a
+/// mnemonic in a file or environment variable. Otherwise a sealed identity file is used.
+#[cfg(not(feature = "production"))]
+pub fn get_identity(
+    identity_path: PathBuf,
+    mnemonic_file: Option<PathBuf>,
+) -> Result<ServiceIdentity, Err> {
+    let mnemonic = match mnemonic_file {
+        Some(mnemonic_file) => Some(Zeroizing::new(std::fs::read_to_string(mnemonic_file)?)),
+        None => std::env::var(MNEMONIC_ENV_VAR).ok().map(Zeroizing::new),
+    };
+    match mnemonic {
+        Some(mnemonic) => ServiceIdentity::from_mnemonic(mnemonic.trim()),
+        None => load_or_create_identity(&SealedFile::new(identity_path, get_sealing_key()?)),
+    }
+}
@@ -0,0 +1,50 @@
+use super::{ServiceIdentity, load_or_create_identity};
+use crate::{
+    sealing::{SealedFile, get_sealing_key},
+    test_helpers::temporary_path,
+};
+use sp_core::Pair;
+use sp_keyring::sr25519::Keyring;
+
+#[test]
+fn test_identity_from_seed_is_deterministic() {
+    let first = ServiceIdentity::from_seed(&[1; 32]).unwrap();
+    let second = ServiceIdentity::from_seed(&[1; 32]).unwrap();
+    let other = ServiceIdentity::from_seed(&[2; 32]).unwrap();
+
+    assert_eq!(first.pair.public(), second.pair.public());
+    assert_eq!(
+        first.x25519_secret.to_bytes(),
+        second.x25519_secret.to_bytes()
+    );
+    assert_ne!(first.pair.public(), other.pair.public());
+    assert_ne!(
+        first.x25519_secret.to_bytes(),
+        other.x25519_secret.to_bytes()
+    );
+}
+
+#[test]
+fn test_identity_from_mnemonic() {
+    let identity = ServiceIdentity::from_mnemonic("//Alice").unwrap();
+    assert_eq!(identity.pair.public(), Keyring::Alice.pair().public());
+
+    assert!(ServiceIdentity::from_mnemonic("//Alice/soft").is_err());
+}
+
+#[test]
+fn test_sealed_identity_is_stable_across_restarts() {
+    let path = temporary_path("identity");
+    let sealed_file = SealedFile::new(path.clone(), get_sealing_key().unwrap());
+
+    let first = load_or_create_identity(&sealed_file).unwrap();
+    let second = load_or_create_identity(&sealed_file).unwrap();
+
+    assert_eq!(first.pair.public(), second.pair.public());
+    assert_eq!(
+        first.x25519_secret.to_bytes(),
+        second.x25519_secret.to_bytes()
+    );
+
+    std::fs::remove_file(path).unwrap();
+}
@@ -1,40 +1,99 @@
+pub mod api_key_store;
 pub mod api_keys;
 pub mod app_state;
+pub mod attestation;
 pub mod errors;
 pub mod health;
+pub mod identity;
+pub mod migration;
 pub mod node_info;
+pub mod replay_cache;
+pub mod replication;
+pub mod response;
+pub mod sealing;
 
 #[cfg(test)]
 pub mod test_helpers;
 
 use crate::{
-    api_keys::api::{delete_secret, deploy_api_key, make_request},
+    api_keys::{
+        api::{
+            delete_secret, deploy_api_key, export_api_keys, import_api_keys, list_api_keys,
+            make_request, promote_api_key, rollback_api_key,
+        },
+        egress::{AllowedDestination, EgressPolicy},
+        rotation::rotate_api_keys,
+    },
     health::api::healthz,
+    migration::{
+        MIGRATION_COMPLETE_ROUTE, MIGRATION_EXPORT_ROUTE, MIGRATION_IDENTITY_ROUTE,
+        api::{migration_complete, migration_export, migration_identity},
+        migrate_from,
+    },
     node_info::api::{info, version},
+    replication::{
+        REPLICATION_CHANGES_ROUTE, REPLICATION_IDENTITY_ROUTE,
+        api::{replication_changes, replication_identity},
+        replicate_api_keys,
+    },
 };
 use anyhow::anyhow;
-use app_state::{AppState, Configuration};
+use api_key_store::{ApiKeyStoreType, purge_expired_api_keys};
+use app_state::{AppState, Configuration, DEFAULT_STORAGE_PATH};
 use axum::{
     Router,
     routing::{get, post},
 };
 use clap::Parser;
+use entropy_api_key_service_shared::{
+    DELETE_SECRET_ROUTE, DEPLOY_API_KEY_ROUTE, EXPORT_API_KEYS_ROUTE, IMPORT_API_KEYS_ROUTE,
+    LIST_API_KEYS_ROUTE, MAKE_REQUEST_ROUTE, PROMOTE_API_KEY_ROUTE, ROLLBACK_API_KEY_ROUTE,
+};
 use entropy_client::forest::declare_to_chain;
-use rand_core::OsRng;
-use sp_core::{Pair, sr25519};
-use std::{net::SocketAddr, str::FromStr};
-use x25519_dalek::StaticSecret;
+use identity::{DEFAULT_IDENTITY_PATH, get_identity};
+use replay_cache::sweep_replay_cache;
+use sealing::TamperPolicy;
+use std::{net::SocketAddr, path::PathBuf, str::FromStr};
 
 pub use entropy_api_key_service_shared::{DeleteApiKeyInfo, DeployApiKeyInfo, SendApiKeyMessage};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = StartupArgs::parse();
-    let configuration = Configuration::new(args.chain_endpoint);
+    #[cfg(not(feature = "production"))]
+    if !args.replication_peer.is_empty() || args.replicate_with_chain_peers {
+        // Without production quotes are mocks, so peers cannot be checked
+        return Err(anyhow!("Replication is only available in production"));
+    }
+    #[cfg(not(feature = "production"))]
+    if !args.accept_migration_measurement.is_empty() || args.migrate_from.is_some() {
+        // Without production quotes are mocks, so the other instance cannot be checked
+        return Err(anyhow!("Migration is only available in production"));
+    }
+    let configuration = Configuration {
+        endpoint: args.chain_endpoint,
+        api_key_store: args.api_key_store,
+        storage_path: args.storage_path,
+        tamper_policy: args.on_storage_tamper,
+        migration_measurements: args.accept_migration_measurement,
+        replication_peers: args.replication_peer,
+        replicate_with_chain_peers: args.replicate_with_chain_peers,
+        egress_policy: EgressPolicy {
+            allowed: args.allow_egress,
+        },
+    };
+
+    #[cfg(feature = "production")]
+    let identity = get_identity(args.identity_path)?;
+    #[cfg(not(feature = "production"))]
+    let identity = get_identity(args.identity_path, args.mnemonic_file)?;
+    let app_state = AppState::new(configuration, identity)?;
+
+    if let Some(old_instance_url) = args.migrate_from {
+        let migrated = migrate_from(&app_state, &old_instance_url).await?;
+        tracing::info!("Migrated {migrated} api keys from {old_instance_url}");
+    }
 
-    let (pair, _seed) = sr25519::Pair::generate();
-    let x25519_secret = StaticSecret::random_from_rng(OsRng);
-    let app_state = AppState::new(configuration, pair.clone(), x25519_secret);
     let (api, rpc) = app_state.get_api_rpc().await.expect("No chain connection");
 
     let _ = declare_to_chain(
@@ -42,7 +101,7 @@ async fn main() -> anyhow::Result<()> {
         &rpc,
         args.box_url.clone(),
         app_state.x25519_public_key(),
-        &pair,
+        &app_state.identity.pair,
         None,
     )
     .await
@@ -53,6 +112,16 @@ async fn main() -> anyhow::Result<()> {
     let listener = tokio::net::TcpListener::bind(&addr)
         .await
         .map_err(|_| anyhow!("Unable to bind to given server address"))?;
+    tokio::spawn(purge_expired_api_keys(
+        app_state.api_keys.clone(),
+        app_state.api_key_update_lock.clone(),
+    ));
+    tokio::spawn(rotate_api_keys(app_state.clone()));
+    tokio::spawn(sweep_replay_cache(app_state.replay_cache.clone()));
+    if app_state.configuration.replicates() {
+        tokio::spawn(replicate_api_keys(app_state.clone()));
+    }
+
     // TODO: add loggings
     axum::serve(listener, app(app_state).into_make_service()).await?;
     Ok(())
@@ -77,17 +146,81 @@ pub struct StartupArgs {
         default_value = "ws://localhost:9944"
     )]
     pub chain_endpoint: String,
+    /// Where to store API keys. With `memory`, API keys are lost on restart.
+    #[arg(long = "api-key-store", value_enum, default_value_t = ApiKeyStoreType::File)]
+    pub api_key_store: ApiKeyStoreType,
+    /// File to persist sealed API keys to when using the `file` API key store.
+    #[arg(
+        short = 's',
+        long = "storage-path",
+        required = false,
+        default_value = DEFAULT_STORAGE_PATH
+    )]
+    pub storage_path: PathBuf,
+    /// What to do if persisted API keys fail authentication on startup.
+    #[arg(long = "on-storage-tamper", value_enum, default_value_t = TamperPolicy::Refuse)]
+    pub on_storage_tamper: TamperPolicy,
+    /// File to persist the sealed identity (account and encryption keypairs) of this instance to.
+    #[arg(long = "identity-path", required = false, default_value = DEFAULT_IDENTITY_PATH)]
+    pub identity_path: PathBuf,
+    /// File containing a mnemonic to derive the identity of this instance from, rather than using
+    /// a sealed identity. May also be given with the `API_KEY_SERVICE_MNEMONIC` environment
+    /// variable. Not available in production.
+    #[cfg(not(feature = "production"))]
+    #[arg(long = "mnemonic-file", required = false)]
+    pub mnemonic_file: Option<PathBuf>,
+    /// Hex encoded measurement value of a release which api keys may be migrated to or from. May be
+    /// given multiple times.
+    #[arg(long = "accept-migration-measurement", value_parser = parse_measurement)]
+    pub accept_migration_measurement: Vec<[u8; 32]>,
+    /// URL of an instance to migrate all api keys from on startup. Each must accept the measurement
+    /// value of the other's release.
+    #[arg(long = "migrate-from", required = false)]
+    pub migrate_from: Option<String>,
+    /// URL of another instance to replicate api keys with. It must be running the same release.
+    /// May be given multiple times.
+    #[arg(long = "replication-peer")]
+    pub replication_peer: Vec<String>,
+    /// Replicate api keys with the other instances registered on chain which are running the same
+    /// release.
+    #[arg(long = "replicate-with-chain-peers")]
+    pub replicate_with_chain_peers: bool,
+    /// An address which requests made with api keys may be sent to even though it is not public,
+    /// given as an IP address, or an IP address and port. May be given multiple times.
+    #[arg(long = "allow-egress")]
+    pub allow_egress: Vec<AllowedDestination>,
+}
+
+/// Parses a hex encoded measurement value
+fn parse_measurement(s: &str) -> Result<[u8; 32], String> {
+    hex::decode(s)
+        .map_err(|error| error.to_string())?
+        .try_into()
+        .map_err(|_| "Measurement value must be 32 bytes".to_string())
 }
 
 pub fn app(app_state: AppState) -> Router {
-    let routes = Router::new()
+    let mut routes = Router::new()
         .route("/healthz", get(healthz))
-        .route("/deploy-api-key", post(deploy_api_key))
-        .route("/delete-secret", post(delete_secret))
-        .route("/make-request", post(make_request))
+        .route(DEPLOY_API_KEY_ROUTE, post(deploy_api_key))
+        .route(DELETE_SECRET_ROUTE, post(delete_secret))
+        .route(MAKE_REQUEST_ROUTE, post(make_request))
+        .route(LIST_API_KEYS_ROUTE, post(list_api_keys))
+        .route(PROMOTE_API_KEY_ROUTE, post(promote_api_key))
+        .route(ROLLBACK_API_KEY_ROUTE, post(rollback_api_key))
+        .route(EXPORT_API_KEYS_ROUTE, post(export_api_keys))
+        .route(IMPORT_API_KEYS_ROUTE, post(import_api_keys))
+        .route(MIGRATION_IDENTITY_ROUTE, post(migration_identity))
+        .route(MIGRATION_EXPORT_ROUTE, post(migration_export))
+        .route(MIGRATION_COMPLETE_ROUTE, post(migration_complete))
         .route("/version", get(version))
-        .route("/info", get(info))
-        .with_state(app_state);
+        .route("/info", get(info));
+    // Other instances may only send changes to api keys if this instance replicates with peers
+    if app_state.configuration.replicates() {
+        routes = routes
+            .route(REPLICATION_IDENTITY_ROUTE, post(replication_identity))
+            .route(REPLICATION_CHANGES_ROUTE, post(replication_changes));
+    }
 
-    routes
+    routes.with_state(app_state)
 }
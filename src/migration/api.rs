@@ -0,0 +1,105 @@
+use super::{
+    MIGRATION_COMPLETE_ROUTE, MIGRATION_EXPORT_ROUTE, MigrationChallenge, MigrationComplete,
+    MigrationRequest, PendingMigration, issue_migration_nonce, take_migration_nonce,
+    verify_migration_request,
+};
+use crate::{
+    AppState,
+    api_keys::api::{check_stale, get_current_timestamp},
+    attestation::{IdentityRequest, attested_identity},
+    errors::Err,
+};
+use axum::{Json, extract::State};
+use entropy_protocol::sign_and_encrypt::EncryptedSignedMessage;
+use subxt::utils::AccountId32 as SubxtAccountId32;
+use zeroize::Zeroizing;
+
+/// Returns the identity of this instance, with a quote bound to the nonce given by a new instance,
+/// and a nonce which its migration request must be bound to
+pub async fn migration_identity(
+    State(app_state): State<AppState>,
+    Json(request): Json<IdentityRequest>,
+) -> Result<Json<MigrationChallenge>, Err> {
+    if app_state.configuration.migration_measurements.is_empty() {
+        return Err(Err::MigrationRejected(
+            "This instance does not accept migrations".to_string(),
+        ));
+    }
+    Ok(Json(MigrationChallenge {
+        identity: attested_identity(&app_state, request.nonce).await?,
+        nonce: issue_migration_nonce(&app_state, get_current_timestamp()?),
+    }))
+}
+
+/// Sends all api keys to a new instance of the service, if its quote is valid and its measurement
+/// value is accepted
+pub async fn migration_export(
+    State(app_state): State<AppState>,
+    Json(request): Json<MigrationRequest>,
+) -> Result<Json<EncryptedSignedMessage>, Err> {
+    // The quote is checked first, so that only instances with an accepted measurement value can
+    // use up nonces
+    verify_migration_request(&request, &app_state.configuration.migration_measurements)?;
+    take_migration_nonce(&app_state, &request.nonce, get_current_timestamp()?).await?;
+
+    let api_keys = app_state.api_keys.list_all().await?;
+    // The plaintext api keys are serialized into a buffer which is wiped if encryption is not
+    // reached, and which is otherwise moved into the message to be encrypted without a copy
+    let mut payload = Zeroizing::new(Vec::new());
+    serde_json::to_writer(&mut *payload, &api_keys)?;
+    let encrypted = EncryptedSignedMessage::new(
+        &app_state.identity.pair,
+        std::mem::take(&mut *payload),
+        &request.x25519_public_key,
+        &app_state.associated_data(MIGRATION_EXPORT_ROUTE),
+    )?;
+
+    tracing::info!(
+        "Sending {} api keys to new instance {}",
+        api_keys.len(),
+        SubxtAccountId32(request.account_id)
+    );
+    *app_state.pending_migration.lock().await = Some(PendingMigration {
+        account_id: request.account_id,
+        api_keys,
+    });
+    Ok(Json(encrypted))
+}
+
+/// Deletes the api keys sent to a new instance once it confirms it has stored them, returning how
+/// many were deleted
+pub async fn migration_complete(
+    State(app_state): State<AppState>,
+    Json(encrypted_msg): Json<EncryptedSignedMessage>,
+) -> Result<Json<usize>, Err> {
+    let signed_message = encrypted_msg.decrypt(
+        &app_state.identity.x25519_secret,
+        &app_state.associated_data(MIGRATION_COMPLETE_ROUTE),
+    )?;
+    let complete: MigrationComplete = serde_json::from_slice(&signed_message.message.0)?;
+    check_stale(complete.timestamp, get_current_timestamp()?).await?;
+
+    let mut pending_migration = app_state.pending_migration.lock().await;
+    let pending = match pending_migration.take() {
+        Some(pending) if &pending.account_id == signed_message.account_id().as_ref() => pending,
+        other => {
+            *pending_migration = other;
+            return Err(Err::MigrationRejected(
+                "No migration to this instance is in progress".to_string(),
+            ));
+        }
+    };
+
+    // Api keys which changed after being sent are kept, as the new instance does not have them.
+    // They are only deleted from this instance, as any peers it replicates with keep serving them
+    let _update_guard = app_state.api_key_update_lock.lock().await;
+    let mut deleted = 0;
+    for (api_key_id, entry) in pending.api_keys {
+        if app_state.read_from_api_keys(&api_key_id).await?.as_ref() == Some(&entry) {
+            app_state.replica.delete_locally(&api_key_id).await?;
+            deleted += 1;
+        }
+    }
+    tracing::info!("Migration complete - deleted {deleted} api keys");
+    Ok(Json(deleted))
+}
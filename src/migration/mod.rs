@@ -0,0 +1,247 @@
+//! Moving all api keys from one instance of the service to another, such as a new release with a
+//! different measurement value
+//!
+//! The new instance first asks the old one for its identity, giving a random nonce. The old
+//! instance responds with a quote bound to that nonce, and a random nonce of its own. The new
+//! instance checks the quote, then asks for the api keys, giving a quote which binds its account
+//! ID and x25519 public key to the old instance's nonce. Each instance checks that the other's
+//! measurement value is one it has been configured to accept. The old instance responds with its
+//! api keys encrypted to the new instance. Once the new instance has stored them it confirms this,
+//! and the old instance deletes its own copy.
+pub mod api;
+
+#[cfg(test)]
+mod tests;
+
+use crate::{
+    api_key_store::{ApiKeyEntry, ApiKeyId},
+    api_keys::api::get_current_timestamp,
+    app_state::AppState,
+    attestation::{
+        AttestedIdentity, IdentityRequest, create_identity_quote, verify_identity_quote,
+    },
+    errors::Err,
+};
+use entropy_api_key_service_shared::associated_data;
+use entropy_protocol::sign_and_encrypt::EncryptedSignedMessage;
+use hkdf::Hkdf;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sp_core::Pair;
+use zeroize::Zeroize;
+
+pub const MIGRATION_IDENTITY_ROUTE: &str = "/migration/identity";
+pub const MIGRATION_EXPORT_ROUTE: &str = "/migration/export";
+pub const MIGRATION_COMPLETE_ROUTE: &str = "/migration/complete";
+
+/// Number of seconds a new instance has to make its migration request after being given a nonce
+const MIGRATION_NONCE_TIMEOUT: u64 = 60;
+
+/// Length in bytes of the part of a migration nonce which authenticates the rest
+const MIGRATION_NONCE_TAG_LENGTH: usize = 16;
+
+/// Response of an old instance to a request for its identity
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MigrationChallenge {
+    /// Identity of the old instance, with a quote bound to the nonce given by the new instance
+    pub identity: AttestedIdentity,
+    /// Nonce which the quote in the migration request must be bound to
+    pub nonce: [u8; 32],
+}
+
+/// Request from a new instance for the api keys of an old one
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MigrationRequest {
+    /// Account ID of the new instance, which must sign to confirm the migration
+    pub account_id: [u8; 32],
+    /// x25519 public key of the new instance, which the api keys are encrypted to
+    pub x25519_public_key: [u8; 32],
+    /// Nonce given by the old instance in its [MigrationChallenge]
+    pub nonce: [u8; 32],
+    /// TDX quote whose input data binds the above
+    pub tdx_quote: Vec<u8>,
+}
+
+/// Sent by the new instance once it has stored the api keys
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MigrationComplete {
+    /// Unix time in seconds at which the message was made
+    pub timestamp: u64,
+}
+
+/// A migration which an old instance has sent, but which the new instance has not yet confirmed
+pub struct PendingMigration {
+    /// Account ID of the new instance
+    pub account_id: [u8; 32],
+    /// The api keys as they were sent. Any which change before the migration is confirmed are
+    /// not deleted
+    pub api_keys: Vec<(ApiKeyId, ApiKeyEntry)>,
+}
+
+/// Give out a nonce which a migration request must be bound to, at the given unix time in
+/// seconds. The nonce holds the time it was given out and random bytes, authenticated with a key
+/// of this instance. Nothing is stored until it is used, so that asking for nonces cannot stop
+/// other instances from migrating.
+pub fn issue_migration_nonce(app_state: &AppState, current_timestamp: u64) -> [u8; 32] {
+    let mut nonce = [0; 32];
+    let (issued, tag) = nonce.split_at_mut(32 - MIGRATION_NONCE_TAG_LENGTH);
+    issued[..8].copy_from_slice(&current_timestamp.to_be_bytes());
+    OsRng.fill_bytes(&mut issued[8..]);
+    tag.copy_from_slice(&migration_nonce_tag(app_state, issued));
+    nonce
+}
+
+/// Use up a nonce given out by [issue_migration_nonce], so that a migration request cannot be
+/// replayed. This should only be called for requests with a valid quote, as used nonces are
+/// remembered until they expire.
+pub async fn take_migration_nonce(
+    app_state: &AppState,
+    nonce: &[u8; 32],
+    current_timestamp: u64,
+) -> Result<(), Err> {
+    let (issued, tag) = nonce.split_at(32 - MIGRATION_NONCE_TAG_LENGTH);
+    let issued_at = u64::from_be_bytes(issued[..8].try_into().expect("Slice is 8 bytes"));
+    // Compared without returning early, so that the time taken does not tell how much matched
+    let tag_matches = migration_nonce_tag(app_state, issued)
+        .iter()
+        .zip(tag)
+        .fold(0, |difference, (a, b)| difference | (a ^ b))
+        == 0;
+
+    let mut used_nonces = app_state.used_migration_nonces.lock().await;
+    used_nonces.retain(|_, issued_at| {
+        current_timestamp.saturating_sub(*issued_at) < MIGRATION_NONCE_TIMEOUT
+    });
+    if tag_matches
+        && current_timestamp.saturating_sub(issued_at) < MIGRATION_NONCE_TIMEOUT
+        && used_nonces.insert(*nonce, issued_at).is_none()
+    {
+        Ok(())
+    } else {
+        Err(Err::MigrationRejected(
+            "Nonce was not given out by this instance, has expired or has been used".to_string(),
+        ))
+    }
+}
+
+/// Authenticate the time and random bytes of a migration nonce
+fn migration_nonce_tag(app_state: &AppState, issued: &[u8]) -> [u8; MIGRATION_NONCE_TAG_LENGTH] {
+    let hkdf = Hkdf::<Sha256>::new(None, app_state.migration_nonce_key.as_slice());
+    let mut tag = [0; MIGRATION_NONCE_TAG_LENGTH];
+    hkdf.expand(issued, &mut tag)
+        .expect("Tag is shorter than the longest HKDF output");
+    tag
+}
+
+/// Check the quote of a migration request, and that it was made by a release with one of the
+/// given measurement values
+pub fn verify_migration_request(
+    request: &MigrationRequest,
+    accepted_measurements: &[[u8; 32]],
+) -> Result<(), Err> {
+    verify_identity_quote(
+        &request.tdx_quote,
+        request.account_id,
+        request.x25519_public_key,
+        request.nonce,
+        accepted_measurements,
+    )
+}
+
+/// Make a migration request for this instance, bound to the nonce given by the old instance
+pub async fn migration_request(
+    app_state: &AppState,
+    nonce: [u8; 32],
+) -> Result<MigrationRequest, Err> {
+    Ok(MigrationRequest {
+        account_id: app_state.identity.pair.public().0,
+        x25519_public_key: app_state.x25519_public_key(),
+        nonce,
+        tdx_quote: create_identity_quote(app_state, nonce).await?,
+    })
+}
+
+/// Move all api keys from the instance at the given URL to this one, returning how many were
+/// moved. Each instance must accept the measurement value of the other.
+pub async fn migrate_from(app_state: &AppState, old_instance_url: &str) -> Result<usize, Err> {
+    let client = reqwest::Client::new();
+    // The old instance is only trusted once it gives a quote bound to a nonce we chose
+    let mut nonce = [0; 32];
+    OsRng.fill_bytes(&mut nonce);
+    let challenge: MigrationChallenge = client
+        .post(format!("{old_instance_url}{MIGRATION_IDENTITY_ROUTE}"))
+        .json(&IdentityRequest { nonce })
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let old_instance = challenge.identity;
+    verify_identity_quote(
+        &old_instance.tdx_quote,
+        old_instance.account_id,
+        old_instance.x25519_public_key,
+        nonce,
+        &app_state.configuration.migration_measurements,
+    )?;
+    let old_account_id = old_instance.account_id;
+
+    let request = migration_request(app_state, challenge.nonce).await?;
+    let response = client
+        .post(format!("{old_instance_url}{MIGRATION_EXPORT_ROUTE}"))
+        .json(&request)
+        .send()
+        .await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(Err::MigrationFailed(format!(
+            "Old instance responded with {status}: {}",
+            response.text().await?
+        )));
+    }
+    let encrypted: EncryptedSignedMessage = response.json().await?;
+    let mut signed_message = encrypted.decrypt(
+        &app_state.identity.x25519_secret,
+        &associated_data(MIGRATION_EXPORT_ROUTE, &old_account_id),
+    )?;
+    if signed_message.account_id().as_ref() != &old_account_id {
+        return Err(Err::MigrationFailed(
+            "Api keys were not signed by the old instance".to_string(),
+        ));
+    }
+    let api_keys: Result<Vec<(ApiKeyId, ApiKeyEntry)>, _> =
+        serde_json::from_slice(&signed_message.message.0);
+    signed_message.message.0.zeroize();
+    let api_keys = api_keys?;
+
+    let migrated = api_keys.len();
+    {
+        let _update_guard = app_state.api_key_update_lock.lock().await;
+        for (api_key_id, entry) in api_keys {
+            app_state.write_to_api_keys(api_key_id, entry).await?;
+        }
+    }
+
+    let complete = EncryptedSignedMessage::new(
+        &app_state.identity.pair,
+        serde_json::to_vec(&MigrationComplete {
+            timestamp: get_current_timestamp()?,
+        })?,
+        &old_instance.x25519_public_key,
+        &associated_data(MIGRATION_COMPLETE_ROUTE, &old_account_id),
+    )?;
+    let response = client
+        .post(format!("{old_instance_url}{MIGRATION_COMPLETE_ROUTE}"))
+        .json(&complete)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(Err::MigrationFailed(format!(
+            "Api keys were migrated, but the old instance did not confirm deleting them: {}",
+            response.text().await?
+        )));
+    }
+
+    Ok(migrated)
+}
@@ -0,0 +1,194 @@
+use super::{
+    MIGRATION_EXPORT_ROUTE, MIGRATION_IDENTITY_ROUTE, MigrationChallenge, MigrationRequest,
+    migrate_from, migration_request, verify_migration_request,
+};
+use crate::{
+    app_state::Configuration,
+    attestation::{IdentityRequest, create_identity_quote, quote_measurement},
+    errors::Err,
+    test_helpers::{
+        DEFAULT_ENDPOINT, make_test_client, make_test_client_for, setup_client, start_service,
+    },
+};
+use serial_test::serial;
+use sp_keyring::sr25519::Keyring;
+
+#[tokio::test]
+#[serial]
+async fn test_migrate_api_keys() {
+    let mut new_instance = setup_client().await;
+    let measurement =
+        quote_measurement(&create_identity_quote(&new_instance, [0; 32]).await.unwrap()).unwrap();
+
+    let old_instance_url = "http://127.0.0.1:3003";
+    let old_instance = start_service(
+        Configuration {
+            migration_measurements: vec![measurement],
+            ..Configuration::new(DEFAULT_ENDPOINT.to_string())
+        },
+        "0.0.0.0:3003",
+    )
+    .await;
+    let old_client = make_test_client_for(old_instance_url, &old_instance, &Keyring::One);
+    old_client
+        .deploy_named_api_key(
+            "client_secret".to_string(),
+            "some-secret".to_string(),
+            "https://api.example.com/v2".to_string(),
+        )
+        .await
+        .unwrap();
+    make_test_client_for(old_instance_url, &old_instance, &Keyring::Two)
+        .deploy_api_key(
+            "other-secret".to_string(),
+            "https://api.example.com".to_string(),
+        )
+        .await
+        .unwrap();
+    let api_keys = old_client.list().await.unwrap();
+
+    // The new instance must also accept the measurement value of the old one
+    assert!(matches!(
+        migrate_from(&new_instance, old_instance_url).await,
+        Err(Err::QuoteRejected(_))
+    ));
+    assert_eq!(old_instance.api_keys.list_all().await.unwrap().len(), 2);
+
+    new_instance.configuration.migration_measurements = vec![measurement];
+    assert_eq!(
+        migrate_from(&new_instance, old_instance_url).await.unwrap(),
+        2
+    );
+
+    // The old instance has deleted its copy
+    assert!(old_instance.api_keys.list_all().await.unwrap().is_empty());
+
+    let migrated = make_test_client(&new_instance, &Keyring::One)
+        .list()
+        .await
+        .unwrap();
+    assert_eq!(migrated.len(), 1);
+    assert_eq!(migrated[0].service, api_keys[0].service);
+    assert_eq!(migrated[0].secret_name, api_keys[0].secret_name);
+    assert_eq!(migrated[0].fingerprint, api_keys[0].fingerprint);
+    assert_eq!(migrated[0].deployed_at, api_keys[0].deployed_at);
+    assert_eq!(
+        make_test_client(&new_instance, &Keyring::Two)
+            .list()
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+#[tokio::test]
+#[serial]
+async fn test_migration_request_must_have_accepted_quote() {
+    let app_state = setup_client().await;
+    let request = migration_request(&app_state, [1; 32]).await.unwrap();
+    let measurement = quote_measurement(&request.tdx_quote).unwrap();
+
+    verify_migration_request(&request, &[measurement]).unwrap();
+
+    // The measurement value is not accepted
+    assert!(matches!(
+        verify_migration_request(&request, &[]),
+        Err(Err::QuoteRejected(_))
+    ));
+    assert!(matches!(
+        verify_migration_request(&request, &[[0; 32]]),
+        Err(Err::QuoteRejected(_))
+    ));
+
+    // The quote does not match the rest of the request
+    let other_key = MigrationRequest {
+        x25519_public_key: [1; 32],
+        ..request.clone()
+    };
+    assert!(matches!(
+        verify_migration_request(&other_key, &[measurement]),
+        Err(Err::QuoteRejected(_))
+    ));
+    let other_nonce = MigrationRequest {
+        nonce: [2; 32],
+        ..request.clone()
+    };
+    assert!(matches!(
+        verify_migration_request(&other_nonce, &[measurement]),
+        Err(Err::QuoteRejected(_))
+    ));
+
+    let bad_quote = MigrationRequest {
+        tdx_quote: vec![0; 32],
+        ..request
+    };
+    assert!(matches!(
+        verify_migration_request(&bad_quote, &[measurement]),
+        Err(Err::QuoteRejected(_))
+    ));
+}
+
+#[tokio::test]
+#[serial]
+async fn test_migration_request_cannot_be_replayed() {
+    let new_instance = setup_client().await;
+    let measurement =
+        quote_measurement(&create_identity_quote(&new_instance, [0; 32]).await.unwrap()).unwrap();
+    let old_instance_url = "http://127.0.0.1:3003";
+    start_service(
+        Configuration {
+            migration_measurements: vec![measurement],
+            ..Configuration::new(DEFAULT_ENDPOINT.to_string())
+        },
+        "0.0.0.0:3003",
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let challenge = || async {
+        client
+            .post(format!("{old_instance_url}{MIGRATION_IDENTITY_ROUTE}"))
+            .json(&IdentityRequest { nonce: [0; 32] })
+            .send()
+            .await
+            .unwrap()
+            .json::<MigrationChallenge>()
+            .await
+            .unwrap()
+    };
+    // Asking for many nonces does not stop others from being used
+    for _ in 0..32 {
+        challenge().await;
+    }
+    let challenge = challenge().await;
+    let request = migration_request(&new_instance, challenge.nonce)
+        .await
+        .unwrap();
+    let export = || {
+        client
+            .post(format!("{old_instance_url}{MIGRATION_EXPORT_ROUTE}"))
+            .json(&request)
+            .send()
+    };
+    assert!(export().await.unwrap().status().is_success());
+    // The nonce given by the old instance can only be used once
+    assert!(!export().await.unwrap().status().is_success());
+
+    // A nonce chosen by the new instance, or changed from one given out, is not accepted
+    let mut changed_nonce = challenge.nonce;
+    changed_nonce[0] ^= 1;
+    for nonce in [[1; 32], changed_nonce] {
+        let request = migration_request(&new_instance, nonce).await.unwrap();
+        assert!(
+            !client
+                .post(format!("{old_instance_url}{MIGRATION_EXPORT_ROUTE}"))
+                .json(&request)
+                .send()
+                .await
+                .unwrap()
+                .status()
+                .is_success()
+        );
+    }
+}
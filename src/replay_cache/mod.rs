@@ -0,0 +1,100 @@
+//! Tracks recently seen messages so that a captured request cannot be replayed while it is still
+//! within the staleness window
+use crate::{
+    api_keys::api::{TIME_BUFFER, get_current_timestamp},
+    errors::Err,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::Mutex;
+
+#[cfg(test)]
+mod tests;
+
+/// The maximum number of messages remembered for a single account. Once this is reached, further
+/// messages from that account are rejected until older ones expire. Evicting old entries instead
+/// would allow them to be replayed.
+pub const MAX_MESSAGES_PER_ACCOUNT: usize = 1000;
+
+/// How often to forget stale messages from accounts which have not sent a message since
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A message which has been seen, which must be remembered until its timestamp is stale
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct SeenMessage {
+    /// Unix time in seconds after which the message would be rejected as stale anyway
+    expires: u64,
+    /// SHA256 hash of the message payload
+    hash: [u8; 32],
+}
+
+/// Per-account cache of recently seen message hashes
+#[derive(Debug, Default)]
+pub struct ReplayCache {
+    seen: Mutex<HashMap<[u8; 32], HashSet<SeenMessage>>>,
+}
+
+impl ReplayCache {
+    /// Record a message from the given account, returning an error if it has already been seen
+    /// or the account has too many recent messages. The message must already have passed the
+    /// staleness check.
+    pub async fn check(
+        &self,
+        account_id: &[u8; 32],
+        message: &[u8],
+        timestamp: u64,
+        current_timestamp: u64,
+    ) -> Result<(), Err> {
+        let seen_message = SeenMessage {
+            expires: timestamp + TIME_BUFFER,
+            hash: Sha256::digest(message).into(),
+        };
+
+        let mut seen = self.seen.lock().await;
+        let messages = seen.entry(*account_id).or_default();
+        // Only this account's messages are checked here, so that the time taken does not grow with
+        // the number of accounts. Other accounts are left to [ReplayCache::remove_stale].
+        messages.retain(|message| message.expires >= current_timestamp);
+        if messages.contains(&seen_message) {
+            return Err(Err::ReplayedMessage);
+        }
+        if messages.len() >= MAX_MESSAGES_PER_ACCOUNT {
+            return Err(Err::TooManyRecentMessages);
+        }
+        messages.insert(seen_message);
+        Ok(())
+    }
+
+    /// Forget messages from every account which would now be rejected as stale, returning how many
+    /// were forgotten
+    pub async fn remove_stale(&self, current_timestamp: u64) -> usize {
+        let mut seen = self.seen.lock().await;
+        let mut removed = 0;
+        seen.retain(|_, messages| {
+            let count = messages.len();
+            messages.retain(|message| message.expires >= current_timestamp);
+            removed += count - messages.len();
+            !messages.is_empty()
+        });
+        removed
+    }
+}
+
+/// Periodically forget stale messages from every account. This runs forever, so should be spawned
+/// as a task.
+pub async fn sweep_replay_cache(replay_cache: Arc<ReplayCache>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match get_current_timestamp() {
+            Ok(current_timestamp) => {
+                replay_cache.remove_stale(current_timestamp).await;
+            }
+            Err(error) => tracing::error!("Failed to forget stale messages: {error}"),
+        }
+    }
+}
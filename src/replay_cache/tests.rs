@@ -0,0 +1,81 @@
+use super::{MAX_MESSAGES_PER_ACCOUNT, ReplayCache};
+use crate::{api_keys::api::TIME_BUFFER, errors::Err};
+
+#[tokio::test]
+async fn test_replay_cache_rejects_duplicates() {
+    let cache = ReplayCache::default();
+    let alice = [1; 32];
+    let bob = [2; 32];
+
+    cache.check(&alice, b"message", 100, 100).await.unwrap();
+    assert!(matches!(
+        cache.check(&alice, b"message", 100, 101).await,
+        Err(Err::ReplayedMessage)
+    ));
+
+    // The same message from a different account is fine
+    cache.check(&bob, b"message", 100, 101).await.unwrap();
+    // As is a different message from the same account
+    cache
+        .check(&alice, b"other message", 100, 101)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_replay_cache_forgets_stale_messages() {
+    let cache = ReplayCache::default();
+    let alice = [1; 32];
+
+    cache.check(&alice, b"message", 100, 100).await.unwrap();
+    assert!(matches!(
+        cache
+            .check(&alice, b"message", 100, 100 + TIME_BUFFER)
+            .await,
+        Err(Err::ReplayedMessage)
+    ));
+
+    cache
+        .check(&alice, b"message", 100, 101 + TIME_BUFFER)
+        .await
+        .unwrap();
+    assert_eq!(cache.seen.lock().await[&alice].len(), 1);
+
+    // Stale messages of other accounts are only forgotten by a sweep
+    let bob = [2; 32];
+    cache.check(&bob, b"message", 120, 120).await.unwrap();
+    cache
+        .check(&alice, b"other message", 200, 200)
+        .await
+        .unwrap();
+    assert_eq!(cache.seen.lock().await[&bob].len(), 1);
+    assert_eq!(cache.remove_stale(200).await, 1);
+    assert!(!cache.seen.lock().await.contains_key(&bob));
+    assert_eq!(cache.seen.lock().await[&alice].len(), 1);
+}
+
+#[tokio::test]
+async fn test_replay_cache_is_bounded() {
+    let cache = ReplayCache::default();
+    let alice = [1; 32];
+
+    for i in 0..MAX_MESSAGES_PER_ACCOUNT {
+        cache
+            .check(&alice, &i.to_le_bytes(), 100, 100)
+            .await
+            .unwrap();
+    }
+    assert!(matches!(
+        cache.check(&alice, b"one too many", 100, 100).await,
+        Err(Err::TooManyRecentMessages)
+    ));
+
+    // Other accounts are not affected
+    cache.check(&[2; 32], b"message", 100, 100).await.unwrap();
+
+    // Once earlier messages are stale there is room again
+    cache
+        .check(&alice, b"one too many", 120, 101 + TIME_BUFFER)
+        .await
+        .unwrap();
+}
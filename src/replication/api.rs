@@ -0,0 +1,51 @@
+use super::{
+    ApiKeyChange, REPLICATION_CHANGES_ROUTE, ReplicationMessage, verify_replication_message,
+};
+use crate::{
+    AppState,
+    api_keys::api::{check_stale, get_current_timestamp},
+    attestation::{AttestedIdentity, IdentityRequest, attested_identity},
+    errors::Err,
+};
+use axum::{Json, extract::State};
+use zeroize::Zeroize;
+
+/// Returns the identity of this instance, with a quote bound to the given nonce
+pub async fn replication_identity(
+    State(app_state): State<AppState>,
+    Json(request): Json<IdentityRequest>,
+) -> Result<Json<AttestedIdentity>, Err> {
+    Ok(Json(attested_identity(&app_state, request.nonce).await?))
+}
+
+/// Applies changes to api keys sent by another instance running the same release, returning how
+/// many were applied
+pub async fn replication_changes(
+    State(app_state): State<AppState>,
+    Json(message): Json<ReplicationMessage>,
+) -> Result<Json<usize>, Err> {
+    check_stale(message.timestamp, get_current_timestamp()?).await?;
+    verify_replication_message(&app_state, &message).await?;
+
+    let mut signed_message = message.changes.decrypt(
+        &app_state.identity.x25519_secret,
+        &app_state.associated_data(REPLICATION_CHANGES_ROUTE),
+    )?;
+    let changes: Result<Vec<ApiKeyChange>, _> = serde_json::from_slice(&signed_message.message.0);
+    let sender = *signed_message.account_id().as_ref();
+    signed_message.message.0.zeroize();
+    if sender != message.sender.account_id {
+        return Err(Err::QuoteRejected(
+            "Changes were not signed by the attested sender".to_string(),
+        ));
+    }
+
+    let _update_guard = app_state.api_key_update_lock.lock().await;
+    let mut applied = 0;
+    for change in changes? {
+        if app_state.replica.apply(change).await? {
+            applied += 1;
+        }
+    }
+    Ok(Json(applied))
+}
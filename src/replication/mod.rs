@@ -0,0 +1,292 @@
+//! Replicating api keys between instances of the service, so that any of them can serve any user
+//!
+//! Instances find each other from the forest on chain, or from a configured list. Each
+//! periodically sends the changes to api keys which it has not yet sent to each peer, signed and
+//! encrypted to the peer's x25519 public key. Both sides check that the other's quote binds its
+//! identity and was made by the same release. Where two instances change the same api key, the
+//! later change wins.
+pub mod api;
+mod store;
+
+#[cfg(test)]
+mod tests;
+
+pub use store::{
+    ApiKeyChange, ChangeVersion, DELETION_RETENTION_MILLIS, ReplicatedApiKeyStore, change_log_path,
+    current_time_millis,
+};
+
+use crate::{
+    api_keys::api::get_current_timestamp,
+    app_state::AppState,
+    attestation::{
+        AttestedIdentity, IdentityRequest, attested_identity, create_identity_quote,
+        quote_measurement, timestamp_nonce, verify_identity_quote,
+    },
+    errors::Err,
+};
+use entropy_api_key_service_shared::associated_data;
+use entropy_client::chain_api::entropy;
+use entropy_protocol::sign_and_encrypt::EncryptedSignedMessage;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sp_core::Pair;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use zeroize::Zeroizing;
+
+pub const REPLICATION_IDENTITY_ROUTE: &str = "/replication/identity";
+pub const REPLICATION_CHANGES_ROUTE: &str = "/replication/changes";
+
+/// How often to send changes to peers
+pub const REPLICATION_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How often to look for peers on chain
+pub const PEER_DISCOVERY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Context of the nonce for the quote of the sender of changes
+const REPLICATION_NONCE_CONTEXT: &[u8] = b"entropy-api-key-service-replication";
+
+/// Changes to api keys sent from one instance to another
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplicationMessage {
+    /// Identity of the sender, with a quote bound to the timestamp
+    pub sender: AttestedIdentity,
+    /// Unix time in seconds at which the message was made
+    pub timestamp: u64,
+    /// The changes, signed by the sender and encrypted to the receiver
+    pub changes: EncryptedSignedMessage,
+}
+
+/// Get the measurement value of the release this instance is running, which peers must also be
+/// running
+pub async fn own_measurement(app_state: &AppState) -> Result<[u8; 32], Err> {
+    app_state
+        .measurement
+        .get_or_try_init(|| async {
+            quote_measurement(&create_identity_quote(app_state, [0; 32]).await?)
+        })
+        .await
+        .copied()
+}
+
+/// Check that the quote of the sender of changes binds its identity and was made by the same
+/// release as this instance
+pub async fn verify_replication_message(
+    app_state: &AppState,
+    message: &ReplicationMessage,
+) -> Result<(), Err> {
+    let sender = &message.sender;
+    verify_identity_quote(
+        &sender.tdx_quote,
+        sender.account_id,
+        sender.x25519_public_key,
+        timestamp_nonce(REPLICATION_NONCE_CONTEXT, message.timestamp),
+        &[own_measurement(app_state).await?],
+    )
+}
+
+/// What this instance knows about a peer
+#[derive(Default)]
+struct Peer {
+    /// Account ID and x25519 public key of the peer, once its quote has been checked
+    identity: Option<([u8; 32], [u8; 32])>,
+    /// Sequence number of the latest change the peer has been sent
+    sent_sequence: u64,
+}
+
+/// Sends changes to api keys to a set of peers, keeping track of which changes each has been sent
+#[derive(Default)]
+pub struct Replicator {
+    peers: HashMap<String, Peer>,
+    client: reqwest::Client,
+}
+
+impl Replicator {
+    /// Set the URLs of the peers to send changes to, forgetting any others
+    pub fn set_peers(&mut self, urls: Vec<String>) {
+        self.peers.retain(|url, _| urls.contains(url));
+        for url in urls {
+            self.peers.entry(url).or_default();
+        }
+    }
+
+    /// Send changes to every peer, returning how many were applied
+    pub async fn replicate(&mut self, app_state: &AppState) -> usize {
+        let mut applied = 0;
+        for (url, peer) in self.peers.iter_mut() {
+            match replicate_to_peer(&self.client, app_state, url, peer).await {
+                Ok(applied_by_peer) => applied += applied_by_peer,
+                Err(error) => {
+                    tracing::warn!("Failed to replicate api keys to {url}: {error}");
+                    // The peer may have restarted, so its quote is checked again and it is sent
+                    // all changes
+                    *peer = Peer::default();
+                }
+            }
+        }
+
+        // Deleted api keys need only be remembered until every other peer has been sent the
+        // deletion, and peers which are not currently known have had time to be sent it
+        let account_id = app_state.identity.pair.public().0;
+        let acknowledged_sequence = self
+            .peers
+            .values()
+            .filter(|peer| {
+                peer.identity
+                    .is_none_or(|(peer_id, _)| peer_id != account_id)
+            })
+            .map(|peer| peer.sent_sequence)
+            .min();
+        if let Some(acknowledged_sequence) = acknowledged_sequence
+            && let Err(error) = match current_time_millis() {
+                Ok(current_time) => {
+                    app_state
+                        .replica
+                        .prune_deleted(acknowledged_sequence, current_time)
+                        .await
+                }
+                Err(error) => Err(error),
+            }
+        {
+            tracing::error!("Failed to forget deleted api keys: {error}");
+        }
+        applied
+    }
+}
+
+/// Send a peer the changes it has not yet been sent, returning how many it applied
+async fn replicate_to_peer(
+    client: &reqwest::Client,
+    app_state: &AppState,
+    url: &str,
+    peer: &mut Peer,
+) -> Result<usize, Err> {
+    let (account_id, x25519_public_key) = match peer.identity {
+        Some(identity) => identity,
+        None => {
+            let mut nonce = [0; 32];
+            OsRng.fill_bytes(&mut nonce);
+            let identity: AttestedIdentity = client
+                .post(format!("{url}{REPLICATION_IDENTITY_ROUTE}"))
+                .json(&IdentityRequest { nonce })
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            verify_identity_quote(
+                &identity.tdx_quote,
+                identity.account_id,
+                identity.x25519_public_key,
+                nonce,
+                &[own_measurement(app_state).await?],
+            )?;
+            *peer
+                .identity
+                .insert((identity.account_id, identity.x25519_public_key))
+        }
+    };
+    if account_id == app_state.identity.pair.public().0 {
+        return Ok(0);
+    }
+
+    let (changes, latest_sequence) = app_state.replica.changes_since(peer.sent_sequence).await?;
+    if changes.is_empty() {
+        peer.sent_sequence = latest_sequence;
+        return Ok(0);
+    }
+
+    // The changes hold plaintext api keys, so they are serialized into a buffer which is wiped if
+    // encryption is not reached, and which is otherwise moved into the message without a copy
+    let mut payload = Zeroizing::new(Vec::new());
+    serde_json::to_writer(&mut *payload, &changes)?;
+    let timestamp = get_current_timestamp()?;
+    let message = ReplicationMessage {
+        sender: attested_identity(
+            app_state,
+            timestamp_nonce(REPLICATION_NONCE_CONTEXT, timestamp),
+        )
+        .await?,
+        timestamp,
+        changes: EncryptedSignedMessage::new(
+            &app_state.identity.pair,
+            std::mem::take(&mut *payload),
+            &x25519_public_key,
+            &associated_data(REPLICATION_CHANGES_ROUTE, &account_id),
+        )?,
+    };
+    let applied = client
+        .post(format!("{url}{REPLICATION_CHANGES_ROUTE}"))
+        .json(&message)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    peer.sent_sequence = latest_sequence;
+    Ok(applied)
+}
+
+/// Get the URLs of the other api key service instances registered in the forest on chain
+async fn chain_peers(app_state: &AppState) -> Result<Vec<String>, Err> {
+    let (api, rpc) = app_state.get_api_rpc().await?;
+    let block_hash = rpc
+        .chain_get_block_hash(None)
+        .await?
+        .ok_or(Err::BlockHash)?;
+    let storage_address = entropy::storage().forest().trees_iter();
+    let mut iter = api.storage().at(block_hash).iter(storage_address).await?;
+    let mut urls = Vec::new();
+    while let Some(Ok(kv)) = iter.next().await {
+        let account_id: [u8; 32] = kv.key_bytes[kv.key_bytes.len() - 32..].try_into()?;
+        if account_id == app_state.identity.pair.public().0 {
+            continue;
+        }
+        match String::from_utf8(kv.value.endpoint) {
+            // Endpoints are declared on chain without a scheme
+            Ok(endpoint) if endpoint.contains("://") => urls.push(endpoint),
+            Ok(endpoint) => urls.push(format!("http://{endpoint}")),
+            Err(_) => tracing::warn!("Ignoring peer with invalid endpoint"),
+        }
+    }
+    Ok(urls)
+}
+
+/// Get the URLs of peers given in the configuration and, if enabled, found on chain
+async fn discover_peers(app_state: &AppState) -> Result<Vec<String>, Err> {
+    let mut urls = app_state.configuration.replication_peers.clone();
+    if app_state.configuration.replicate_with_chain_peers {
+        urls.extend(chain_peers(app_state).await?);
+    }
+    Ok(urls)
+}
+
+/// Periodically send changes to api keys to peers. This runs forever, so should be spawned as a
+/// task.
+pub async fn replicate_api_keys(app_state: AppState) {
+    if let Err(error) = app_state.replica.record_existing().await {
+        tracing::error!("Failed to record existing api keys for replication: {error}");
+    }
+    let mut replicator = Replicator::default();
+    let mut last_discovery: Option<Instant> = None;
+    let mut interval = tokio::time::interval(REPLICATION_INTERVAL);
+    loop {
+        interval.tick().await;
+        if last_discovery.is_none_or(|instant| instant.elapsed() >= PEER_DISCOVERY_INTERVAL) {
+            match discover_peers(&app_state).await {
+                Ok(urls) => {
+                    replicator.set_peers(urls);
+                    last_discovery = Some(Instant::now());
+                }
+                Err(error) => tracing::error!("Failed to discover peers: {error}"),
+            }
+        }
+        let applied = replicator.replicate(&app_state).await;
+        if applied > 0 {
+            tracing::info!("Peers applied {applied} changes to api keys");
+        }
+    }
+}
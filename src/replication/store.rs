@@ -0,0 +1,345 @@
+use crate::{
+    api_key_store::{ApiKeyEntry, ApiKeyId, ApiKeyStore},
+    errors::Err,
+    sealing::{SealedFile, TamperPolicy},
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::Mutex;
+
+/// Milliseconds for which deleted api keys are remembered, even once every current peer has been
+/// sent the deletion, so that peers which are offline or not yet known are also sent it
+pub const DELETION_RETENTION_MILLIS: u64 = 7 * 24 * 60 * 60 * 1000;
+
+/// Get the current unix time in milliseconds, which change versions are given in
+pub fn current_time_millis() -> Result<u64, Err> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64)
+}
+
+/// Get the path of the sealed file the change log is persisted to, next to the file api keys are
+/// persisted to
+pub fn change_log_path(storage_path: &Path) -> PathBuf {
+    storage_path.with_extension("changes.sealed")
+}
+
+/// Orders changes to the same api key made on different instances. Later changes win, with ties
+/// broken by the account ID of the instance which made them
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ChangeVersion {
+    /// Unix time in milliseconds at which the change was made
+    pub updated_at: u64,
+    /// Account ID of the instance which made the change
+    pub origin: [u8; 32],
+}
+
+/// A change to an api key, as sent between instances
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ApiKeyChange {
+    pub id: ApiKeyId,
+    pub version: ChangeVersion,
+    /// The api key after the change, or `None` if it was deleted
+    pub entry: Option<ApiKeyEntry>,
+}
+
+/// The latest change to an api key
+#[derive(Serialize, Deserialize, Clone)]
+struct ChangeRecord {
+    version: ChangeVersion,
+    /// Position of the change in the order in which changes were made or applied on this instance
+    sequence: u64,
+    deleted: bool,
+}
+
+#[derive(Default, Clone)]
+struct ChangeLog {
+    records: HashMap<ApiKeyId, ChangeRecord>,
+    /// Sequence number of the latest change
+    sequence: u64,
+}
+
+/// The form in which the change log is persisted
+#[derive(Serialize, Deserialize)]
+struct PersistedChangeLog {
+    records: Vec<(ApiKeyId, ChangeRecord)>,
+    sequence: u64,
+}
+
+impl ChangeLog {
+    fn version(&self, id: &ApiKeyId) -> ChangeVersion {
+        self.records
+            .get(id)
+            .map(|record| record.version)
+            .unwrap_or_default()
+    }
+
+    fn record(&mut self, id: ApiKeyId, version: ChangeVersion, deleted: bool) {
+        self.sequence += 1;
+        self.records.insert(
+            id,
+            ChangeRecord {
+                version,
+                sequence: self.sequence,
+                deleted,
+            },
+        );
+    }
+}
+
+/// Api key storage which wraps another store, recording the version of each change so that changes
+/// can be sent to and applied from other instances. Deleted api keys are remembered until every
+/// peer has been sent the deletion and [DELETION_RETENTION_MILLIS] has passed, so that an earlier
+/// change from another instance cannot restore them.
+///
+/// When the wrapped store persists api keys, the change log is persisted alongside it to a sealed
+/// file, so that versions and deletions are not lost on restart.
+pub struct ReplicatedApiKeyStore {
+    api_keys: Arc<dyn ApiKeyStore>,
+    /// Account ID of this instance
+    account_id: [u8; 32],
+    change_log: Mutex<ChangeLog>,
+    /// Where the change log is persisted, if it is
+    sealed_file: Option<Arc<SealedFile>>,
+}
+
+impl ReplicatedApiKeyStore {
+    /// Wrap a store whose api keys are not persisted, so neither is the change log
+    pub fn new(api_keys: Arc<dyn ApiKeyStore>, account_id: [u8; 32]) -> Self {
+        Self {
+            api_keys,
+            account_id,
+            change_log: Default::default(),
+            sealed_file: None,
+        }
+    }
+
+    /// Wrap a store whose api keys are persisted, loading any change log which was previously
+    /// persisted to the given file
+    pub fn open(
+        api_keys: Arc<dyn ApiKeyStore>,
+        account_id: [u8; 32],
+        sealed_file: SealedFile,
+        tamper_policy: TamperPolicy,
+    ) -> Result<Self, Err> {
+        let change_log = match sealed_file.load() {
+            Ok(Some(plaintext)) => {
+                let persisted: PersistedChangeLog = serde_json::from_slice(&plaintext)?;
+                ChangeLog {
+                    records: persisted.records.into_iter().collect(),
+                    sequence: persisted.sequence,
+                }
+            }
+            Ok(None) => Default::default(),
+            Err(Err::SealedDataTampered) if tamper_policy == TamperPolicy::StartEmpty => {
+                tracing::error!("Persisted change log failed authentication - starting with none");
+                Default::default()
+            }
+            Err(error) => return Err(error),
+        };
+        Ok(Self {
+            api_keys,
+            account_id,
+            change_log: Mutex::new(change_log),
+            sealed_file: Some(Arc::new(sealed_file)),
+        })
+    }
+
+    /// Seal and write the given change log to the file, if it is persisted. This is done on a
+    /// blocking thread, as it waits for the file to reach the disk.
+    async fn persist(&self, change_log: &ChangeLog) -> Result<(), Err> {
+        let Some(sealed_file) = self.sealed_file.clone() else {
+            return Ok(());
+        };
+        let persisted = PersistedChangeLog {
+            records: change_log
+                .records
+                .iter()
+                .map(|(id, record)| (id.clone(), record.clone()))
+                .collect(),
+            sequence: change_log.sequence,
+        };
+        let plaintext = serde_json::to_vec(&persisted)?;
+        tokio::task::spawn_blocking(move || sealed_file.store(&plaintext)).await?
+    }
+
+    /// Make a change to the underlying store and record it. The change log is persisted before
+    /// the store is changed, so that a change which reaches the store is never missing from the
+    /// persisted log, and is only changed in memory once both have succeeded.
+    async fn change(
+        &self,
+        change_log: &mut ChangeLog,
+        id: ApiKeyId,
+        version: ChangeVersion,
+        entry: Option<ApiKeyEntry>,
+    ) -> Result<(), Err> {
+        let mut updated = change_log.clone();
+        updated.record(id.clone(), version, entry.is_none());
+        self.persist(&updated).await?;
+        match entry {
+            Some(entry) => self.api_keys.put(id, entry).await?,
+            None => self.api_keys.delete(&id).await?,
+        }
+        *change_log = updated;
+        Ok(())
+    }
+
+    /// Get the version of a change made by this instance now, which must be later than the
+    /// existing version
+    fn local_version(&self, existing: ChangeVersion) -> Result<ChangeVersion, Err> {
+        let now = current_time_millis()?;
+        Ok(ChangeVersion {
+            updated_at: now.max(existing.updated_at + 1),
+            origin: self.account_id,
+        })
+    }
+
+    /// Record api keys which are in the underlying store but not in the change log, such as those
+    /// persisted before the change log was, so that they are sent to other instances. As the time
+    /// they were changed is not known they are given the earliest version, so that any change made
+    /// on another instance wins.
+    pub async fn record_existing(&self) -> Result<(), Err> {
+        let mut change_log = self.change_log.lock().await;
+        let mut updated = change_log.clone();
+        for (id, _) in self.api_keys.list_all().await? {
+            if !updated.records.contains_key(&id) {
+                let version = ChangeVersion {
+                    updated_at: 0,
+                    origin: self.account_id,
+                };
+                updated.record(id, version, false);
+            }
+        }
+        if updated.sequence != change_log.sequence {
+            self.persist(&updated).await?;
+            *change_log = updated;
+        }
+        Ok(())
+    }
+
+    /// Forget deleted api keys whose deletion every peer has been sent, and which were deleted at
+    /// least [DELETION_RETENTION_MILLIS] before the given unix time in milliseconds. Takes the
+    /// lowest sequence number of the changes the peers have been sent, and returns how many were
+    /// forgotten.
+    pub async fn prune_deleted(
+        &self,
+        acknowledged_sequence: u64,
+        current_time: u64,
+    ) -> Result<usize, Err> {
+        let mut change_log = self.change_log.lock().await;
+        let mut updated = change_log.clone();
+        updated.records.retain(|_, record| {
+            !record.deleted
+                || record.sequence > acknowledged_sequence
+                || current_time.saturating_sub(record.version.updated_at)
+                    < DELETION_RETENTION_MILLIS
+        });
+        let pruned = change_log.records.len() - updated.records.len();
+        if pruned > 0 {
+            self.persist(&updated).await?;
+            *change_log = updated;
+        }
+        Ok(pruned)
+    }
+
+    /// Delete an api key from this instance only, without recording a change, so that other
+    /// instances keep their copy
+    pub async fn delete_locally(&self, id: &ApiKeyId) -> Result<(), Err> {
+        self.api_keys.delete(id).await
+    }
+
+    /// Get the changes made or applied after the given sequence number, and the sequence number of
+    /// the latest change
+    pub async fn changes_since(&self, sequence: u64) -> Result<(Vec<ApiKeyChange>, u64), Err> {
+        let change_log = self.change_log.lock().await;
+        let mut changes = Vec::new();
+        for (id, record) in &change_log.records {
+            if record.sequence <= sequence {
+                continue;
+            }
+            let entry = if record.deleted {
+                None
+            } else {
+                match self.api_keys.get(id).await? {
+                    Some(entry) => Some(entry),
+                    // Expired api keys are removed by every instance, so need not be sent
+                    None => continue,
+                }
+            };
+            changes.push(ApiKeyChange {
+                id: id.clone(),
+                version: record.version,
+                entry,
+            });
+        }
+        Ok((changes, change_log.sequence))
+    }
+
+    /// Apply a change from another instance if it is later than the latest change we have,
+    /// returning whether it was applied
+    pub async fn apply(&self, change: ApiKeyChange) -> Result<bool, Err> {
+        let mut change_log = self.change_log.lock().await;
+        if change.version <= change_log.version(&change.id) {
+            return Ok(false);
+        }
+        self.change(&mut change_log, change.id, change.version, change.entry)
+            .await?;
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl ApiKeyStore for ReplicatedApiKeyStore {
+    async fn get(&self, id: &ApiKeyId) -> Result<Option<ApiKeyEntry>, Err> {
+        self.api_keys.get(id).await
+    }
+
+    async fn put(&self, id: ApiKeyId, entry: ApiKeyEntry) -> Result<(), Err> {
+        let mut change_log = self.change_log.lock().await;
+        let version = self.local_version(change_log.version(&id))?;
+        self.change(&mut change_log, id, version, Some(entry)).await
+    }
+
+    async fn delete(&self, id: &ApiKeyId) -> Result<(), Err> {
+        let mut change_log = self.change_log.lock().await;
+        let version = self.local_version(change_log.version(id))?;
+        self.change(&mut change_log, id.clone(), version, None)
+            .await
+    }
+
+    async fn list(&self, account_id: &[u8; 32]) -> Result<Vec<(ApiKeyId, ApiKeyEntry)>, Err> {
+        self.api_keys.list(account_id).await
+    }
+
+    async fn list_all(&self) -> Result<Vec<(ApiKeyId, ApiKeyEntry)>, Err> {
+        self.api_keys.list_all().await
+    }
+
+    async fn delete_expired(&self, current_timestamp: u64) -> Result<usize, Err> {
+        // Every instance removes expired api keys itself, so this is not recorded as a change
+        self.api_keys.delete_expired(current_timestamp).await
+    }
+
+    async fn list_due_for_rotation(&self, current_timestamp: u64) -> Result<Vec<ApiKeyId>, Err> {
+        let due = self
+            .api_keys
+            .list_due_for_rotation(current_timestamp)
+            .await?;
+        let change_log = self.change_log.lock().await;
+        // Only the instance which last changed an api key rotates it, so that it is not rotated by
+        // several instances at once
+        Ok(due
+            .into_iter()
+            .filter(|id| {
+                change_log
+                    .records
+                    .get(id)
+                    .is_none_or(|record| record.version.origin == self.account_id)
+            })
+            .collect())
+    }
+}
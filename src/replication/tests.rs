@@ -0,0 +1,333 @@
+use super::{
+    ApiKeyChange, ChangeVersion, DELETION_RETENTION_MILLIS, REPLICATION_IDENTITY_ROUTE,
+    ReplicatedApiKeyStore, Replicator,
+};
+use crate::{
+    api_key_store::{
+        ApiKeyEntry, ApiKeyId, ApiKeyStore, ApiKeyVersion, FileApiKeyStore, InMemoryApiKeyStore,
+        KeyRotation,
+    },
+    app_state::Configuration,
+    sealing::{SealedFile, TamperPolicy, get_sealing_key},
+    test_helpers::{
+        DEFAULT_ENDPOINT, make_test_client, make_test_client_for, setup_client, start_service,
+        temporary_path,
+    },
+};
+use entropy_api_key_service_shared::{HttpMethod, RotationRecipe, RotationRequest};
+use serial_test::serial;
+use sp_keyring::sr25519::Keyring;
+use std::sync::Arc;
+
+fn replicated_store(account_id: [u8; 32]) -> ReplicatedApiKeyStore {
+    ReplicatedApiKeyStore::new(Arc::new(InMemoryApiKeyStore::default()), account_id)
+}
+
+fn api_key_id() -> ApiKeyId {
+    ApiKeyId {
+        account_id: [1; 32],
+        service: "https://api.example.com/".to_string(),
+        secret_name: "default".to_string(),
+    }
+}
+
+fn api_key_entry(api_key: &str) -> ApiKeyEntry {
+    ApiKeyEntry::new(ApiKeyVersion {
+        version: 1,
+        api_key: api_key.to_string().into(),
+        deployed_at: 1000,
+        expires_at: None,
+    })
+}
+
+/// Applies all of the changes from one store to another, returning how many were applied
+async fn sync(from: &ReplicatedApiKeyStore, to: &ReplicatedApiKeyStore) -> usize {
+    let (changes, _) = from.changes_since(0).await.unwrap();
+    let mut applied = 0;
+    for change in changes {
+        if to.apply(change).await.unwrap() {
+            applied += 1;
+        }
+    }
+    applied
+}
+
+#[tokio::test]
+async fn test_later_changes_win() {
+    let one = replicated_store([1; 32]);
+    let two = replicated_store([2; 32]);
+    let id = api_key_id();
+
+    one.put(id.clone(), api_key_entry("first")).await.unwrap();
+    assert_eq!(sync(&one, &two).await, 1);
+    assert_eq!(two.get(&id).await.unwrap(), Some(api_key_entry("first")));
+    // Changes which have already been applied are ignored
+    assert_eq!(sync(&one, &two).await, 0);
+
+    two.put(id.clone(), api_key_entry("second")).await.unwrap();
+    assert_eq!(sync(&two, &one).await, 1);
+    assert_eq!(one.get(&id).await.unwrap(), Some(api_key_entry("second")));
+    // An earlier change does not replace a later one
+    let earlier_change = ApiKeyChange {
+        id: id.clone(),
+        version: ChangeVersion {
+            updated_at: 1,
+            origin: [1; 32],
+        },
+        entry: Some(api_key_entry("first")),
+    };
+    assert!(!one.apply(earlier_change.clone()).await.unwrap());
+    assert_eq!(one.get(&id).await.unwrap(), Some(api_key_entry("second")));
+
+    // Deletion is replicated, and an earlier change cannot restore the api key
+    one.delete(&id).await.unwrap();
+    assert_eq!(sync(&one, &two).await, 1);
+    assert!(two.get(&id).await.unwrap().is_none());
+    assert!(!two.apply(earlier_change).await.unwrap());
+    assert!(two.get(&id).await.unwrap().is_none());
+
+    // Changes made at the same time are ordered by the instance which made them
+    let at_the_same_time = |origin, api_key| ApiKeyChange {
+        id: id.clone(),
+        version: ChangeVersion {
+            updated_at: u64::MAX,
+            origin,
+        },
+        entry: Some(api_key_entry(api_key)),
+    };
+    assert!(
+        two.apply(at_the_same_time([2; 32], "from-two"))
+            .await
+            .unwrap()
+    );
+    assert!(
+        !two.apply(at_the_same_time([1; 32], "from-one"))
+            .await
+            .unwrap()
+    );
+    assert!(
+        one.apply(at_the_same_time([1; 32], "from-one"))
+            .await
+            .unwrap()
+    );
+    assert!(
+        one.apply(at_the_same_time([2; 32], "from-two"))
+            .await
+            .unwrap()
+    );
+    assert_eq!(one.get(&id).await.unwrap(), two.get(&id).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_change_log_is_persisted() {
+    let storage_path = temporary_path("replicated-api-keys");
+    let change_log_path = temporary_path("replicated-api-keys-changes");
+    let open = || {
+        let api_keys = FileApiKeyStore::open(
+            SealedFile::new(storage_path.clone(), get_sealing_key().unwrap()),
+            TamperPolicy::Refuse,
+        )
+        .unwrap();
+        ReplicatedApiKeyStore::open(
+            Arc::new(api_keys),
+            [1; 32],
+            SealedFile::new(change_log_path.clone(), get_sealing_key().unwrap()),
+            TamperPolicy::Refuse,
+        )
+        .unwrap()
+    };
+    let id = api_key_id();
+    let other_id = ApiKeyId {
+        secret_name: "other".to_string(),
+        ..api_key_id()
+    };
+
+    let store = open();
+    store.put(id.clone(), api_key_entry("first")).await.unwrap();
+    store
+        .put(other_id.clone(), api_key_entry("other"))
+        .await
+        .unwrap();
+    store.delete(&other_id).await.unwrap();
+    let (changes, sequence) = store.changes_since(0).await.unwrap();
+    drop(store);
+
+    // Versions and deletions are the same after reopening
+    let store = open();
+    store.record_existing().await.unwrap();
+    let (reopened_changes, reopened_sequence) = store.changes_since(0).await.unwrap();
+    assert_eq!(reopened_sequence, sequence);
+    assert_eq!(reopened_changes.len(), changes.len());
+    for change in changes {
+        assert!(reopened_changes.contains(&change));
+    }
+    let earlier_change = ApiKeyChange {
+        id: other_id,
+        version: ChangeVersion {
+            updated_at: 1,
+            origin: [2; 32],
+        },
+        entry: Some(api_key_entry("other")),
+    };
+    assert!(!store.apply(earlier_change).await.unwrap());
+
+    std::fs::remove_file(storage_path).unwrap();
+    std::fs::remove_file(change_log_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_change_log_is_persisted_before_api_keys_are_changed() {
+    let api_keys = Arc::new(InMemoryApiKeyStore::default());
+    // The directory does not exist, so the change log cannot be written
+    let change_log_path = temporary_path("missing-directory").join("api-keys.changes.sealed");
+    let store = ReplicatedApiKeyStore::open(
+        api_keys.clone(),
+        [1; 32],
+        SealedFile::new(change_log_path, get_sealing_key().unwrap()),
+        TamperPolicy::Refuse,
+    )
+    .unwrap();
+    let id = api_key_id();
+
+    assert!(store.put(id.clone(), api_key_entry("first")).await.is_err());
+    assert!(api_keys.get(&id).await.unwrap().is_none());
+    assert!(store.changes_since(0).await.unwrap().0.is_empty());
+}
+
+#[tokio::test]
+async fn test_deleted_api_keys_are_forgotten_once_acknowledged() {
+    let store = replicated_store([1; 32]);
+    let id = api_key_id();
+    store.put(id.clone(), api_key_entry("first")).await.unwrap();
+    store.delete(&id).await.unwrap();
+    let (changes, sequence) = store.changes_since(0).await.unwrap();
+    let retained_until = changes[0].version.updated_at + DELETION_RETENTION_MILLIS;
+
+    // Not every peer has been sent the deletion
+    assert_eq!(
+        store
+            .prune_deleted(sequence - 1, retained_until)
+            .await
+            .unwrap(),
+        0
+    );
+    // Every current peer has been sent it, but others may not yet be known
+    assert_eq!(
+        store
+            .prune_deleted(sequence, retained_until - 1000)
+            .await
+            .unwrap(),
+        0
+    );
+    assert_eq!(store.changes_since(0).await.unwrap().0.len(), 1);
+
+    assert_eq!(
+        store.prune_deleted(sequence, retained_until).await.unwrap(),
+        1
+    );
+    assert!(store.changes_since(0).await.unwrap().0.is_empty());
+}
+
+#[tokio::test]
+async fn test_only_last_instance_to_change_an_api_key_rotates_it() {
+    let one = replicated_store([1; 32]);
+    let two = replicated_store([2; 32]);
+    let id = api_key_id();
+
+    let mut entry = api_key_entry("some-secret");
+    entry.rotation = Some(KeyRotation {
+        recipe: RotationRecipe {
+            interval: 3600,
+            create: RotationRequest {
+                http_verb: HttpMethod::Post,
+                api_url: "https://api.example.com/keys".to_string(),
+                http_headers: vec![],
+                request_body: String::new(),
+            },
+            new_key_pointer: "/key".to_string(),
+            revoke: None,
+        },
+        next_rotation_at: 2000,
+    });
+    one.put(id.clone(), entry).await.unwrap();
+    sync(&one, &two).await;
+
+    assert_eq!(
+        one.list_due_for_rotation(2000).await.unwrap(),
+        vec![id.clone()]
+    );
+    assert!(two.list_due_for_rotation(2000).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+#[serial]
+async fn test_replicate_api_keys_between_instances() {
+    let one_url = "http://127.0.0.1:3001";
+    let two_url = "http://127.0.0.1:3003";
+    let one = start_service(
+        Configuration {
+            replication_peers: vec![two_url.to_string()],
+            ..Configuration::new(DEFAULT_ENDPOINT.to_string())
+        },
+        "0.0.0.0:3001",
+    )
+    .await;
+    let two = start_service(
+        Configuration {
+            replication_peers: vec![one_url.to_string()],
+            ..Configuration::new(DEFAULT_ENDPOINT.to_string())
+        },
+        "0.0.0.0:3003",
+    )
+    .await;
+
+    make_test_client(&one, &Keyring::One)
+        .deploy_api_key(
+            "some-secret".to_string(),
+            "https://api.example.com".to_string(),
+        )
+        .await
+        .unwrap();
+
+    let mut replicator = Replicator::default();
+    replicator.set_peers(vec![two_url.to_string()]);
+    assert_eq!(replicator.replicate(&one).await, 1);
+    // Changes are only sent once
+    assert_eq!(replicator.replicate(&one).await, 0);
+
+    let client_of_two = make_test_client_for(two_url, &two, &Keyring::One);
+    let api_keys = client_of_two.list().await.unwrap();
+    assert_eq!(
+        api_keys,
+        make_test_client(&one, &Keyring::One).list().await.unwrap()
+    );
+
+    // Changes made on the other instance are replicated back
+    client_of_two
+        .delete_api_key("https://api.example.com".to_string())
+        .await
+        .unwrap();
+    let mut replicator = Replicator::default();
+    replicator.set_peers(vec![one_url.to_string()]);
+    assert_eq!(replicator.replicate(&two).await, 1);
+    assert!(
+        make_test_client(&one, &Keyring::One)
+            .list()
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+#[serial]
+async fn test_replication_routes_are_only_served_when_replicating() {
+    let _app_state = setup_client().await;
+    let response = reqwest::Client::new()
+        .post(format!("http://127.0.0.1:3001{REPLICATION_IDENTITY_ROUTE}"))
+        .json(&serde_json::json!({ "nonce": [0; 32] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,54 @@
+//! Responses which are encrypted to the caller and signed by this service
+use crate::{app_state::AppState, errors::Err};
+use axum::{
+    Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use entropy_protocol::sign_and_encrypt::{EncryptedSignedMessage, SignedMessage};
+use serde::Serialize;
+
+/// An HTTP response whose body is encrypted to the caller and signed by this service
+pub struct EncryptedResponse {
+    status: StatusCode,
+    message: EncryptedSignedMessage,
+}
+
+impl IntoResponse for EncryptedResponse {
+    fn into_response(self) -> Response {
+        (self.status, Json(self.message)).into_response()
+    }
+}
+
+impl AppState {
+    /// Encrypt the result of handling a request to the response key given in that request, and
+    /// sign it. On success the payload is the JSON encoded output, and on failure it is a JSON
+    /// encoded [entropy_api_key_service_shared::ErrorResponse]. Errors which occur before the
+    /// request can be decrypted cannot be encrypted, so the client must treat those as
+    /// unauthenticated.
+    pub fn encrypt_response<T: Serialize>(
+        &self,
+        request: &SignedMessage,
+        result: Result<T, Err>,
+        associated_data: &[u8],
+    ) -> Result<EncryptedResponse, Err> {
+        let receiver_x25519 = request.receiver_x25519.ok_or(Err::NoResponseKey)?;
+        let (status, payload) = match result {
+            Ok(output) => (StatusCode::OK, serde_json::to_vec(&output)?),
+            Err(error) => {
+                tracing::error!("{:?}", format!("{error}"));
+                (
+                    error.status_code(),
+                    serde_json::to_vec(&error.error_response())?,
+                )
+            }
+        };
+        let message = EncryptedSignedMessage::new(
+            &self.identity.pair,
+            payload,
+            &receiver_x25519,
+            associated_data,
+        )?;
+        Ok(EncryptedResponse { status, message })
+    }
+}
@@ -0,0 +1,166 @@
+//! Encryption of data persisted to disk, using a key which is bound to this CVM
+use crate::errors::Err;
+use chacha20poly1305::{
+    XChaCha20Poly1305, XNonce,
+    aead::{Aead, AeadCore, KeyInit},
+};
+use hkdf::Hkdf;
+use rand_core::OsRng;
+use serde::Deserialize;
+use sha2::Sha256;
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+use zeroize::Zeroizing;
+
+#[cfg(test)]
+mod tests;
+
+/// Length of the random nonce which is prepended to sealed data
+const NONCE_LENGTH: usize = 24;
+
+/// HKDF info string used when deriving the sealing key
+const SEALING_KEY_INFO: &[u8] = b"entropy-api-key-service-sealing-key";
+
+/// Location of the platform secret which is provisioned to the CVM at boot. This is combined with
+/// the measurement value of the running image, so that data sealed by one release cannot be
+/// unsealed by another.
+///
+/// TDX has no sealing key of its own, so this must be written by the boot process of the CVM image
+/// from a key broker which only releases it after remote attestation of the image. It must never
+/// come from the host, which could then unseal persisted data.
+#[cfg(feature = "production")]
+const PLATFORM_SECRET_PATH: &str = "/run/entropy/platform-secret";
+
+/// Shortest platform secret which will be accepted
+#[cfg(feature = "production")]
+const MIN_PLATFORM_SECRET_LENGTH: usize = 32;
+
+/// Input key material used to derive a mock sealing key when not in production
+#[cfg(not(feature = "production"))]
+const MOCK_PLATFORM_SECRET: &[u8] = b"entropy-api-key-service-mock-platform-secret";
+
+/// What to do if sealed data on disk fails authentication when loading
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum TamperPolicy {
+    /// Refuse to start
+    #[default]
+    Refuse,
+    /// Log an error and start with empty storage
+    StartEmpty,
+}
+
+/// A 32 byte symmetric key used to seal data
+pub struct SealingKey(Zeroizing<[u8; 32]>);
+
+impl SealingKey {
+    /// Derive a sealing key from input key material and the measurement value
+    fn derive(input_key_material: &[u8], measurement: &[u8]) -> Result<Self, Err> {
+        let hkdf = Hkdf::<Sha256>::new(Some(measurement), input_key_material);
+        let mut key = Zeroizing::new([0u8; 32]);
+        hkdf.expand(SEALING_KEY_INFO, key.as_mut())
+            .map_err(|_| Err::Sealing("Cannot derive sealing key".to_string()))?;
+        Ok(Self(key))
+    }
+
+    /// Encrypt the given plaintext, prepending a random nonce
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, Err> {
+        let cipher = XChaCha20Poly1305::new(self.0.as_ref().into());
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| Err::Sealing("Cannot encrypt data".to_string()))?;
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    /// Decrypt and authenticate data produced by [SealingKey::seal]
+    pub fn unseal(&self, sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>, Err> {
+        if sealed.len() < NONCE_LENGTH {
+            return Err(Err::SealedDataTampered);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+        let cipher = XChaCha20Poly1305::new(self.0.as_ref().into());
+        let plaintext = cipher
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| Err::SealedDataTampered)?;
+        Ok(Zeroizing::new(plaintext))
+    }
+}
+
+/// Get the sealing key for this CVM, which is derived from a platform secret and bound to the
+/// measurement value of the running image
+#[cfg(feature = "production")]
+pub fn get_sealing_key() -> Result<SealingKey, Err> {
+    let platform_secret = Zeroizing::new(std::fs::read(PLATFORM_SECRET_PATH).map_err(|e| {
+        Err::Sealing(format!(
+            "Cannot read platform secret from {PLATFORM_SECRET_PATH}: {e}"
+        ))
+    })?);
+    if platform_secret.len() < MIN_PLATFORM_SECRET_LENGTH {
+        return Err(Err::Sealing(format!(
+            "Platform secret must be at least {MIN_PLATFORM_SECRET_LENGTH} bytes"
+        )));
+    }
+    let measurement = entropy_client::attestation::get_measurement_value()
+        .map_err(|e| Err::Sealing(format!("Cannot get measurement value: {e:?}")))?;
+    SealingKey::derive(&platform_secret, &measurement)
+}
+
+/// Get a deterministic mock sealing key for testing
+#[cfg(not(feature = "production"))]
+pub fn get_sealing_key() -> Result<SealingKey, Err> {
+    SealingKey::derive(MOCK_PLATFORM_SECRET, &[0; 32])
+}
+
+/// A file on disk containing sealed data
+pub struct SealedFile {
+    path: PathBuf,
+    key: SealingKey,
+}
+
+impl SealedFile {
+    pub fn new(path: PathBuf, key: SealingKey) -> Self {
+        Self { path, key }
+    }
+
+    /// Read and unseal the file, returning `None` if it does not exist yet
+    pub fn load(&self) -> Result<Option<Zeroizing<Vec<u8>>>, Err> {
+        match std::fs::read(&self.path) {
+            Ok(sealed) => Ok(Some(self.key.unseal(&sealed)?)),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Seal and write the given data, replacing the file atomically. This only returns once the
+    /// new file has reached the disk, so that a crash cannot leave an empty or truncated file which
+    /// would fail authentication.
+    pub fn store(&self, plaintext: &[u8]) -> Result<(), Err> {
+        let sealed = self.key.seal(plaintext)?;
+        let mut temporary_path = self.path.clone().into_os_string();
+        temporary_path.push(".tmp");
+        let mut temporary_file = File::create(&temporary_path)?;
+        temporary_file.write_all(&sealed)?;
+        temporary_file.sync_all()?;
+        let directory = self.directory();
+        sync_directory(directory)?;
+        std::fs::rename(&temporary_path, &self.path)?;
+        sync_directory(directory)
+    }
+
+    /// The directory containing the file
+    fn directory(&self) -> &Path {
+        match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        }
+    }
+}
+
+/// Flush changes to the entries of a directory, such as a rename, to disk
+fn sync_directory(directory: &Path) -> Result<(), Err> {
+    File::open(directory)?.sync_all()?;
+    Ok(())
+}
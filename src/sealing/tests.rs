@@ -0,0 +1,38 @@
+use super::{SealedFile, get_sealing_key};
+use crate::{errors::Err, test_helpers::temporary_path};
+
+#[test]
+fn test_seal_and_unseal() {
+    let key = get_sealing_key().unwrap();
+    let sealed = key.seal(b"some secret").unwrap();
+    assert_eq!(key.unseal(&sealed).unwrap().as_slice(), b"some secret");
+
+    let mut tampered = sealed.clone();
+    let last = tampered.len() - 1;
+    tampered[last] ^= 1;
+    assert!(matches!(
+        key.unseal(&tampered),
+        Err(Err::SealedDataTampered)
+    ));
+    assert!(matches!(key.unseal(&[0; 10]), Err(Err::SealedDataTampered)));
+}
+
+#[test]
+fn test_sealed_file() {
+    let path = temporary_path("sealed-file");
+    let sealed_file = SealedFile::new(path.clone(), get_sealing_key().unwrap());
+    assert!(sealed_file.load().unwrap().is_none());
+
+    sealed_file.store(b"some secret").unwrap();
+    assert_ne!(std::fs::read(&path).unwrap(), b"some secret");
+    assert_eq!(
+        sealed_file.load().unwrap().unwrap().as_slice(),
+        b"some secret"
+    );
+    // The temporary file is renamed over the sealed file
+    let mut temporary_path = path.clone().into_os_string();
+    temporary_path.push(".tmp");
+    assert!(!std::path::Path::new(&temporary_path).exists());
+
+    std::fs::remove_file(path).unwrap();
+}
@@ -2,45 +2,88 @@
 mod test_server;
 
 use crate::{
+    api_keys::egress::EgressPolicy,
     app,
     app_state::{AppState, Configuration},
+    identity::ServiceIdentity,
 };
 use entropy_api_key_service_client::ApiKeyServiceClient;
-use rand_core::OsRng;
+use rand_core::{OsRng, RngCore};
 use sp_core::{Pair, sr25519};
 use sp_keyring::sr25519::Keyring;
+use std::path::PathBuf;
 use test_server::start_test_api_server;
+pub use test_server::{
+    INITIAL_ROTATING_API_KEY, OTHER_ORIGIN, ROTATING_SLOW_CREATE_DELAY,
+    VALID_API_KEY_WITH_SPECIAL_CHARACTERS,
+};
 use x25519_dalek::StaticSecret;
 
 pub const DEFAULT_ENDPOINT: &str = "ws://localhost:9944";
 
 pub async fn setup_client() -> AppState {
-    let configuration = Configuration::new(DEFAULT_ENDPOINT.to_string());
+    let mut configuration = Configuration::new(DEFAULT_ENDPOINT.to_string());
+    configuration.egress_policy = EgressPolicy {
+        allowed: vec![
+            // The test API server
+            "127.0.0.1:3002".parse().unwrap(),
+            "127.0.0.1:3004".parse().unwrap(),
+            // Nothing listens here, for testing unreachable upstream servers
+            "127.0.0.1:3009".parse().unwrap(),
+        ],
+    };
+    let app_state = start_service(configuration, "0.0.0.0:3001").await;
+
+    // Now start a server to test API calls with
+    start_test_api_server().await;
+
+    app_state
+}
 
+/// Starts an instance of the service with a new identity, listening on the given address
+pub async fn start_service(configuration: Configuration, address: &str) -> AppState {
     let (pair, _seed) = sr25519::Pair::generate();
     let x25519_secret = StaticSecret::random_from_rng(OsRng);
 
-    let app_state = AppState::new(configuration, pair, x25519_secret);
+    let identity = ServiceIdentity {
+        pair,
+        x25519_secret,
+    };
+    let app_state = AppState::new(configuration, identity).unwrap();
     let app = app(app_state.clone()).into_make_service();
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3001")
+    let listener = tokio::net::TcpListener::bind(address)
         .await
         .expect("Unable to bind to given server address.");
     tokio::spawn(async move {
         axum::serve(listener, app).await.unwrap();
     });
 
-    // Now start a server to test API calls with
-    start_test_api_server().await;
-
     app_state
 }
 
 /// Returns a client for the test server
 pub fn make_test_client(app_state: &AppState, keyring: &Keyring) -> ApiKeyServiceClient {
+    make_test_client_for("http://127.0.0.1:3001", app_state, keyring)
+}
+
+/// Returns a client for an instance of the service at the given URL
+pub fn make_test_client_for(
+    url: &str,
+    app_state: &AppState,
+    keyring: &Keyring,
+) -> ApiKeyServiceClient {
     ApiKeyServiceClient::new(
-        "http://127.0.0.1:3001".to_string(),
+        url.to_string(),
         app_state.x25519_public_key(),
+        app_state.subxt_account_id().0,
         keyring.pair(),
     )
 }
+
+/// Returns a path in the system temporary directory which does not yet exist
+pub fn temporary_path(name: &str) -> PathBuf {
+    let mut suffix = [0u8; 8];
+    OsRng.fill_bytes(&mut suffix);
+    std::env::temp_dir().join(format!("{name}-{}", hex::encode(suffix)))
+}
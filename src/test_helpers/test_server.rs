@@ -2,48 +2,86 @@
 use axum::{
     Router,
     body::{Body, Bytes},
-    extract::State,
-    http::{Request, StatusCode},
+    extract::{Form, Json, State},
+    http::{
+        HeaderMap, Method, Request, StatusCode, Uri,
+        header::{AUTHORIZATION, CONTENT_TYPE, LOCATION},
+    },
     middleware::{self, Next},
     response::Response,
-    routing::{get, post},
+    routing::{any, get, post},
 };
+use rand_core::{OsRng, RngCore};
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 const API_KEY_HEADER: &str = "api-key";
 const VALID_API_KEY: &str = "some-secret";
+/// A valid API key containing characters which must be escaped in JSON and form-urlencoded bodies
+pub const VALID_API_KEY_WITH_SPECIAL_CHARACTERS: &str = "some \"secret\"&with=special+chars%\\";
+/// The API key initially accepted by the `/rotating` routes, which can be used to create new keys
+/// and then revoked
+pub const INITIAL_ROTATING_API_KEY: &str = "initial-rotating-secret";
+/// How long `/rotating/slow-keys` takes to create a key
+pub const ROTATING_SLOW_CREATE_DELAY: Duration = Duration::from_millis(500);
+/// Another origin serving the same routes, for testing redirects between origins
+pub const OTHER_ORIGIN: &str = "http://127.0.0.1:3004";
 
 /// Application state containing API keys of users
-#[derive(Clone)]
 struct AppState {
     accepted_api_keys: Vec<String>,
+    /// Keys accepted by the `/rotating` routes, which change as keys are created and revoked
+    rotating_api_keys: Mutex<Vec<String>>,
 }
 
 /// Start the test server in a spawned task
 pub async fn start_test_api_server() {
     let app_state = Arc::new(AppState {
-        accepted_api_keys: vec![VALID_API_KEY.to_string()],
+        accepted_api_keys: vec![
+            VALID_API_KEY.to_string(),
+            VALID_API_KEY_WITH_SPECIAL_CHARACTERS.to_string(),
+        ],
+        rotating_api_keys: Mutex::new(vec![INITIAL_ROTATING_API_KEY.to_string()]),
     });
 
     let app = Router::new()
         .route("/protected", get(protected_handler))
         .route("/protected", post(protected_post_handler))
+        .route("/echo-method", any(echo_method_handler))
         .layer(middleware::from_fn_with_state(
             app_state.clone(),
             api_key_auth,
         ))
+        // These routes take the API key in the request body
+        .route("/json-body-auth", post(json_body_auth_handler))
+        .route("/form-body-auth", post(form_body_auth_handler))
+        // These routes take the API key as a bearer token, and act like a provider with an API
+        // for rotating keys
+        .route("/rotating/keys", post(rotating_create_handler))
+        .route("/rotating/keys", get(rotating_count_handler))
+        .route("/rotating/slow-keys", post(rotating_slow_create_handler))
+        .route("/rotating/revoke", post(rotating_revoke_handler))
+        .route("/rotating/protected", get(rotating_protected_handler))
+        // These routes redirect, keeping the query string, to the same or another origin
+        .route("/moved", get(moved_handler))
+        .route("/moved-host", get(moved_host_handler))
+        .route("/echo-request", get(echo_request_handler))
         .with_state(app_state);
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3002));
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    tracing::debug!("Test HTTP server running at http://{}", addr);
+    for port in [3002, 3004] {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+        tracing::debug!("Test HTTP server running at http://{}", addr);
 
-    tokio::spawn(async move {
-        axum::serve(listener, app.into_make_service())
-            .await
-            .unwrap();
-    });
+        let app = app.clone();
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service())
+                .await
+                .unwrap();
+        });
+    }
 }
 
 /// An example GET handler
@@ -51,6 +89,54 @@ async fn protected_handler() -> &'static str {
     "Success response"
 }
 
+/// Redirects to `/protected` on the same origin
+async fn moved_handler(uri: Uri) -> Response {
+    redirect(
+        StatusCode::MOVED_PERMANENTLY,
+        format!("/protected?{}", uri.query().unwrap_or_default()),
+    )
+}
+
+/// Redirects to `/echo-request` on [OTHER_ORIGIN]
+async fn moved_host_handler(uri: Uri) -> Response {
+    redirect(
+        StatusCode::TEMPORARY_REDIRECT,
+        format!(
+            "{OTHER_ORIGIN}/echo-request?{}",
+            uri.query().unwrap_or_default()
+        ),
+    )
+}
+
+/// Responds with a redirect to the given location
+fn redirect(status: StatusCode, location: String) -> Response {
+    Response::builder()
+        .status(status)
+        .header(LOCATION, location)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Responds with the path, query string and headers of the request as JSON, and with the
+/// `api-key` header of the request, if any, in the `x-echoed-api-key` header
+async fn echo_request_handler(uri: Uri, headers: HeaderMap) -> Response {
+    let echoed_headers: HashMap<_, _> = headers
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.to_string(),
+                String::from_utf8_lossy(value.as_bytes()).to_string(),
+            )
+        })
+        .collect();
+    let body = serde_json::json!({ "uri": uri.to_string(), "headers": echoed_headers });
+    let mut response = Response::builder().header(CONTENT_TYPE, "application/json");
+    if let Some(api_key) = headers.get(API_KEY_HEADER) {
+        response = response.header("x-echoed-api-key", api_key);
+    }
+    response.body(Body::from(body.to_string())).unwrap()
+}
+
 /// An example POST handler
 async fn protected_post_handler(body: Bytes) -> String {
     format!(
@@ -59,6 +145,113 @@ async fn protected_post_handler(body: Bytes) -> String {
     )
 }
 
+/// A handler for any method, which responds with the method and body of the request
+async fn echo_method_handler(method: Method, body: Bytes) -> String {
+    format!("{method} {}", String::from_utf8_lossy(&body))
+}
+
+/// A POST handler which accepts an API key in a JSON body, like a JSON-RPC or GraphQL endpoint
+async fn json_body_auth_handler(
+    State(state): State<Arc<AppState>>,
+    Json(body): Json<serde_json::Value>,
+) -> Result<&'static str, StatusCode> {
+    match body["api_key"].as_str() {
+        Some(key) if state.accepted_api_keys.contains(&key.to_string()) => Ok("Success response"),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+/// A POST handler which accepts an API key in a form-urlencoded body, like an OAuth token endpoint
+async fn form_body_auth_handler(
+    State(state): State<Arc<AppState>>,
+    Form(body): Form<HashMap<String, String>>,
+) -> Result<&'static str, StatusCode> {
+    match body.get("client_secret") {
+        Some(key) if state.accepted_api_keys.contains(key) => Ok("Success response"),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+/// Check the bearer token of a request to the `/rotating` routes, returning the key it contains
+fn check_rotating_api_key(state: &AppState, headers: &HeaderMap) -> Result<String, StatusCode> {
+    let key = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    if state
+        .rotating_api_keys
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|accepted| accepted == key)
+    {
+        Ok(key.to_string())
+    } else {
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+/// Creates a new API key, responding with it in a JSON body
+async fn rotating_create_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    check_rotating_api_key(&state, &headers)?;
+    let mut secret = [0u8; 16];
+    OsRng.fill_bytes(&mut secret);
+    let new_key = format!("rotated-secret-{}", hex::encode(secret));
+    state
+        .rotating_api_keys
+        .lock()
+        .unwrap()
+        .push(new_key.clone());
+    Ok(Json(serde_json::json!({ "data": { "secret": new_key } })))
+}
+
+/// Creates a new API key after a delay, so that the key can be changed while it is rotated
+async fn rotating_slow_create_handler(
+    state: State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    tokio::time::sleep(ROTATING_SLOW_CREATE_DELAY).await;
+    rotating_create_handler(state, headers).await
+}
+
+/// Responds with the number of API keys currently accepted by the `/rotating` routes
+async fn rotating_count_handler(State(state): State<Arc<AppState>>) -> String {
+    state.rotating_api_keys.lock().unwrap().len().to_string()
+}
+
+/// Revokes the API key given in a JSON body
+async fn rotating_revoke_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<serde_json::Value>,
+) -> Result<&'static str, StatusCode> {
+    let key = check_rotating_api_key(&state, &headers)?;
+    let revoked = body["key"].as_str().ok_or(StatusCode::BAD_REQUEST)?;
+    // Revoking the key used to authenticate would lock the caller out
+    if revoked == key {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    state
+        .rotating_api_keys
+        .lock()
+        .unwrap()
+        .retain(|accepted| accepted != revoked);
+    Ok("Revoked")
+}
+
+/// An example GET handler authenticated with a rotating API key
+async fn rotating_protected_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<&'static str, StatusCode> {
+    check_rotating_api_key(&state, &headers)?;
+    Ok("Success response")
+}
+
 /// Middleware to accept API keys given in either the header or the URL
 async fn api_key_auth(
     State(state): State<Arc<AppState>>,